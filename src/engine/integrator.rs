@@ -14,7 +14,10 @@ use utilities::sampler::Sampler;
 use utilities::sampler::PseudorandomSampler;
 
 use super::scene::*;
-use super::shader::*;
+use super::material::*;
+use super::intersectable::IntersectionRecord;
+use super::light_sampling::LightSamplingKind;
+use super::probability::{Warper, UniformSphereWarper};
 use self::cgmath::Matrix3;
 use self::rand::Rng;
 use utilities::sampler::SamplerSpec;
@@ -27,6 +30,7 @@ pub enum IntegratorSpec {
         max_bounces: u32,
         number_of_samples: u32,
         shade_shadow_rays: Option<bool>,
+        light_sampling: Option<LightSamplingKind>,
         #[serde(rename = "sampler")]
         sampler_spec: SamplerSpec
     },
@@ -38,7 +42,7 @@ impl IntegratorSpec {
         match *self {
             PathTracer {
                 max_bounces, number_of_samples,
-                shade_shadow_rays, ref sampler_spec
+                shade_shadow_rays, light_sampling, ref sampler_spec
             } => {
                 if shade_shadow_rays == Some(true) {
                     println!("shading shadow rays is not yet implemented.");
@@ -47,6 +51,7 @@ impl IntegratorSpec {
                     max_bounces,
                     number_of_samples,
                     shade_shadow_rays: shade_shadow_rays.unwrap_or(false),
+                    light_sampling: light_sampling.unwrap_or(LightSamplingKind::All),
                     sampler_number_sequence: sampler_spec.to_number_sequence(1000),
                 })
             }
@@ -59,28 +64,32 @@ impl IntegratorSpec {
 //in the future should sample emissive surfaces
 //as well
 struct LightSample {
+    light_index: usize,
     position: Vec3,
     intensity: Color3,
     sample_probability: f32
 }
 
-fn sample_light<TSpl: Sampler + ?Sized>(scene: &Scene, sampler: &mut TSpl) -> Option<LightSample> {
-    if scene.lights.is_empty() { return None }
-
-    let light_i = sampler.get_usize_from_f32(scene.lights.len());
-    let light: &Light = &scene.lights[light_i];
+fn sample_light<TSpl: Sampler + ?Sized>(
+    scene: &Scene, kind: LightSamplingKind, position: &Vec3, normal: Option<&UnitVec3>,
+    sampler: &mut TSpl
+) -> Option<LightSample> {
+    let choice = scene.light_sampler.choose_light(kind, position, normal, sampler)?;
+    let light: &Light = &scene.lights[choice.index];
 
     Some(LightSample {
+        light_index: choice.index,
         position: light.position.get(),
         intensity: Color3::new(light.intensity, light.intensity, light.intensity),
-        sample_probability: 1. / scene.lights.len() as f32
+        sample_probability: choice.probability
     })
 }
 
 struct PathIntersection {
-    shader: Rc<Shader>,
-    position: Vec3,
-    normal: Vec3
+    material: Rc<Material>,
+    record: IntersectionRecord,
+    ///weight of the bounce sampled at this intersection
+    bounce_weight: Color3
 }
 
 struct Path {
@@ -104,9 +113,14 @@ pub trait Integrator: Debug {
 fn unidirectional_path_has_no_light(path: &Path, scene: &Scene) -> bool {
     if path.intersections.is_empty() || path.light_sample.is_none() { return true }
     let last_intersection: &PathIntersection = path.intersections.last().as_ref().unwrap();
+    if last_intersection.material.bsdf().is_delta() { return true }
     let light_sample: &LightSample = path.light_sample.as_ref().unwrap();
+    let light = &scene.lights[light_sample.light_index];
+    if !light.illuminates(last_intersection.record.mesh_id) { return true }
     let light_intersected = scene
-        .intersect_for_obstruction(last_intersection.position, light_sample.position)
+        .intersect_for_obstruction(
+            last_intersection.record.position, light_sample.position, &light.links.shadow_casters
+        )
         .intersected();
     light_intersected
 }
@@ -124,36 +138,29 @@ fn shade_path(path: &Path, scene: &Scene, max_bounces: u32) -> Color3 {
         let next_position: &Vec3 = if i_intersection == path.intersections.len() - 1 {
             &light_sample.position
         } else {
-            &path.intersections[i_intersection + 1].position
-        };
-
-        //create incoming and outgoing light vectors
-        let light_directions = LightDirectionPair {
-            outgoing: &(previous_position - intersection.position).unit(),
-            incoming: &(next_position - intersection.position).unit()
+            &path.intersections[i_intersection + 1].record.position
         };
 
-        //shade brdf cos term
-        let brdf_cos_term = intersection.shader.brdf_cosine_term(
-            &intersection.normal.unit(), &light_directions
-        );
-
-        //probability
-        let probability_of_incoming_sample = if i_intersection == path.intersections.len() - 1 {
-            //if last intersection, use a uniform hemisphere
-            1. / 2. * PI
+        //accumulate. Bounces use the weight computed when they were sampled,
+        //which is also correct for delta materials. The last intersection is
+        //connected to the light directly
+        let throughput = if i_intersection == path.intersections.len() - 1 {
+            let record = &intersection.record;
+            let incoming = (next_position - record.position).unit();
+            let light_directions = LightDirectionPair {
+                outgoing: &(previous_position - record.position).unit(),
+                incoming: &incoming
+            };
+            let cosine = record.normal.value().dot(*incoming.value()).abs();
+            intersection.material.bsdf().brdf(record, &light_directions) * cosine /
+                light_sample.sample_probability
         } else {
-            intersection.shader.probability_of_sample(
-                &intersection.normal.unit(), &light_directions
-            )
+            intersection.bounce_weight
         };
 
-        //accumulate
-        accumulated_light.mul_assign_element_wise(
-            brdf_cos_term / probability_of_incoming_sample
-        );
+        accumulated_light.mul_assign_element_wise(throughput);
 
-        previous_position = intersection.position;
+        previous_position = intersection.record.position;
     }
     
     //finally, multiply by light sample
@@ -174,7 +181,10 @@ fn shade_path_interconnected(path: &Path) {
 ///in Path.intersections. It is not guaranteed that a ray from last intersection
 ///to the light sample is unobstructed
 ///Max bounces is >= 0
-fn trace_path(ray: &RayUnit, scene: &Scene, max_bounces: u32, sampler: &mut NumberSequenceSampler) -> Path {
+fn trace_path(
+    ray: &RayUnit, scene: &Scene, max_bounces: u32, light_sampling: LightSamplingKind,
+    sampler: &mut NumberSequenceSampler
+) -> Path {
     let mut path = Path {
         start_position: ray.position,
         intersections: Vec::new(),
@@ -183,31 +193,35 @@ fn trace_path(ray: &RayUnit, scene: &Scene, max_bounces: u32, sampler: &mut Numb
 
     let mut current_ray = ray.clone();
     for _ in 0..(max_bounces + 1) {
-        let record = scene.intersect(&current_ray);
+        let mut record = scene.intersect(&current_ray);
         if !record.intersected() {
             break;
         }
 
-        let shader = record.shader.clone()
-            .unwrap_or(Rc::new(default_shader()));
+        let material = record.material.clone()
+            .unwrap_or_else(|| Rc::new(default_material()));
+        material.perturb_normal(&mut record);
+        let bounce = material.bsdf().sample(
+            &record,
+            &current_ray.direction.neg(),
+            sampler);
+
+        current_ray = RayBase::new_epsilon_offset(record.position, bounce.direction.clone());
+
         let intersection = PathIntersection {
-            shader: shader.clone(),
-            position: record.position,
-            normal: record.normal
+            material: material.clone(),
+            record,
+            bounce_weight: bounce.weight()
         };
 
         path.intersections.push(intersection);
-
-        let new_direction = shader.sample_bounce(
-            &record.normal.unit(),
-            &current_ray.direction.neg(),
-            sampler);
-        current_ray = RayBase::new_epsilon_offset(record.position, new_direction);
     }
 
     //if there are intersections, connect path to a light
-    if !path.intersections.is_empty() {
-        path.light_sample = sample_light(scene, sampler);
+    if let Some(last_intersection) = path.intersections.last() {
+        path.light_sample = sample_light(
+            scene, light_sampling, &last_intersection.record.position, None, sampler
+        );
     }
 
     path
@@ -229,62 +243,198 @@ pub struct PathTracerIntegrator {
     pub max_bounces: u32,
     pub number_of_samples: u32,
     pub shade_shadow_rays: bool, //currently shades shadow rays without weights
+    pub light_sampling: LightSamplingKind,
     sampler_number_sequence: NumberSequenceSampler
 }
 
+///False if light arriving from direction is below the intersected face, where
+///shading normals would otherwise let it leak through opaque surfaces
+fn reaches_surface(intersection: &IntersectionRecord, bsdf: &BSDFMaterial,
+                   direction: &UnitVec3) -> bool {
+    bsdf.is_transmissive() ||
+        direction.value().dot(*intersection.geometric_normal.value()) > 0.0
+}
+
+///brdf * cos * radiance arriving from a single point light, or zero if the light is obstructed
+///or not linked to the intersected mesh
+fn direct_light_contribution(
+    light: &Light, intersection: &IntersectionRecord, bsdf: &BSDFMaterial,
+    outgoing_light_dir: &UnitVec3, scene: &Scene
+) -> Color3 {
+    if !light.illuminates(intersection.mesh_id) {
+        return Color3::zero();
+    }
+
+    let position = intersection.position;
+    let shadow_ray_intersection = scene.intersect_for_obstruction(
+        position, light.position.get(), &light.links.shadow_casters
+    );
+    if shadow_ray_intersection.intersected() {
+        return Color3::zero();
+    }
+
+    let incoming_light_vec: Vec3 = light.position.get() - position;
+    let incoming_light_dir = incoming_light_vec.unit();
+    let distance_to_light: f32 = incoming_light_vec.magnitude();
+    if !reaches_surface(intersection, bsdf, &incoming_light_dir) {
+        return Color3::zero();
+    }
+
+    let radiance = light.intensity / distance_to_light.powi(2);
+    let brdf_value = bsdf.brdf(
+        intersection,
+        &LightDirectionPair {
+            incoming: &incoming_light_dir,
+            outgoing: outgoing_light_dir
+        }
+    );
+    let cosine = intersection.normal.value().dot(*incoming_light_dir.value()).abs();
+
+    brdf_value * cosine * radiance
+}
+
+///Scattering events after which a subsurface walk is abandoned
+const MAX_SUBSURFACE_STEPS: u32 = 256;
+
 impl PathTracerIntegrator {
+    ///Light from the scene's lights reflected towards outgoing_light_dir.
+    ///Delta materials can't be connected to a light, their lighting comes
+    ///entirely from bounces
+    fn direct_light(
+        &self, intersection: &IntersectionRecord, bsdf: &BSDFMaterial,
+        outgoing_light_dir: &UnitVec3, scene: &Scene, sampler: &mut NumberSequenceSampler
+    ) -> Color3 {
+        if bsdf.is_delta() {
+            Color3::zero()
+        } else if self.light_sampling == LightSamplingKind::All {
+            scene.lights.iter()
+                .map(|light| direct_light_contribution(
+                    light, intersection, bsdf, outgoing_light_dir, scene
+                ))
+                .fold(Color3::zero(), |acc, new_elem| acc + new_elem)
+        } else {
+            // lights below the surface only matter if light can pass through it
+            let culling_normal = if bsdf.is_transmissive() {
+                None
+            } else {
+                Some(&intersection.normal)
+            };
+            let choice = scene.light_sampler.choose_light(
+                self.light_sampling, &intersection.position, culling_normal, sampler
+            );
+            match choice {
+                Some(choice) => direct_light_contribution(
+                    &scene.lights[choice.index], intersection, bsdf, outgoing_light_dir, scene
+                ) / choice.probability,
+                None => Color3::zero()
+            }
+        }
+    }
+
+    ///Radiance arriving at entry from direction, which points into the medium.
+    ///The walk ends at the first surface it reaches, where light leaves the medium
+    fn shade_subsurface_walk(
+        &self, material: &SubsurfaceMaterial, entry: &IntersectionRecord, direction: UnitVec3,
+        scene: &Scene, sampler: &mut NumberSequenceSampler, bounces: u32
+    ) -> Color3 {
+        let medium = material.medium(entry);
+        //product of the walk's values and of its per channel densities,
+        //both rescaled after each step to stay in range
+        let (mut value, mut pdfs) = (Color3::new(1.0, 1.0, 1.0), Color3::new(1.0, 1.0, 1.0));
+        //one channel samples every distance of the walk
+        let channel = medium.sample_channel(sampler);
+        let mut ray = RayUnit::new_epsilon_offset(entry.position, direction);
+        for _ in 0..MAX_SUBSURFACE_STEPS {
+            let distance = medium.sample_distance(channel, sampler);
+            let mut exit = scene.intersect(&ray);
+            let reached_surface = exit.intersected() && exit.t <= distance;
+            let (step_value, step_pdfs) = if reached_surface {
+                medium.reaching_surface(exit.t)
+            } else {
+                medium.scattering(distance)
+            };
+            value.mul_assign_element_wise(step_value);
+            pdfs.mul_assign_element_wise(step_pdfs);
+            let scale = average(&pdfs);
+            if !(scale > 0.0) {
+                return Color3::zero()
+            }
+            value /= scale;
+            pdfs /= scale;
+
+            if !reached_surface {
+                let scattered = UniformSphereWarper.sample(sampler).unit();
+                ray = RayUnit::new_epsilon_offset(
+                    ray.position + *ray.direction.value() * distance, scattered
+                );
+                continue
+            }
+
+            if exit.geometric_normal.value().dot(*ray.direction.value()) < 0.0 {
+                exit.normal = exit.normal.clone().neg();
+                exit.geometric_normal = exit.geometric_normal.clone().neg();
+            }
+            let exit_bsdf = material.exit_bsdf();
+            let outgoing = exit.normal.clone();
+            let mut radiance = self.direct_light(&exit, exit_bsdf, &outgoing, scene, sampler);
+            if bounces > 0 {
+                let bounce = exit_bsdf.sample(&exit, &outgoing, sampler);
+                let weight = bounce.weight();
+                let bounce_ray = RayUnit::new_epsilon_offset(exit.position, bounce.direction);
+                radiance += self.shade_ray_intern(&bounce_ray, scene, sampler, bounces - 1)
+                    .mul_element_wise(weight);
+            }
+            return radiance.mul_element_wise(value)
+        }
+        Color3::zero()
+    }
+
     fn shade_ray_intern(&self, ray: &RayUnit, scene: &Scene, sampler: &mut NumberSequenceSampler, bounces: u32) -> Color3 {
-        let intersection = scene.intersect(ray);
+        let mut intersection = scene.intersect(ray);
         if !intersection.intersected() {
             return scene.background_color;
         }
 
-        let shader = intersection.shader.clone().unwrap_or_else(|| Rc::new(default_shader()));
-        let normal = intersection.normal.unit();
+        let material = intersection.material.clone()
+            .unwrap_or_else(|| Rc::new(default_material()));
+        material.perturb_normal(&mut intersection);
+        let bsdf = material.bsdf();
 
         let outgoing_light_dir = ray.direction.clone().neg();
 
-        // contribution from all scene lights
-        let light_contribution: Color3 = scene.lights.iter()
-            .map(|light| -> Color3 {
-                let shadow_ray_intersection = scene.intersect_for_obstruction(intersection.position, light.position.get());
-                if shadow_ray_intersection.intersected() {
-                    return Color3::zero();
-                }
-
-                let incoming_light_vec: Vec3 = light.position.get() - intersection.position;
-                let incoming_light_dir = incoming_light_vec.unit();
-                let distance_to_light: f32 = incoming_light_vec.magnitude();
-
-                let radiance = light.intensity / distance_to_light.powi(2);
-                let brdf_cos_value = shader.brdf_cosine_term(
-                    &normal,
-                    &LightDirectionPair {
-                        incoming: &incoming_light_dir,
-                        outgoing: &outgoing_light_dir
-                    }
-                );
-
-                brdf_cos_value * radiance
-            })
-            .fold(Color3::zero(), |acc, new_elem| acc + new_elem);
+        let light_contribution = self.direct_light(
+            &intersection, bsdf, &outgoing_light_dir, scene, sampler
+        );
 
-        // contribution from shader sample bounce
+        // contribution from bsdf sample bounce
         let bsdf_contribution = if bounces <= 0 {
             Color3::zero()
         } else {
-            let incoming_dir = shader.sample_bounce(&normal, &outgoing_light_dir, sampler);
-            let light_directions = &LightDirectionPair {
-                incoming: &incoming_dir,
-                outgoing: &outgoing_light_dir
-            };
-            let sample_pdf = shader.probability_of_sample(&normal, light_directions);
-            let brdf_cos_value = shader.brdf_cosine_term(&normal, light_directions);
-
-            let sample_ray = RayUnit::new_epsilon_offset(intersection.position, incoming_dir.clone());
-            let radiance = self.shade_ray_intern(&sample_ray, scene, sampler, bounces - 1);
+            let bounce = bsdf.sample(&intersection, &outgoing_light_dir, sampler);
+            let weight = bounce.weight();
+            if weight == Color3::zero() ||
+                !reaches_surface(&intersection, bsdf, &bounce.direction) {
+                Color3::zero()
+            } else {
+                // the sampled direction may point into the surface for transmissive
+                // materials. The ray then continues inside the mesh until it hits the
+                // mesh's other side, where the material sees the outgoing direction
+                // below the normal
+                let enters_medium =
+                    bounce.direction.value().dot(*intersection.geometric_normal.value()) < 0.0;
+                let radiance = match material.subsurface() {
+                    Some(subsurface) if enters_medium => self.shade_subsurface_walk(
+                        subsurface, &intersection, bounce.direction, scene, sampler, bounces - 1
+                    ),
+                    _ => {
+                        let sample_ray =
+                            RayUnit::new_epsilon_offset(intersection.position, bounce.direction);
+                        self.shade_ray_intern(&sample_ray, scene, sampler, bounces - 1)
+                    }
+                };
 
-            radiance.mul_element_wise(brdf_cos_value) / sample_pdf
+                radiance.mul_element_wise(weight)
+            }
         };
 
         bsdf_contribution + light_contribution
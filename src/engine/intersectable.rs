@@ -1,11 +1,14 @@
 
 extern crate cgmath;
 
-use super::shader::*;
+use super::material::Material;
 use super::bvh::*;
 use super::transformable::*;
+use super::meshutils::MeshId;
+use super::primitive::Primitive;
 
 use utilities::math::*;
+use utilities::color::Color3;
 
 #[cfg(all(target_feature = "avx"))]
 use utilities::simd::{
@@ -52,7 +55,19 @@ pub trait Intersectable {
 pub struct Triangle {
     pub positions: [Vec3; 3],
     pub normals: [Vec3; 3],
-    pub shader: Rc<Shader>
+    pub texcoords: [Vec2; 3],
+    ///vertex colors, white for meshes without them
+    pub colors: [Color3; 3],
+    ///positions before the mesh's transformations. Used by object space textures
+    pub object_positions: [Vec3; 3],
+    ///dp/du and dp/dv, where u and v are the texture coordinates. Used by normal maps
+    pub tangents: [Vec3; 2],
+    ///tangents before the mesh's transformations
+    pub object_tangents: [Vec3; 2],
+    ///if true, rays hitting the back of the triangle see it from the front
+    pub double_sided: bool,
+    pub material: Rc<Material>,
+    pub mesh_id: MeshId
 }
 
 impl HasSurfaceArea for Triangle {
@@ -71,7 +86,14 @@ impl Clone for Triangle {
             normals: [self.normals[0].clone(),
                 self.normals[1].clone(),
                 self.normals[2].clone()],
-            shader: self.shader.clone()
+            texcoords: self.texcoords,
+            colors: self.colors,
+            object_positions: self.object_positions,
+            tangents: self.tangents,
+            object_tangents: self.object_tangents,
+            double_sided: self.double_sided,
+            material: self.material.clone(),
+            mesh_id: self.mesh_id
         }
     }
 }
@@ -106,6 +128,10 @@ impl Transformable for Triangle {
         for normal in self.normals.iter_mut() {
             *normal = normal_transform.transform_vector(*normal);
         }
+
+        for tangent in self.tangents.iter_mut() {
+            *tangent = transform.transform_vector(*tangent);
+        }
     }
 }
 
@@ -156,6 +182,10 @@ pub struct IntersectableTriangle {
 }
 
 impl IntersectableTriangle {
+    pub fn mesh_id(&self) -> MeshId {
+        self.triangle.mesh_id
+    }
+
     #[cfg(not(target_feature = "avx"))]
     pub fn new_from_triangle(triangle: &Triangle) -> IntersectableTriangle {
         let triangle_ptr = Rc::new(triangle.clone());
@@ -247,33 +277,177 @@ impl Intersectable for IntersectableTriangle {
 
         let position = (ray.position + t_multiplier * ray_normalized_direction).into();
 
-        *record = IntersectionRecord {
+        let mut normal = self.triangle.normals[0] * alpha +
+            self.triangle.normals[1] * beta +
+            self.triangle.normals[2] * gamma;
+        //the face normal, on the side of the vertex normals
+        let positions = &self.triangle.positions;
+        let mut geometric_normal = (positions[1] - positions[0]).cross(positions[2] - positions[0]);
+        if geometric_normal.dot(normal) < 0.0 {
+            geometric_normal = -geometric_normal;
+        }
+        let direction: Vec3 = if_avx!(
+            avx = ray_normalized_direction.into(),
+            noavx = ray_normalized_direction
+        );
+        if self.triangle.double_sided && geometric_normal.dot(direction) > 0.0 {
+            normal = -normal;
+            geometric_normal = -geometric_normal;
+        }
+
+        let hit = IntersectionRecord {
             position,
-            normal: self.triangle.normals[0] * alpha +
-                self.triangle.normals[1] * beta +
-                self.triangle.normals[2] * gamma,
+            normal: normal.unit(),
+            geometric_normal: geometric_normal.unit(),
+            uv: self.triangle.texcoords[0] * alpha +
+                self.triangle.texcoords[1] * beta +
+                self.triangle.texcoords[2] * gamma,
+            vertex_color: self.triangle.colors[0] * alpha +
+                self.triangle.colors[1] * beta +
+                self.triangle.colors[2] * gamma,
+            object_position: self.triangle.object_positions[0] * alpha +
+                self.triangle.object_positions[1] * beta +
+                self.triangle.object_positions[2] * gamma,
+            tangents: self.triangle.tangents,
+            object_tangents: self.triangle.object_tangents,
+            ray_direction: direction,
             t: t,
-            shader: Some(self.triangle.shader.clone())
+            material: Some(self.triangle.material.clone()),
+            mesh_id: Some(self.triangle.mesh_id)
         };
+
+        if is_cut_out(&hit) {
+            return false;
+        }
+
+        *record = hit;
         return true;
     }
 
 }
 
+///Rays pass through cutout parts of the surface, stochastically where it is translucent
+pub fn is_cut_out(hit: &IntersectionRecord) -> bool {
+    let opacity = match hit.material {
+        Some(ref material) => material.opacity(hit),
+        None => return false
+    };
+    opacity < 1.0 && (opacity <= 0.0 || cutout_sample(&hit.position, &hit.ray_direction) >= opacity)
+}
+
+///Number in [0, 1) decided by a ray and where it hits a surface, so that
+///deciding whether the ray passes through the surface needs no sampler
+fn cutout_sample(position: &Vec3, direction: &Vec3) -> f32 {
+    let mut hash: u32 = 2166136261;
+    for value in &[position.x, position.y, position.z, direction.x, direction.y, direction.z] {
+        hash = (hash ^ value.to_bits()).wrapping_mul(16777619);
+    }
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x7feb352d);
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(0x846ca68b);
+    hash ^= hash >> 16;
+    (hash >> 8) as f32 / (1u32 << 24) as f32
+}
+
+///Anything the scene's bounding volume hierarchy holds
+#[derive(Debug)]
+pub enum SceneObject {
+    Triangle(IntersectableTriangle),
+    Primitive(Primitive)
+}
+
+impl SceneObject {
+    pub fn mesh_id(&self) -> MeshId {
+        match *self {
+            SceneObject::Triangle(ref triangle) => triangle.mesh_id(),
+            SceneObject::Primitive(ref primitive) => primitive.mesh_id()
+        }
+    }
+}
+
+impl Intersectable for SceneObject {
+    fn intersect(&self, args: IntersectionArgs) -> bool {
+        match *self {
+            SceneObject::Triangle(ref triangle) => triangle.intersect(args),
+            SceneObject::Primitive(ref primitive) => primitive.intersect(args)
+        }
+    }
+}
+
+///Scene objects while the bounding volume hierarchy is built
+pub enum SceneObjectWithAABoundingBox {
+    Triangle(TriangleWithAABoundingBox),
+    Primitive(Primitive)
+}
+
+impl SceneObjectWithAABoundingBox {
+    pub fn to_scene_object(&self) -> SceneObject {
+        match *self {
+            SceneObjectWithAABoundingBox::Triangle(ref bb_triangle) => SceneObject::Triangle(
+                IntersectableTriangle::new_from_triangle(&bb_triangle.triangle)
+            ),
+            SceneObjectWithAABoundingBox::Primitive(ref primitive) =>
+                SceneObject::Primitive(primitive.clone())
+        }
+    }
+}
+
+impl HasAABoundingBox for SceneObjectWithAABoundingBox {
+    fn aa_bounding_box_ref(&self) -> &AABoundingBox {
+        match *self {
+            SceneObjectWithAABoundingBox::Triangle(ref triangle) => triangle.aa_bounding_box_ref(),
+            SceneObjectWithAABoundingBox::Primitive(ref primitive) =>
+                primitive.aa_bounding_box_ref()
+        }
+    }
+}
+
+impl HasSurfaceArea for SceneObjectWithAABoundingBox {
+    fn surface_area(&self) -> f32 {
+        match *self {
+            SceneObjectWithAABoundingBox::Triangle(ref triangle) => triangle.surface_area(),
+            SceneObjectWithAABoundingBox::Primitive(ref primitive) => primitive.surface_area()
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct IntersectionRecord {
-    pub shader: Option<Rc<Shader>>,
+    pub material: Option<Rc<Material>>,
+    pub mesh_id: Option<MeshId>,
     pub position: Vec3,
-    pub normal: Vec3, // TODO change this to UnitVec3
+    ///shading normal, interpolated from the vertex normals or from a normal map
+    pub normal: UnitVec3,
+    ///normal of the intersected face. Light can't reach the surface from below it
+    ///unless the material is transmissive
+    pub geometric_normal: UnitVec3,
+    pub uv: Vec2,
+    ///interpolated vertex color, white where the mesh has none
+    pub vertex_color: Color3,
+    pub object_position: Vec3,
+    ///dp/du and dp/dv of the intersected triangle
+    pub tangents: [Vec3; 2],
+    pub object_tangents: [Vec3; 2],
+    ///direction of the ray that hit the surface
+    pub ray_direction: Vec3,
     pub t: f32
 }
 
 impl IntersectionRecord {
     pub fn no_intersection() -> IntersectionRecord {
         IntersectionRecord {
-            shader: None,
+            material: None,
+            mesh_id: None,
             position: Vec3{x: 0., y: 0., z: 0.},
-            normal: Vec3{x: 0., y: 0., z: 0.},
+            normal: Vec3::new(0.0, 1.0, 0.0).unit(),
+            geometric_normal: Vec3::new(0.0, 1.0, 0.0).unit(),
+            uv: Vec2::new(0.0, 0.0),
+            vertex_color: Color3::new(1.0, 1.0, 1.0),
+            object_position: Vec3{x: 0., y: 0., z: 0.},
+            tangents: [Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0)],
+            object_tangents: [Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0)],
+            ray_direction: Vec3::new(0.0, -1.0, 0.0),
             t: f32::INFINITY
         }
     }
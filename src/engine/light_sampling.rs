@@ -0,0 +1,352 @@
+//!Strategies for choosing which light to connect to from a shading point
+
+use std::f32;
+use std::cmp::Ordering;
+
+use utilities::math::*;
+use utilities::sampler::Sampler;
+
+use super::bvh::AABoundingBox;
+use super::scene::Light;
+
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON;
+
+///Controls how lights are picked for next event estimation
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LightSamplingKind {
+    ///Every light is connected to at every hit
+    All,
+    ///A single light is chosen with equal probability
+    Uniform,
+    ///A single light is chosen with probability proportional to its power
+    Power,
+    ///A single light is chosen by walking down a light bvh, picking
+    ///each subtree by its estimated contribution at the shading point
+    LightTree
+}
+
+///A light picked by a LightSampler along with the probability of picking it
+#[derive(Debug, Clone, Copy)]
+pub struct LightChoice {
+    pub index: usize,
+    pub probability: f32
+}
+
+///Distribution over the indices of a list of non negative weights
+#[derive(Debug)]
+pub struct DiscreteDistribution {
+    cdf: Vec<f32>,
+    total: f32
+}
+
+impl DiscreteDistribution {
+    pub fn new(weights: &[f32]) -> DiscreteDistribution {
+        let mut cdf = Vec::with_capacity(weights.len());
+        let mut total = 0.0;
+        for weight in weights {
+            total += weight.max(0.0);
+            cdf.push(total);
+        }
+        DiscreteDistribution { cdf, total }
+    }
+
+    pub fn probability(&self, index: usize) -> f32 {
+        if self.total <= 0.0 || index >= self.cdf.len() {
+            return 0.0
+        }
+        let previous = if index == 0 { 0.0 } else { self.cdf[index - 1] };
+        (self.cdf[index] - previous) / self.total
+    }
+
+    ///Maps u in [0, 1) to an index. Indices with a weight of 0 are never returned.
+    ///returns None if all weights are 0
+    pub fn sample(&self, u: f32) -> Option<LightChoice> {
+        if self.total <= 0.0 {
+            return None
+        }
+        let target = u.max(0.0).min(ONE_MINUS_EPSILON) * self.total;
+        let index = match self.cdf.binary_search_by(|value| {
+            if *value <= target { Ordering::Less } else { Ordering::Greater }
+        }) {
+            Ok(index) | Err(index) => index.min(self.cdf.len() - 1)
+        };
+        Some(LightChoice {
+            index,
+            probability: self.probability(index)
+        })
+    }
+}
+
+#[derive(Debug)]
+enum LightTreeNode {
+    ///the left child is always stored right after its parent
+    Interior { bounds: AABoundingBox, power: f32, right_child: usize },
+    Leaf { position: Vec3, power: f32, light_index: usize }
+}
+
+impl LightTreeNode {
+    ///Rough estimate of how much light this subtree sends towards the shading point.
+    ///If a normal is given, lights that are entirely below the surface are ignored.
+    fn importance(&self, position: &Vec3, normal: Option<&UnitVec3>) -> f32 {
+        match *self {
+            LightTreeNode::Leaf { position: light_position, power, .. } => {
+                let to_light = light_position - position;
+                let distance2 = to_light.magnitude2().max(f32::EPSILON);
+                let cosine = normal
+                    .map(|n| n.value().dot(to_light).max(0.0) / distance2.sqrt())
+                    .unwrap_or(1.0);
+                power * cosine / distance2
+            },
+            LightTreeNode::Interior { ref bounds, power, .. } => {
+                if let Some(n) = normal {
+                    if bounds_below_plane(bounds, position, n) {
+                        return 0.0
+                    }
+                }
+                //distances closer than the box's radius aren't meaningful
+                let center = (bounds.lower + bounds.upper) / 2.0;
+                let radius2 = (bounds.upper - bounds.lower).magnitude2() / 4.0;
+                let distance2 = (center - position).magnitude2()
+                    .max(radius2)
+                    .max(f32::EPSILON);
+                power / distance2
+            }
+        }
+    }
+}
+
+///returns true if every corner of the box is on or below the plane.
+///since the box is convex, this means the entire box is
+fn bounds_below_plane(bounds: &AABoundingBox, position: &Vec3, normal: &UnitVec3) -> bool {
+    (0..8).all(|corner: usize| {
+        let point = Vec3::new(
+            if corner & 1 == 0 { bounds.lower.x } else { bounds.upper.x },
+            if corner & 2 == 0 { bounds.lower.y } else { bounds.upper.y },
+            if corner & 4 == 0 { bounds.lower.z } else { bounds.upper.z },
+        );
+        normal.value().dot(point - position) <= 0.0
+    })
+}
+
+struct LightTreeBuildInfo {
+    position: Vec3,
+    power: f32,
+    light_index: usize
+}
+
+///Binary tree over the scene's point lights, similar to the bvh used for
+///triangles. Nodes are laid out depth first.
+#[derive(Debug)]
+pub struct LightTree {
+    nodes: Vec<LightTreeNode>
+}
+
+impl LightTree {
+    pub fn new(lights: &[Light]) -> LightTree {
+        let mut infos: Vec<LightTreeBuildInfo> = lights.iter().enumerate()
+            .map(|(light_index, light)| LightTreeBuildInfo {
+                position: light.position.get(),
+                power: light.power(),
+                light_index
+            })
+            .collect();
+
+        let mut nodes = Vec::<LightTreeNode>::new();
+        if !infos.is_empty() {
+            LightTree::build_tree(&mut nodes, infos.as_mut_slice());
+        }
+        LightTree { nodes }
+    }
+
+    fn build_tree(nodes: &mut Vec<LightTreeNode>, infos: &mut [LightTreeBuildInfo]) {
+        if infos.len() == 1 {
+            nodes.push(LightTreeNode::Leaf {
+                position: infos[0].position,
+                power: infos[0].power,
+                light_index: infos[0].light_index
+            });
+            return;
+        }
+
+        let mut bounds = AABoundingBox::empty();
+        for info in infos.iter() {
+            bounds.lower = bounds.lower.min_elem_wise(&info.position);
+            bounds.upper = bounds.upper.max_elem_wise(&info.position);
+        }
+        let power = infos.iter().map(|info| info.power).sum();
+
+        //split along the widest axis at the median
+        let extent = bounds.upper - bounds.lower;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        infos.sort_by(|a, b| a.position[axis].partial_cmp(&b.position[axis])
+            .unwrap_or(Ordering::Equal));
+
+        let node_index = nodes.len();
+        nodes.push(LightTreeNode::Interior { bounds, power, right_child: 0 });
+
+        let middle = infos.len() / 2;
+        let (left_infos, right_infos) = infos.split_at_mut(middle);
+        LightTree::build_tree(nodes, left_infos);
+        let right_child_index = nodes.len();
+        LightTree::build_tree(nodes, right_infos);
+
+        if let LightTreeNode::Interior { ref mut right_child, .. } = nodes[node_index] {
+            *right_child = right_child_index;
+        }
+    }
+
+    ///Walks down the tree choosing a child with probability proportional
+    ///to its importance. u is reused at every level after being rescaled.
+    ///returns None if no light can contribute to the shading point
+    pub fn sample(&self, position: &Vec3, normal: Option<&UnitVec3>, u: f32)
+        -> Option<LightChoice>
+    {
+        if self.nodes.is_empty() {
+            return None
+        }
+
+        let mut u = u.max(0.0).min(ONE_MINUS_EPSILON);
+        let mut probability = 1.0;
+        let mut node_index = 0;
+        loop {
+            match self.nodes[node_index] {
+                LightTreeNode::Leaf { light_index, .. } => {
+                    return Some(LightChoice { index: light_index, probability })
+                },
+                LightTreeNode::Interior { right_child, .. } => {
+                    let left_child = node_index + 1;
+                    let left_importance = self.nodes[left_child].importance(position, normal);
+                    let right_importance = self.nodes[right_child].importance(position, normal);
+                    let total_importance = left_importance + right_importance;
+                    if !(total_importance > 0.0) {
+                        return None
+                    }
+
+                    let left_probability = left_importance / total_importance;
+                    if u < left_probability {
+                        u = (u / left_probability).min(ONE_MINUS_EPSILON);
+                        probability *= left_probability;
+                        node_index = left_child;
+                    } else {
+                        u = ((u - left_probability) / (1.0 - left_probability))
+                            .min(ONE_MINUS_EPSILON);
+                        probability *= 1.0 - left_probability;
+                        node_index = right_child;
+                    }
+                }
+            }
+        }
+    }
+}
+
+///Precomputed light selection structures for a scene
+#[derive(Debug)]
+pub struct LightSampler {
+    number_of_lights: usize,
+    power_distribution: DiscreteDistribution,
+    light_tree: LightTree
+}
+
+impl LightSampler {
+    pub fn new(lights: &[Light]) -> LightSampler {
+        let powers: Vec<f32> = lights.iter().map(|light| light.power()).collect();
+        LightSampler {
+            number_of_lights: lights.len(),
+            power_distribution: DiscreteDistribution::new(powers.as_slice()),
+            light_tree: LightTree::new(lights)
+        }
+    }
+
+    ///Chooses a single light for the shading point.
+    ///LightSamplingKind::All has no single light to choose, so it picks uniformly
+    pub fn choose_light<TSpl: Sampler + ?Sized>(
+        &self, kind: LightSamplingKind, position: &Vec3, normal: Option<&UnitVec3>,
+        sampler: &mut TSpl
+    ) -> Option<LightChoice> {
+        if self.number_of_lights == 0 {
+            return None
+        }
+
+        match kind {
+            LightSamplingKind::All | LightSamplingKind::Uniform => Some(LightChoice {
+                index: sampler.get_usize_from_f32(self.number_of_lights),
+                probability: 1.0 / self.number_of_lights as f32
+            }),
+            LightSamplingKind::Power => self.power_distribution.sample(sampler.get_f32()),
+            LightSamplingKind::LightTree =>
+                self.light_tree.sample(position, normal, sampler.get_f32())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utilities::codable::CodableWrapper;
+
+    fn test_lights() -> Vec<Light> {
+        [(0.0, 0.0, 0.0, 1.0), (4.0, 1.0, 0.0, 3.0), (-2.0, 5.0, 1.0, 0.5),
+            (1.0, -3.0, 2.0, 8.0), (0.5, 0.5, -6.0, 2.0)].iter()
+            .map(|&(x, y, z, intensity)| Light {
+                position: CodableWrapper(Vec3::new(x, y, z)),
+                intensity,
+                illumination_links: None,
+                shadow_links: None,
+                links: Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_discrete_distribution() {
+        let distribution = DiscreteDistribution::new(&[1.0, 0.0, 3.0]);
+        assert_near!(distribution.probability(0), 0.25, 0.00001);
+        assert_near!(distribution.probability(1), 0.0, 0.00001);
+        assert_near!(distribution.probability(2), 0.75, 0.00001);
+
+        assert_eq!(distribution.sample(0.0).unwrap().index, 0);
+        assert_eq!(distribution.sample(0.2).unwrap().index, 0);
+        assert_eq!(distribution.sample(0.25).unwrap().index, 2);
+        assert_eq!(distribution.sample(1.0).unwrap().index, 2);
+
+        assert!(DiscreteDistribution::new(&[0.0, 0.0]).sample(0.5).is_none());
+    }
+
+    #[test]
+    fn test_light_tree_probabilities() {
+        let lights = test_lights();
+        let tree = LightTree::new(lights.as_slice());
+        let position = Vec3::new(0.3, 0.2, 0.1);
+        let normal = Vec3::new(0.0, 1.0, 0.0).unit();
+
+        //integrate the probability of each light over u
+        let steps = 100000;
+        let mut probabilities = vec![0.0f32; lights.len()];
+        for step in 0..steps {
+            let u = (step as f32 + 0.5) / steps as f32;
+            if let Some(choice) = tree.sample(&position, Some(&normal), u) {
+                probabilities[choice.index] += 1.0 / steps as f32;
+            }
+        }
+
+        let total: f32 = probabilities.iter().sum();
+        assert_near!(total, 1.0, 0.001);
+
+        //the reported probability must match how often each light is chosen
+        for step in 0..100 {
+            let u = (step as f32 + 0.5) / 100.0;
+            if let Some(choice) = tree.sample(&position, Some(&normal), u) {
+                assert_near!(choice.probability, probabilities[choice.index], 0.001);
+            }
+        }
+
+        //light 3 is below the shading point's surface
+        assert_near!(probabilities[3], 0.0, 0.00001);
+    }
+}
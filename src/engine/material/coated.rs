@@ -0,0 +1,155 @@
+use utilities::math::*;
+use utilities::color::*;
+use utilities::sampler::{Sampler, NumberSequenceSampler};
+
+use engine::probability::*;
+use engine::intersectable::IntersectionRecord;
+use engine::texture::Texture;
+use super::{Material, BSDFMaterial, BSDFSampleResult, LightDirectionPair, ggx_alpha};
+use super::frame::ShadingFrame;
+use super::optics::*;
+use super::microfacet::{sample_ggx_reflection, ggx_reflection_pdf};
+
+use std::rc::Rc;
+
+///Dielectric clearcoat over another material. Light reflected by the coat never
+///reaches the base, so the base is weighted by the coat's fresnel transmittance
+///on the way in and on the way out
+#[derive(Debug)]
+pub struct CoatedBSDFMaterial {
+    base: Rc<Material>,
+    index_of_refraction: f32,
+    ///None for a perfectly smooth coat
+    roughness: Option<Texture>
+}
+
+impl CoatedBSDFMaterial {
+    pub fn new(base: Rc<Material>, index_of_refraction: f32, roughness: Option<Texture>)
+        -> CoatedBSDFMaterial
+    {
+        CoatedBSDFMaterial {
+            base,
+            index_of_refraction,
+            roughness
+        }
+    }
+
+    fn coat_reflectance(&self, normal: &UnitVec3, direction: &UnitVec3) -> f32 {
+        let cosine = direction.value().dot(*normal.value()).abs();
+        fresnel_dielectric(cosine, 1.0, self.index_of_refraction)
+    }
+
+    fn base_transmittance(&self, normal: &UnitVec3, light_directions: &LightDirectionPair) -> f32 {
+        (1.0 - self.coat_reflectance(normal, light_directions.incoming)) *
+            (1.0 - self.coat_reflectance(normal, light_directions.outgoing))
+    }
+
+    ///GGX reflection off a rough coat
+    fn coat_brdf(&self, normal: &UnitVec3, light_directions: &LightDirectionPair,
+                 alpha: f32) -> f32 {
+        let (facing_normal, _, _) = facing_interface(normal, light_directions.outgoing, 1.0);
+        let n = *facing_normal.value();
+        let cos_incoming = light_directions.incoming.value().dot(n);
+        let cos_outgoing = light_directions.outgoing.value().dot(n);
+        if cos_incoming <= 0.0 || cos_outgoing <= 0.0 {
+            return 0.0
+        }
+
+        let half = half_vector(light_directions.incoming, light_directions.outgoing);
+        let reflectance = fresnel_dielectric(
+            light_directions.outgoing.value().dot(*half.value()).abs(),
+            1.0, self.index_of_refraction);
+        let geometry =
+            ggx_smith_g1(light_directions.outgoing, &half, &facing_normal, alpha) *
+            ggx_smith_g1(light_directions.incoming, &half, &facing_normal, alpha);
+        reflectance * ggx_distribution(&half, &facing_normal, alpha) * geometry /
+            (4.0 * cos_incoming * cos_outgoing)
+    }
+
+    fn coat_pdf(&self, normal: &UnitVec3, light_directions: &LightDirectionPair,
+                alpha: f32) -> f32 {
+        let (facing_normal, _, _) = facing_interface(normal, light_directions.outgoing, 1.0);
+        ggx_reflection_pdf(
+            &ShadingFrame::around(&facing_normal), GGXDistribution::isotropic(alpha),
+            light_directions
+        )
+    }
+}
+
+impl BSDFMaterial for CoatedBSDFMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        let normal = &record.normal;
+        // choose the coat with probability equal to its reflectance
+        let reflectance = self.coat_reflectance(normal, outgoing_light_direction);
+        if sampler.get_f32() < reflectance {
+            let (facing_normal, _, _) = facing_interface(normal, outgoing_light_direction, 1.0);
+            return match self.roughness {
+                None => BSDFSampleResult {
+                    direction: reflection(outgoing_light_direction, &facing_normal),
+                    pdf: reflectance,
+                    value: Color3::new(reflectance, reflectance, reflectance),
+                    from_delta_lobe: true
+                },
+                Some(ref roughness) => {
+                    let distribution = GGXDistribution::isotropic(ggx_alpha(roughness, record));
+                    match sample_ggx_reflection(&ShadingFrame::around(&facing_normal),
+                                                distribution, outgoing_light_direction, sampler) {
+                        Some(direction) => BSDFSampleResult::from_direction(
+                            self, record, direction, outgoing_light_direction
+                        ),
+                        None => BSDFSampleResult::rejected(outgoing_light_direction.clone())
+                    }
+                }
+            }
+        }
+
+        let sample = self.base.bsdf().sample(record, outgoing_light_direction, sampler);
+        if sample.from_delta_lobe || sample.pdf <= 0.0 {
+            let transmittance = self.base_transmittance(normal, &LightDirectionPair {
+                incoming: &sample.direction,
+                outgoing: outgoing_light_direction
+            });
+            return BSDFSampleResult {
+                pdf: sample.pdf * (1.0 - reflectance),
+                value: sample.value * transmittance,
+                ..sample
+            }
+        }
+        BSDFSampleResult::from_direction(self, record, sample.direction, outgoing_light_direction)
+    }
+
+    fn sample_pdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> f32 {
+        let normal = &record.normal;
+        let reflectance = self.coat_reflectance(normal, light_directions.outgoing);
+        let coat_pdf = match self.roughness {
+            Some(ref roughness) =>
+                self.coat_pdf(normal, light_directions, ggx_alpha(roughness, record)),
+            None => 0.0
+        };
+        coat_pdf * reflectance +
+            self.base.bsdf().sample_pdf(record, light_directions) * (1.0 - reflectance)
+    }
+
+    fn brdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> Color3 {
+        let normal = &record.normal;
+        let coat_brdf = match self.roughness {
+            Some(ref roughness) =>
+                self.coat_brdf(normal, light_directions, ggx_alpha(roughness, record)),
+            None => 0.0
+        };
+        Color3::new(coat_brdf, coat_brdf, coat_brdf) +
+            self.base.bsdf().brdf(record, light_directions) *
+            self.base_transmittance(normal, light_directions)
+    }
+
+    fn is_transmissive(&self) -> bool {
+        self.base.bsdf().is_transmissive()
+    }
+
+    fn is_delta(&self) -> bool {
+        self.roughness.is_none() && self.base.bsdf().is_delta()
+    }
+}
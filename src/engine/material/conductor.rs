@@ -0,0 +1,185 @@
+use utilities::math::*;
+use utilities::color::*;
+use utilities::codable::CodableWrapper;
+use utilities::sampler::NumberSequenceSampler;
+
+use engine::intersectable::IntersectionRecord;
+use super::{BSDFMaterial, BSDFSampleResult, LightDirectionPair};
+use super::microfacet::*;
+use super::optics::*;
+use super::thin_film::{ThinFilm, Substrate};
+
+///Wavelengths in nanometers used for the red, green and blue channels
+const RGB_WAVELENGTHS: [f32; 3] = [650.0, 550.0, 450.0];
+
+///Metals with measured complex indices of refraction
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum Metal {
+    Au, Ag, Cu, Al, Fe, Cr
+}
+
+impl Metal {
+    ///(eta, k) at RGB_WAVELENGTHS
+    pub fn complex_ior(&self) -> (Color3, Color3) {
+        let (eta, k) = match *self {
+            Metal::Au => ([0.143, 0.374, 1.442], [3.983, 2.385, 1.603]),
+            Metal::Ag => ([0.155, 0.117, 0.138], [4.828, 3.122, 2.147]),
+            Metal::Cu => ([0.200, 0.924, 1.102], [3.912, 2.452, 2.142]),
+            Metal::Al => ([1.657, 0.880, 0.521], [9.224, 6.270, 4.837]),
+            Metal::Fe => ([2.911, 2.950, 2.585], [3.089, 2.932, 2.767]),
+            Metal::Cr => ([3.180, 3.180, 2.010], [3.300, 3.330, 3.040])
+        };
+        (Color3::new(eta[0], eta[1], eta[2]), Color3::new(k[0], k[1], k[2]))
+    }
+}
+
+///Per channel values, or [wavelength in nm, value] samples of a spectrum
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum SpectrumSpec {
+    Rgb(CodableWrapper<Color3>),
+    Samples(Vec<(f32, f32)>)
+}
+
+impl SpectrumSpec {
+    ///Spectra are linearly interpolated at RGB_WAVELENGTHS
+    pub fn to_rgb(&self) -> Result<Color3, String> {
+        match *self {
+            SpectrumSpec::Rgb(ref color) => Ok(color.get()),
+            SpectrumSpec::Samples(ref samples) => {
+                if samples.is_empty() {
+                    return Err("spectrum has no samples".into())
+                }
+                let mut samples = samples.clone();
+                samples.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(::std::cmp::Ordering::Equal));
+                let at = |wavelength: f32| {
+                    match samples.iter().position(|sample| sample.0 >= wavelength) {
+                        Some(0) => samples[0].1,
+                        Some(index) => {
+                            let (low, high) = (samples[index - 1], samples[index]);
+                            let amount = (wavelength - low.0) / (high.0 - low.0);
+                            low.1 + (high.1 - low.1) * amount
+                        },
+                        None => samples[samples.len() - 1].1
+                    }
+                };
+                Ok(Color3::new(at(RGB_WAVELENGTHS[0]), at(RGB_WAVELENGTHS[1]),
+                               at(RGB_WAVELENGTHS[2])))
+            }
+        }
+    }
+}
+
+///Metal with the exact fresnel reflectance of a conductor. eta and k are the
+///real and imaginary parts of the index of refraction, per channel
+#[derive(Debug)]
+pub struct ConductorBSDFMaterial {
+    eta: Color3,
+    k: Color3,
+    ///None for a perfectly smooth metal
+    roughness: Option<MicrofacetRoughness>,
+    thin_film: Option<ThinFilm>
+}
+
+impl ConductorBSDFMaterial {
+    pub fn new(eta: Color3, k: Color3, roughness: Option<MicrofacetRoughness>,
+               thin_film: Option<ThinFilm>) -> ConductorBSDFMaterial
+    {
+        ConductorBSDFMaterial { eta, k, roughness, thin_film }
+    }
+
+    fn reflectance(&self, record: &IntersectionRecord, cos_incident: f32) -> Color3 {
+        if let Some(ref thin_film) = self.thin_film {
+            return thin_film.reflectance(
+                record, cos_incident, &Substrate::Conductor { eta: self.eta, k: self.k }
+            )
+        }
+        Color3::new(
+            fresnel_conductor(cos_incident, self.eta.x, self.k.x),
+            fresnel_conductor(cos_incident, self.eta.y, self.k.y),
+            fresnel_conductor(cos_incident, self.eta.z, self.k.z)
+        )
+    }
+}
+
+impl BSDFMaterial for ConductorBSDFMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        match self.roughness {
+            None => {
+                let (facing_normal, _, _) =
+                    facing_interface(&record.normal, outgoing_light_direction, 1.0);
+                let cosine = outgoing_light_direction.value().dot(*facing_normal.value());
+                BSDFSampleResult {
+                    direction: reflection(outgoing_light_direction, &facing_normal),
+                    pdf: 1.0,
+                    value: self.reflectance(record, cosine),
+                    from_delta_lobe: true
+                }
+            },
+            Some(ref roughness) => {
+                let (frame, distribution) = roughness.distribution(record);
+                match sample_ggx_reflection(&frame, distribution, outgoing_light_direction, sampler) {
+                    Some(direction) => BSDFSampleResult::from_direction(
+                        self, record, direction, outgoing_light_direction
+                    ),
+                    None => BSDFSampleResult::rejected(outgoing_light_direction.clone())
+                }
+            }
+        }
+    }
+
+    fn sample_pdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> f32 {
+        match self.roughness {
+            None => 0.0,
+            Some(ref roughness) => {
+                let (frame, distribution) = roughness.distribution(record);
+                ggx_reflection_pdf(&frame, distribution, light_directions)
+            }
+        }
+    }
+
+    fn brdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> Color3 {
+        let term = self.roughness.as_ref().and_then(|roughness| {
+            let (frame, distribution) = roughness.distribution(record);
+            ggx_reflection_term(&frame, distribution, light_directions)
+        });
+        match term {
+            Some((term, half)) =>
+                self.reflectance(record, light_directions.incoming.value().dot(*half.value())) * term,
+            None => Color3::zero()
+        }
+    }
+
+    fn is_delta(&self) -> bool {
+        self.roughness.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conductor_reflectance() {
+        //a conductor without absorption is a dielectric
+        let (cos_incident, ior) = (0.7, 1.5);
+        assert!(apprx_eq(fresnel_conductor(cos_incident, ior, 0.0),
+                         fresnel_dielectric(cos_incident, 1.0, ior), 1e-5));
+
+        let (eta, k) = Metal::Au.complex_ior();
+        let gold = ConductorBSDFMaterial::new(eta, k, None, None);
+        let record = IntersectionRecord::no_intersection();
+        let at_normal = gold.reflectance(&record, 1.0);
+        //gold reflects red more than blue, and everything at grazing angles
+        assert!(at_normal.x > 0.9 && at_normal.z < 0.5);
+        assert!(gold.reflectance(&record, 0.0).z > 0.999);
+
+        let spectrum = SpectrumSpec::Samples(vec![(700.0, 1.0), (400.0, 4.0), (500.0, 2.0)]);
+        let rgb = spectrum.to_rgb().ok().unwrap();
+        assert!(apprx_eq(rgb.x, 1.25, 1e-5) && apprx_eq(rgb.y, 1.75, 1e-5) &&
+                apprx_eq(rgb.z, 3.0, 1e-5));
+    }
+}
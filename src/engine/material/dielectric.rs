@@ -0,0 +1,263 @@
+use utilities::math::*;
+use utilities::color::*;
+use utilities::sampler::{Sampler, NumberSequenceSampler};
+
+use engine::probability::*;
+use engine::intersectable::IntersectionRecord;
+use engine::texture::Texture;
+use super::{BSDFMaterial, BSDFSampleResult, LightDirectionPair, ggx_alpha};
+use super::frame::ShadingFrame;
+use super::optics::*;
+
+///Perfectly smooth glass-like surface. Both lobes are delta distributions,
+///so it can only be sampled, not evaluated
+#[derive(Debug)]
+pub struct DielectricBSDFMaterial {
+    index_of_refraction: f32,
+    color: Texture
+}
+
+impl DielectricBSDFMaterial {
+    pub fn new(index_of_refraction: f32, color: Texture) -> DielectricBSDFMaterial {
+        DielectricBSDFMaterial {
+            index_of_refraction,
+            color
+        }
+    }
+}
+
+impl BSDFMaterial for DielectricBSDFMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        let (facing_normal, eta_outgoing, eta_other) =
+            facing_interface(&record.normal, outgoing_light_direction, self.index_of_refraction);
+        let cos_outgoing = outgoing_light_direction.value().dot(*facing_normal.value());
+        let reflectance = fresnel_dielectric(cos_outgoing, eta_outgoing, eta_other);
+
+        // reflect or refract with probability proportional to the fresnel term
+        if sampler.get_f32() >= reflectance {
+            let eta = eta_outgoing / eta_other;
+            if let Some(direction) = refraction(outgoing_light_direction, &facing_normal, eta) {
+                let transmittance = 1.0 - reflectance;
+                return BSDFSampleResult {
+                    direction,
+                    pdf: transmittance,
+                    value: self.color.color(record) * transmittance * eta.powi(2),
+                    from_delta_lobe: true
+                }
+            }
+        }
+
+        BSDFSampleResult {
+            direction: reflection(outgoing_light_direction, &facing_normal),
+            pdf: reflectance,
+            value: Color3::new(reflectance, reflectance, reflectance),
+            from_delta_lobe: true
+        }
+    }
+
+    fn sample_pdf(&self, _record: &IntersectionRecord, _light_directions: &LightDirectionPair) -> f32 {
+        0.0
+    }
+
+    fn brdf(&self, _record: &IntersectionRecord, _light_directions: &LightDirectionPair) -> Color3 {
+        Color3::zero()
+    }
+
+    fn is_transmissive(&self) -> bool {
+        true
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+///Rough glass using GGX microfacets for both reflection and transmission.
+///Walter et al. 2007, "Microfacet Models for Refraction through Rough Surfaces"
+#[derive(Debug)]
+pub struct RoughDielectricBSDFMaterial {
+    index_of_refraction: f32,
+    roughness: Texture,
+    color: Texture
+}
+
+impl RoughDielectricBSDFMaterial {
+    pub fn new(index_of_refraction: f32, roughness: Texture, color: Texture)
+        -> RoughDielectricBSDFMaterial
+    {
+        RoughDielectricBSDFMaterial {
+            index_of_refraction,
+            roughness,
+            color
+        }
+    }
+}
+
+impl BSDFMaterial for RoughDielectricBSDFMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        let (direction, valid) = sample_rough_dielectric(
+            &record.normal, outgoing_light_direction, self.index_of_refraction,
+            ggx_alpha(&self.roughness, record), sampler
+        );
+        if !valid {
+            return BSDFSampleResult::rejected(direction)
+        }
+        BSDFSampleResult::from_direction(self, record, direction, outgoing_light_direction)
+    }
+
+    fn sample_pdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> f32 {
+        rough_dielectric_pdf(&record.normal, light_directions, self.index_of_refraction,
+                             ggx_alpha(&self.roughness, record))
+    }
+
+    fn brdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> Color3 {
+        rough_dielectric_bsdf(&record.normal, light_directions, self.index_of_refraction,
+                              ggx_alpha(&self.roughness, record), &self.color, record)
+    }
+
+    fn is_transmissive(&self) -> bool {
+        true
+    }
+}
+
+///Half vector for a pair of directions, facing the same way as facing_normal.
+///eta is the ior on the incoming side over the ior on the outgoing side
+fn generalized_half_vector(light_directions: &LightDirectionPair, facing_normal: &UnitVec3,
+                           eta: f32) -> UnitVec3 {
+    let wo = *light_directions.outgoing.value();
+    let wi = *light_directions.incoming.value();
+    let is_reflection = wi.dot(*facing_normal.value()) > 0.0;
+    let half = if is_reflection { wo + wi } else { wo + wi * eta };
+    if half.dot(*facing_normal.value()) < 0.0 {
+        (-half).unit()
+    } else {
+        half.unit()
+    }
+}
+
+///Samples a GGX microfacet normal, then reflects or refracts through it.
+///The returned flag is false if the direction ended up on the wrong side
+///of the macro surface for the chosen lobe. Such samples must be discarded
+///because the pdf doesn't account for them
+pub fn sample_rough_dielectric(
+    normal: &UnitVec3, outgoing_light_direction: &UnitVec3, index_of_refraction: f32,
+    alpha: f32, sampler: &mut NumberSequenceSampler
+) -> (UnitVec3, bool) {
+    let (facing_normal, eta_outgoing, eta_other) =
+        facing_interface(normal, outgoing_light_direction, index_of_refraction);
+    let frame = ShadingFrame::around(&facing_normal);
+    let outgoing = frame.to_local(outgoing_light_direction);
+    if outgoing.y <= 0.0 {
+        return (outgoing_light_direction.clone(), false);
+    }
+    let warper = GGXVisibleNormalWarper {
+        distribution: GGXDistribution::isotropic(alpha),
+        outgoing
+    };
+    let microfacet_normal = frame.from_local(&warper.sample(sampler));
+
+    let cos_outgoing = outgoing_light_direction.value().dot(*microfacet_normal.value());
+    if cos_outgoing <= 0.0 {
+        return (reflection(outgoing_light_direction, &microfacet_normal), false);
+    }
+
+    let reflectance = fresnel_dielectric(cos_outgoing, eta_outgoing, eta_other);
+    let refracted = if sampler.get_f32() < reflectance {
+        None
+    } else {
+        refraction(outgoing_light_direction, &microfacet_normal, eta_outgoing / eta_other)
+    };
+
+    match refracted {
+        Some(direction) => {
+            let valid = direction.value().dot(*facing_normal.value()) < 0.0;
+            (direction, valid)
+        },
+        None => {
+            let direction = reflection(outgoing_light_direction, &microfacet_normal);
+            let valid = direction.value().dot(*facing_normal.value()) > 0.0;
+            (direction, valid)
+        }
+    }
+}
+
+///Probability density of sample_rough_dielectric returning light_directions.incoming
+pub fn rough_dielectric_pdf(
+    normal: &UnitVec3, light_directions: &LightDirectionPair, index_of_refraction: f32,
+    alpha: f32
+) -> f32 {
+    let (facing_normal, eta_outgoing, eta_other) =
+        facing_interface(normal, light_directions.outgoing, index_of_refraction);
+    let n = *facing_normal.value();
+    let wo = *light_directions.outgoing.value();
+    let wi = *light_directions.incoming.value();
+    let (cos_outgoing, cos_incoming) = (wo.dot(n), wi.dot(n));
+    if cos_outgoing == 0.0 || cos_incoming == 0.0 {
+        return 0.0
+    }
+
+    let eta = eta_other / eta_outgoing;
+    let half = generalized_half_vector(light_directions, &facing_normal, eta);
+    let (wo_dot_h, wi_dot_h) = (wo.dot(*half.value()), wi.dot(*half.value()));
+    let reflectance = fresnel_dielectric(wo_dot_h.abs(), eta_outgoing, eta_other);
+    // density of the visible microfacet normals
+    let half_pdf = ggx_smith_g1(light_directions.outgoing, &half, &facing_normal, alpha) *
+        wo_dot_h.abs() * ggx_distribution(&half, &facing_normal, alpha) / cos_outgoing.abs();
+
+    if cos_incoming > 0.0 {
+        half_pdf * reflectance / (4.0 * wo_dot_h.abs())
+    } else {
+        if wo_dot_h * wi_dot_h >= 0.0 {
+            return 0.0
+        }
+        let denom = (wo_dot_h + eta * wi_dot_h).powi(2);
+        half_pdf * (1.0 - reflectance) * (eta.powi(2) * wi_dot_h).abs() / denom
+    }
+}
+
+///Value of the rough dielectric bsdf, without the cosine term.
+///Transmitted light is tinted by color
+pub fn rough_dielectric_bsdf(
+    normal: &UnitVec3, light_directions: &LightDirectionPair, index_of_refraction: f32,
+    alpha: f32, color: &Texture, record: &IntersectionRecord
+) -> Color3 {
+    let (facing_normal, eta_outgoing, eta_other) =
+        facing_interface(normal, light_directions.outgoing, index_of_refraction);
+    let n = *facing_normal.value();
+    let wo = *light_directions.outgoing.value();
+    let wi = *light_directions.incoming.value();
+    let (cos_outgoing, cos_incoming) = (wo.dot(n), wi.dot(n));
+    if cos_outgoing == 0.0 || cos_incoming == 0.0 {
+        return Color3::zero()
+    }
+
+    let eta = eta_other / eta_outgoing;
+    let half = generalized_half_vector(light_directions, &facing_normal, eta);
+    let (wo_dot_h, wi_dot_h) = (wo.dot(*half.value()), wi.dot(*half.value()));
+    let reflectance = fresnel_dielectric(wo_dot_h.abs(), eta_outgoing, eta_other);
+    let distribution = ggx_distribution(&half, &facing_normal, alpha);
+    let geometry =
+        ggx_smith_g1(light_directions.outgoing, &half, &facing_normal, alpha) *
+        ggx_smith_g1(light_directions.incoming, &half, &facing_normal, alpha);
+
+    if cos_incoming > 0.0 {
+        let value = reflectance * distribution * geometry /
+            (4.0 * cos_outgoing * cos_incoming);
+        Color3::new(value, value, value)
+    } else {
+        if wo_dot_h * wi_dot_h >= 0.0 {
+            return Color3::zero()
+        }
+        // the 1 / eta^2 radiance scaling cancels the eta^2 of the jacobian
+        let denom = (wo_dot_h + eta * wi_dot_h).powi(2);
+        let value = (1.0 - reflectance) * distribution * geometry *
+            (wi_dot_h * wo_dot_h).abs() / ((cos_outgoing * cos_incoming).abs() * denom);
+        color.color(record) * value
+    }
+}
@@ -0,0 +1,46 @@
+use utilities::math::*;
+use utilities::color::*;
+use utilities::sampler::NumberSequenceSampler;
+
+use engine::probability::*;
+use engine::intersectable::IntersectionRecord;
+use engine::texture::Texture;
+use super::{BSDFMaterial, BSDFSampleResult, LightDirectionPair};
+
+use std::f32::consts::PI;
+
+#[derive(Debug)]
+pub struct DiffuseBSDFMaterial {
+    color: Texture
+}
+impl DiffuseBSDFMaterial {
+    pub fn new(color: Texture) -> DiffuseBSDFMaterial {
+        DiffuseBSDFMaterial { color }
+    }
+}
+impl BSDFMaterial for DiffuseBSDFMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        let normal = &record.normal;
+        let untransformed_sample = CosineHemisphereWarper.sample(sampler);
+        let sample = transform_into(normal, &untransformed_sample);
+        BSDFSampleResult::from_direction(self, record, sample, outgoing_light_direction)
+    }
+
+    fn sample_pdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> f32 {
+        let sample = transform_from(&record.normal, light_directions.incoming.value());
+        CosineHemisphereWarper.pdf(sample.value())
+    }
+
+    fn brdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> Color3 {
+        let normal = &record.normal;
+        if light_directions.outgoing.value().dot(*normal.value()) < 0.0 ||
+            light_directions.incoming.value().dot(*normal.value()) < 0.0 {
+            return Color3::zero()
+        }
+
+        self.color.color(record) / PI
+    }
+}
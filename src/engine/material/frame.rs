@@ -0,0 +1,48 @@
+use utilities::math::*;
+
+use engine::probability::transform_into;
+use engine::intersectable::IntersectionRecord;
+
+///Orthonormal frame used to evaluate microfacet distributions.
+///The normal maps to [0, 1, 0], the tangent to [1, 0, 0] and the bitangent to [0, 0, 1]
+pub struct ShadingFrame {
+    tangent: Vec3,
+    normal: Vec3,
+    bitangent: Vec3
+}
+
+impl ShadingFrame {
+    ///A frame with an arbitrary tangent, for isotropic distributions
+    pub fn around(normal: &UnitVec3) -> ShadingFrame {
+        let tangent = *transform_into(normal, &Vec3::new(1.0, 0.0, 0.0)).value();
+        ShadingFrame::new(tangent, *normal.value())
+    }
+
+    ///A frame whose tangent follows dp/du of the surface, rotated around
+    ///the normal by rotation radians
+    pub fn along_surface(record: &IntersectionRecord, rotation: f32) -> ShadingFrame {
+        let normal = *record.normal.value();
+        let tangent = record.tangents[0] - normal * normal.dot(record.tangents[0]);
+        if tangent.magnitude2() == 0.0 || !tangent.magnitude2().is_finite() {
+            return ShadingFrame::around(&record.normal)
+        }
+        let tangent = tangent.normalize();
+        let tangent = tangent * rotation.cos() + normal.cross(tangent) * rotation.sin();
+        ShadingFrame::new(tangent, normal)
+    }
+
+    fn new(tangent: Vec3, normal: Vec3) -> ShadingFrame {
+        ShadingFrame { tangent, normal, bitangent: tangent.cross(normal) }
+    }
+
+    pub fn to_local(&self, direction: &UnitVec3) -> Vec3 {
+        let direction = direction.value();
+        Vec3::new(direction.dot(self.tangent), direction.dot(self.normal),
+                  direction.dot(self.bitangent))
+    }
+
+    pub fn from_local(&self, direction: &Vec3) -> UnitVec3 {
+        (self.tangent * direction.x + self.normal * direction.y + self.bitangent * direction.z)
+            .unit()
+    }
+}
@@ -0,0 +1,334 @@
+use utilities::math::*;
+use utilities::color::*;
+use utilities::sampler::{Sampler, NumberSequenceSampler};
+
+use engine::probability::*;
+use engine::intersectable::IntersectionRecord;
+use super::{BSDFMaterial, BSDFSampleResult, LightDirectionPair};
+use super::frame::ShadingFrame;
+
+use std::f32::consts::PI;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, BufReader};
+
+const THETA_HALF_RESOLUTION: usize = 90;
+const THETA_DIFF_RESOLUTION: usize = 90;
+const PHI_DIFF_RESOLUTION: usize = 180;
+const VALUE_COUNT: usize = THETA_HALF_RESOLUTION * THETA_DIFF_RESOLUTION * PHI_DIFF_RESOLUTION;
+
+///Factors converting the stored values of each channel to reflectance
+const CHANNEL_SCALES: [f32; 3] = [1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0];
+
+///Probability of sampling the cosine lobe instead of the tabulated half vectors
+const COSINE_SAMPLING_PROBABILITY: f32 = 0.25;
+
+///Isotropic brdf measured by Matusik et al., in the MERL .binary format.
+///Values are tabulated over the half angle and the difference angles of Rusinkiewicz's
+///parameterization. Sampling mixes a cosine lobe with half vectors drawn from the
+///table's average over the difference angles
+pub struct MeasuredBSDFMaterial {
+    ///indexed by theta_half, then theta_diff, then phi_diff
+    values: Vec<Color3>,
+    ///probability of sampling a half vector in each theta_half bin
+    half_angle_probabilities: Vec<f32>,
+    ///running sum of half_angle_probabilities, ending with 1
+    half_angle_cdf: Vec<f32>
+}
+
+impl MeasuredBSDFMaterial {
+    pub fn load(filepath: &str) -> Result<MeasuredBSDFMaterial, String> {
+        let file = File::open(filepath)
+            .map_err(|err| format!("could not open brdf {}: {}", filepath, err))?;
+        MeasuredBSDFMaterial::read(&mut BufReader::new(file))
+            .map_err(|err| format!("could not load brdf {}: {}", filepath, err))
+    }
+
+    ///Reads the three dimensions of the table, then the red, green and blue values
+    ///as little endian integers and doubles
+    pub fn read<R: Read>(reader: &mut R) -> Result<MeasuredBSDFMaterial, String> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(|err| err.to_string())?;
+        if bytes.len() < 12 {
+            return Err("missing header".into())
+        }
+        let dimensions: Vec<usize> = bytes[..12].chunks(4)
+            .map(|chunk| little_endian(chunk) as usize)
+            .collect();
+        if dimensions != [THETA_HALF_RESOLUTION, THETA_DIFF_RESOLUTION, PHI_DIFF_RESOLUTION] {
+            return Err(format!("unsupported dimensions {:?}", dimensions))
+        }
+        let data = &bytes[12..];
+        if data.len() != 3 * VALUE_COUNT * 8 {
+            return Err(format!("expected {} values, found {}", 3 * VALUE_COUNT, data.len() / 8))
+        }
+        //unmeasured values are negative
+        let channel = |channel: usize, index: usize| {
+            let offset = (channel * VALUE_COUNT + index) * 8;
+            let value = f64::from_bits(little_endian(&data[offset..offset + 8])) as f32;
+            (value * CHANNEL_SCALES[channel]).max(0.0)
+        };
+        let values = (0..VALUE_COUNT)
+            .map(|index| Color3::new(channel(0, index), channel(1, index), channel(2, index)))
+            .collect();
+        Ok(MeasuredBSDFMaterial::from_values(values))
+    }
+
+    ///Values indexed by theta_half, then theta_diff, then phi_diff, in 90, 90 and 180 bins
+    pub fn from_values(values: Vec<Color3>) -> MeasuredBSDFMaterial {
+        let bin_size = THETA_DIFF_RESOLUTION * PHI_DIFF_RESOLUTION;
+        let mut weights: Vec<f32> = (0..THETA_HALF_RESOLUTION)
+            .map(|bin| {
+                let total = values[bin * bin_size..(bin + 1) * bin_size].iter()
+                    .fold(0.0, |total, value| total + luminance(value));
+                //half vectors far from the normal mostly reflect light below the surface
+                let cos_theta_half =
+                    ((half_angle_bin_edge(bin) + half_angle_bin_edge(bin + 1)) / 2.0).cos();
+                total / bin_size as f32 * half_angle_bin_solid_angle(bin) * cos_theta_half
+            })
+            .collect();
+        //a black brdf still needs somewhere to send its samples
+        if !(weights.iter().sum::<f32>() > 0.0) {
+            weights = (0..THETA_HALF_RESOLUTION).map(half_angle_bin_solid_angle).collect();
+        }
+        let total: f32 = weights.iter().sum();
+        let half_angle_probabilities: Vec<f32> =
+            weights.iter().map(|weight| weight / total).collect();
+        let mut half_angle_cdf: Vec<f32> = half_angle_probabilities.iter()
+            .scan(0.0, |sum, probability| {
+                *sum += probability;
+                Some(*sum)
+            })
+            .collect();
+        half_angle_cdf[THETA_HALF_RESOLUTION - 1] = 1.0;
+        MeasuredBSDFMaterial { values, half_angle_probabilities, half_angle_cdf }
+    }
+
+    ///Tabulated value for directions in the local frame of ShadingFrame
+    fn lookup(&self, incoming: &Vec3, outgoing: &Vec3) -> Color3 {
+        let half = (incoming + outgoing).normalize();
+        let theta_half = half.y.max(-1.0).min(1.0).acos();
+        let phi_half = half.z.atan2(half.x);
+        //incoming in a frame where the half vector is the normal
+        let tangent = Vec3::new(theta_half.cos() * phi_half.cos(), -theta_half.sin(),
+                                theta_half.cos() * phi_half.sin());
+        let bitangent = Vec3::new(-phi_half.sin(), 0.0, phi_half.cos());
+        let theta_diff = incoming.dot(half).max(-1.0).min(1.0).acos();
+        let phi_diff = incoming.dot(bitangent).atan2(incoming.dot(tangent));
+
+        self.values[(theta_half_index(theta_half) * THETA_DIFF_RESOLUTION +
+            theta_diff_index(theta_diff)) * PHI_DIFF_RESOLUTION + phi_diff_index(phi_diff)]
+    }
+
+    ///Solid angle density of sampling the half vector, in the local frame
+    fn half_vector_pdf(&self, half: &Vec3) -> f32 {
+        let bin = theta_half_index(half.y.max(-1.0).min(1.0).acos());
+        self.half_angle_probabilities[bin] / half_angle_bin_solid_angle(bin)
+    }
+
+    fn sample_half_vector(&self, (u, v): (f32, f32)) -> Vec3 {
+        let bin = self.half_angle_cdf.iter()
+            .position(|&cumulative| u < cumulative)
+            .unwrap_or(THETA_HALF_RESOLUTION - 1);
+        let below = if bin == 0 { 0.0 } else { self.half_angle_cdf[bin - 1] };
+        let probability = self.half_angle_probabilities[bin];
+        let u = if probability > 0.0 { ((u - below) / probability).min(1.0) } else { 0.5 };
+        //uniform over the bin's solid angle
+        let (cos_low, cos_high) =
+            (half_angle_bin_edge(bin).cos(), half_angle_bin_edge(bin + 1).cos());
+        let cos_theta = cos_low + (cos_high - cos_low) * u;
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * v;
+        Vec3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin())
+    }
+}
+
+impl fmt::Debug for MeasuredBSDFMaterial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MeasuredBSDFMaterial")
+    }
+}
+
+fn little_endian(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |value, &byte| (value << 8) | byte as u64)
+}
+
+///The table's half angles are denser near the normal: bin i starts at (i / 90)^2 * pi / 2
+fn half_angle_bin_edge(bin: usize) -> f32 {
+    let fraction = bin as f32 / THETA_HALF_RESOLUTION as f32;
+    fraction * fraction * PI / 2.0
+}
+
+fn half_angle_bin_solid_angle(bin: usize) -> f32 {
+    2.0 * PI * (half_angle_bin_edge(bin).cos() - half_angle_bin_edge(bin + 1).cos())
+}
+
+fn theta_half_index(theta_half: f32) -> usize {
+    let index = (theta_half.max(0.0) / (PI / 2.0)).sqrt() * THETA_HALF_RESOLUTION as f32;
+    (index as usize).min(THETA_HALF_RESOLUTION - 1)
+}
+
+fn theta_diff_index(theta_diff: f32) -> usize {
+    let index = theta_diff.max(0.0) / (PI / 2.0) * THETA_DIFF_RESOLUTION as f32;
+    (index as usize).min(THETA_DIFF_RESOLUTION - 1)
+}
+
+///By reciprocity only half of the phi_diff range is stored
+fn phi_diff_index(phi_diff: f32) -> usize {
+    let phi_diff = if phi_diff < 0.0 { phi_diff + PI } else { phi_diff };
+    let index = phi_diff / PI * PHI_DIFF_RESOLUTION as f32;
+    (index as usize).min(PHI_DIFF_RESOLUTION - 1)
+}
+
+impl BSDFMaterial for MeasuredBSDFMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        let direction = if sampler.get_f32() < COSINE_SAMPLING_PROBABILITY {
+            transform_into(&record.normal, &CosineHemisphereWarper.sample(sampler))
+        } else {
+            let frame = ShadingFrame::around(&record.normal);
+            let outgoing = frame.to_local(outgoing_light_direction);
+            let half = self.sample_half_vector(sampler.get_2d_f32());
+            frame.from_local(&(half * (2.0 * outgoing.dot(half)) - outgoing))
+        };
+        if direction.value().dot(*record.normal.value()) <= 0.0 {
+            return BSDFSampleResult::rejected(direction)
+        }
+        BSDFSampleResult::from_direction(self, record, direction, outgoing_light_direction)
+    }
+
+    fn sample_pdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> f32 {
+        let frame = ShadingFrame::around(&record.normal);
+        let incoming = frame.to_local(light_directions.incoming);
+        let outgoing = frame.to_local(light_directions.outgoing);
+        if incoming.y <= 0.0 || outgoing.y <= 0.0 {
+            return 0.0
+        }
+        let half = (incoming + outgoing).normalize();
+        let half_vector_pdf = self.half_vector_pdf(&half) / (4.0 * outgoing.dot(half));
+        COSINE_SAMPLING_PROBABILITY * incoming.y / PI +
+            (1.0 - COSINE_SAMPLING_PROBABILITY) * half_vector_pdf
+    }
+
+    fn brdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> Color3 {
+        let frame = ShadingFrame::around(&record.normal);
+        let incoming = frame.to_local(light_directions.incoming);
+        let outgoing = frame.to_local(light_directions.outgoing);
+        if incoming.y <= 0.0 || outgoing.y <= 0.0 {
+            return Color3::zero()
+        }
+        self.lookup(&incoming, &outgoing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utilities::sampler::PseudorandomSampler;
+    use engine::validation::hemisphere_integral;
+
+    ///A table that is brighter near the specular direction
+    fn glossy_values() -> Vec<Color3> {
+        (0..VALUE_COUNT)
+            .map(|index| {
+                let theta_half_bin = index / (THETA_DIFF_RESOLUTION * PHI_DIFF_RESOLUTION);
+                let value = 0.1 + 50.0 / (1.0 + theta_half_bin as f32).powi(2);
+                Color3::new(value, value, value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_read_measured_brdf() {
+        let mut header = Vec::new();
+        for &dimension in &[90u32, 90, 180] {
+            header.extend((0..4).map(|byte| (dimension >> (8 * byte)) as u8));
+        }
+        let mut bytes = header.clone();
+        for channel in 0..3 {
+            //1500 is stored for a reflectance of 1 in the red channel
+            let value = (1500.0f64).to_bits();
+            let channel_bytes: Vec<u8> = (0..8).map(|byte| (value >> (8 * byte)) as u8).collect();
+            for _ in 0..VALUE_COUNT {
+                bytes.extend(&channel_bytes);
+            }
+            if channel == 0 {
+                assert!(MeasuredBSDFMaterial::read(&mut &bytes[..]).is_err());
+            }
+        }
+        let material = MeasuredBSDFMaterial::read(&mut &bytes[..]).ok().unwrap();
+        let record = IntersectionRecord::no_intersection();
+        let incoming = Vec3::new(0.6, 0.8, 0.0).unit();
+        let outgoing = Vec3::new(0.0, 0.6, 0.8).unit();
+        let brdf = material.brdf(&record, &LightDirectionPair {
+            incoming: &incoming, outgoing: &outgoing
+        });
+        assert!(apprx_eq(brdf.x, 1.0, 1e-5) && apprx_eq(brdf.y, 1.15, 1e-5) &&
+                apprx_eq(brdf.z, 1.66, 1e-5), "{:?}", brdf);
+
+        header[8] = 90;
+        assert!(MeasuredBSDFMaterial::read(&mut &header[..]).is_err());
+    }
+
+    #[test]
+    fn test_merl_index_mapping() {
+        for bin in 0..THETA_HALF_RESOLUTION {
+            let middle = (half_angle_bin_edge(bin) + half_angle_bin_edge(bin + 1)) / 2.0;
+            assert_eq!(theta_half_index(middle), bin);
+        }
+        assert_eq!(theta_half_index(PI / 2.0), THETA_HALF_RESOLUTION - 1);
+        assert_eq!(theta_diff_index(30.5f32.to_radians()), 30);
+        assert_eq!(theta_diff_index(PI / 2.0), THETA_DIFF_RESOLUTION - 1);
+        //phi_diff and phi_diff - pi are the same entry
+        assert_eq!(phi_diff_index(PI / 4.0), 45);
+        assert_eq!(phi_diff_index(-3.0 * PI / 4.0), 45);
+
+        //a table holding the index of each value
+        let material = MeasuredBSDFMaterial::from_values(
+            (0..VALUE_COUNT).map(|index| Color3::new(index as f32, 0.0, 0.0)).collect()
+        );
+        let index = |incoming: Vec3, outgoing: Vec3| material.lookup(&incoming, &outgoing).x;
+        let (sin, cos) = (30.5f32.to_radians().sin(), 30.5f32.to_radians().cos());
+        //mirrored directions have their half vector on the normal, where the difference
+        //angles are those of the incoming direction
+        let theta_diff_offset = 30.0 * PHI_DIFF_RESOLUTION as f32;
+        assert_eq!(index(Vec3::new(sin, cos, 0.0), Vec3::new(-sin, cos, 0.0)), theta_diff_offset);
+        assert_eq!(index(Vec3::new(0.0, cos, sin), Vec3::new(0.0, cos, -sin)),
+                   theta_diff_offset + 90.0);
+    }
+
+    #[test]
+    fn test_measured_sampling() {
+        let material = MeasuredBSDFMaterial::from_values(glossy_values());
+        let record = IntersectionRecord::no_intersection();
+        let outgoing = Vec3::new(0.6, 0.8, 0.0).unit();
+
+        //the pdf integrates to the fraction of samples that are above the surface
+        let total = hemisphere_integral(|incoming| {
+            material.sample_pdf(&record, &LightDirectionPair { incoming, outgoing: &outgoing })
+        });
+        let sample_count = 20000;
+        let mut sampler =
+            NumberSequenceSampler::new_from_sampler(&mut PseudorandomSampler, 4 * sample_count);
+        let above = (0..sample_count)
+            .filter(|_| material.sample(&record, &outgoing, &mut sampler).pdf > 0.0)
+            .count();
+        let expected = above as f32 / sample_count as f32;
+        assert!(apprx_eq(total, expected, 0.02), "pdf integrates to {}, not {}", total, expected);
+
+        //sampled directions report the pdf of sample_pdf
+        sampler.reset();
+        for _ in 0..1000 {
+            let sample = material.sample(&record, &outgoing, &mut sampler);
+            if sample.pdf == 0.0 {
+                continue
+            }
+            let pdf = material.sample_pdf(&record, &LightDirectionPair {
+                incoming: &sample.direction, outgoing: &outgoing
+            });
+            assert!(apprx_eq(sample.pdf, pdf, 1e-3 * pdf), "{} {}", sample.pdf, pdf);
+        }
+    }
+}
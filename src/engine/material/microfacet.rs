@@ -0,0 +1,161 @@
+use utilities::math::*;
+use utilities::color::*;
+use utilities::sampler::NumberSequenceSampler;
+
+use engine::probability::*;
+use engine::intersectable::IntersectionRecord;
+use engine::texture::Texture;
+use super::{BSDFMaterial, BSDFSampleResult, LightDirectionPair, ggx_alpha};
+use super::frame::ShadingFrame;
+use super::optics::*;
+use super::thin_film::{ThinFilm, Substrate};
+
+///Possibly anisotropic GGX roughness
+#[derive(Debug)]
+pub struct MicrofacetRoughness {
+    ///alpha along the tangent, and also along the bitangent unless y is given
+    pub x: Texture,
+    ///None for isotropic roughness
+    pub y: Option<Texture>,
+    ///radians the tangent is rotated by around the normal, starting from dp/du
+    pub tangent_rotation: f32
+}
+
+impl MicrofacetRoughness {
+    pub fn distribution(&self, record: &IntersectionRecord) -> (ShadingFrame, GGXDistribution) {
+        let alpha_x = ggx_alpha(&self.x, record);
+        match self.y {
+            Some(ref roughness_y) => (
+                ShadingFrame::along_surface(record, self.tangent_rotation),
+                GGXDistribution { alpha_x, alpha_y: ggx_alpha(roughness_y, record) }
+            ),
+            None => (ShadingFrame::around(&record.normal), GGXDistribution::isotropic(alpha_x))
+        }
+    }
+}
+
+///GGX reflection with a schlick fresnel term, tinted by color.
+///A thin film replaces the schlick term with the film's reflectance over a dielectric
+#[derive(Debug)]
+pub struct MicrofacetReflectiveBSDFMaterial {
+    index_of_refraction: f32,
+    roughness: MicrofacetRoughness,
+    color: Texture,
+    thin_film: Option<ThinFilm>
+}
+impl MicrofacetReflectiveBSDFMaterial {
+    pub fn new(index_of_refraction: f32, roughness: MicrofacetRoughness, color: Texture,
+               thin_film: Option<ThinFilm>) -> MicrofacetReflectiveBSDFMaterial
+    {
+        MicrofacetReflectiveBSDFMaterial {
+            index_of_refraction: index_of_refraction,
+            roughness: roughness,
+            color: color,
+            thin_film: thin_film
+        }
+    }
+}
+impl BSDFMaterial for MicrofacetReflectiveBSDFMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        let (frame, distribution) = self.roughness.distribution(record);
+        match sample_ggx_reflection(&frame, distribution, outgoing_light_direction, sampler) {
+            Some(incoming_light_direction) => BSDFSampleResult::from_direction(
+                self, record, incoming_light_direction, outgoing_light_direction
+            ),
+            None => BSDFSampleResult::rejected(outgoing_light_direction.clone())
+        }
+    }
+
+    fn sample_pdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> f32 {
+        let (frame, distribution) = self.roughness.distribution(record);
+        ggx_reflection_pdf(&frame, distribution, light_directions)
+    }
+
+    fn brdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> Color3 {
+        let (frame, distribution) = self.roughness.distribution(record);
+        match ggx_reflection_term(&frame, distribution, light_directions) {
+            Some((term, half)) => {
+                let fresnel = match self.thin_film {
+                    Some(ref thin_film) => thin_film.reflectance(
+                        record, light_directions.incoming.value().dot(*half.value()),
+                        &Substrate::Dielectric(self.index_of_refraction)
+                    ),
+                    None => {
+                        let f0 = fresnel_schlick_at_normal(self.index_of_refraction);
+                        let fresnel = fresnel_schlick(light_directions.incoming, &half, f0);
+                        Color3::new(fresnel, fresnel, fresnel)
+                    }
+                };
+                (self.color.color(record).mul_element_wise(fresnel) * term)
+                    .max_elem_wise(&Color3::zero())
+            },
+            None => Color3::zero()
+        }
+    }
+}
+
+///D * G / (4 cos_incoming cos_outgoing) for GGX reflection, along with the half vector.
+///None if either direction is below the surface
+pub fn ggx_reflection_term(
+    frame: &ShadingFrame, distribution: GGXDistribution, light_directions: &LightDirectionPair
+) -> Option<(f32, UnitVec3)> {
+    let outgoing = frame.to_local(light_directions.outgoing);
+    let incoming = frame.to_local(light_directions.incoming);
+    if incoming.y <= 0.0 || outgoing.y <= 0.0 {
+        return None
+    }
+    let half = (incoming + outgoing).normalize();
+    let term = distribution.distribution(&half) *
+        distribution.smith_g1(&incoming, &half) *
+        distribution.smith_g1(&outgoing, &half) /
+        (4.0 * incoming.y * outgoing.y);
+    Some((term, frame.from_local(&half)))
+}
+
+///Reflects the outgoing direction off a microfacet normal sampled from the visible normals.
+///None if the outgoing direction is below the surface
+pub fn sample_ggx_reflection(
+    frame: &ShadingFrame, distribution: GGXDistribution, outgoing_light_direction: &UnitVec3,
+    sampler: &mut NumberSequenceSampler
+) -> Option<UnitVec3> {
+    let outgoing = frame.to_local(outgoing_light_direction);
+    if outgoing.y <= 0.0 {
+        return None
+    }
+    let half_vector = frame.from_local(
+        &GGXVisibleNormalWarper { distribution, outgoing }.sample(sampler)
+    );
+    Some(reflection(outgoing_light_direction, &half_vector))
+}
+
+///Probability density of sample_ggx_reflection returning light_directions.incoming
+pub fn ggx_reflection_pdf(
+    frame: &ShadingFrame, distribution: GGXDistribution, light_directions: &LightDirectionPair
+) -> f32 {
+    let outgoing = frame.to_local(light_directions.outgoing);
+    let half = (outgoing + frame.to_local(light_directions.incoming)).normalize();
+    let outgoing_dot_half = outgoing.dot(half);
+    if outgoing.y <= 0.0 || !(outgoing_dot_half > 0.0) {
+        return 0.0
+    }
+    // jacobian of the reflection
+    GGXVisibleNormalWarper { distribution, outgoing }.pdf(&half) / (4.0 * outgoing_dot_half)
+}
+
+#[allow(dead_code)]
+fn geometry_neumann(
+    light_directions: &LightDirectionPair,
+    half_vector: &UnitVec3,
+    normal: &UnitVec3
+) -> f32 {
+    let wi = *light_directions.incoming.value();
+    let wo = *light_directions.outgoing.value();
+    let _h = *half_vector.value();
+    let n = *normal.value();
+    let num = n.dot(wi) * n.dot(wo);
+    let denom = n.dot(wi).max(n.dot(wo));
+    num / denom
+}
@@ -0,0 +1,47 @@
+use utilities::math::*;
+use utilities::color::*;
+use utilities::sampler::NumberSequenceSampler;
+
+use engine::intersectable::IntersectionRecord;
+use engine::texture::Texture;
+use super::{BSDFMaterial, BSDFSampleResult, LightDirectionPair};
+use super::optics::*;
+
+///Perfectly smooth reflector
+#[derive(Debug)]
+pub struct MirrorBSDFMaterial {
+    color: Texture
+}
+
+impl MirrorBSDFMaterial {
+    pub fn new(color: Texture) -> MirrorBSDFMaterial {
+        MirrorBSDFMaterial { color }
+    }
+}
+
+impl BSDFMaterial for MirrorBSDFMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        _sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        let (facing_normal, _, _) = facing_interface(&record.normal, outgoing_light_direction, 1.0);
+        BSDFSampleResult {
+            direction: reflection(outgoing_light_direction, &facing_normal),
+            pdf: 1.0,
+            value: self.color.color(record),
+            from_delta_lobe: true
+        }
+    }
+
+    fn sample_pdf(&self, _record: &IntersectionRecord, _light_directions: &LightDirectionPair) -> f32 {
+        0.0
+    }
+
+    fn brdf(&self, _record: &IntersectionRecord, _light_directions: &LightDirectionPair) -> Color3 {
+        Color3::zero()
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,73 @@
+use utilities::math::*;
+use utilities::color::*;
+use utilities::sampler::{Sampler, NumberSequenceSampler};
+
+use engine::intersectable::IntersectionRecord;
+use engine::texture::Texture;
+use super::{Material, BSDFMaterial, BSDFSampleResult, LightDirectionPair};
+
+use std::rc::Rc;
+
+///Linear blend of two materials. A weight of 0 gives only the first material
+///and a weight of 1 only the second
+#[derive(Debug)]
+pub struct MixBSDFMaterial {
+    first: Rc<Material>,
+    second: Rc<Material>,
+    weight: Texture
+}
+
+impl MixBSDFMaterial {
+    pub fn new(first: Rc<Material>, second: Rc<Material>, weight: Texture) -> MixBSDFMaterial {
+        MixBSDFMaterial { first, second, weight }
+    }
+
+    fn weight(&self, record: &IntersectionRecord) -> f32 {
+        self.weight.value(record).max(0.0).min(1.0)
+    }
+}
+
+impl BSDFMaterial for MixBSDFMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        let weight = self.weight(record);
+        let (chosen, probability) = if sampler.get_f32() < weight {
+            (&self.second, weight)
+        } else {
+            (&self.first, 1.0 - weight)
+        };
+        let sample = chosen.bsdf().sample(record, outgoing_light_direction, sampler);
+
+        // the other material can't contribute to a delta lobe's direction
+        if sample.from_delta_lobe || sample.pdf <= 0.0 {
+            return BSDFSampleResult {
+                pdf: sample.pdf * probability,
+                value: sample.value * probability,
+                ..sample
+            }
+        }
+        BSDFSampleResult::from_direction(self, record, sample.direction, outgoing_light_direction)
+    }
+
+    fn sample_pdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> f32 {
+        let weight = self.weight(record);
+        self.first.bsdf().sample_pdf(record, light_directions) * (1.0 - weight) +
+            self.second.bsdf().sample_pdf(record, light_directions) * weight
+    }
+
+    fn brdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> Color3 {
+        let weight = self.weight(record);
+        self.first.bsdf().brdf(record, light_directions) * (1.0 - weight) +
+            self.second.bsdf().brdf(record, light_directions) * weight
+    }
+
+    fn is_transmissive(&self) -> bool {
+        self.first.bsdf().is_transmissive() || self.second.bsdf().is_transmissive()
+    }
+
+    fn is_delta(&self) -> bool {
+        self.first.bsdf().is_delta() && self.second.bsdf().is_delta()
+    }
+}
@@ -0,0 +1,574 @@
+use utilities::codable::*;
+use utilities::math::*;
+use utilities::color::*;
+use utilities::sampler::NumberSequenceSampler;
+
+use super::probability::MIN_GGX_ALPHA;
+use super::intersectable::IntersectionRecord;
+use super::texture::{Texture, TextureSpec};
+
+use std::fmt::Debug;
+use std::rc::Rc;
+use std::collections::HashMap;
+
+mod optics;
+mod frame;
+mod diffuse;
+mod microfacet;
+mod mirror;
+mod dielectric;
+mod mix;
+mod coated;
+mod principled;
+mod normal_map;
+mod conductor;
+mod oren_nayar;
+mod subsurface;
+mod thin_film;
+mod measured;
+mod sheen;
+
+pub use self::diffuse::DiffuseBSDFMaterial;
+pub use self::microfacet::{MicrofacetReflectiveBSDFMaterial, MicrofacetRoughness};
+pub use self::mirror::MirrorBSDFMaterial;
+pub use self::dielectric::{DielectricBSDFMaterial, RoughDielectricBSDFMaterial};
+pub use self::mix::MixBSDFMaterial;
+pub use self::coated::CoatedBSDFMaterial;
+pub use self::principled::PrincipledBSDFMaterial;
+pub use self::normal_map::NormalMap;
+pub use self::oren_nayar::OrenNayarBSDFMaterial;
+pub use self::subsurface::SubsurfaceMaterial;
+pub use self::thin_film::{ThinFilm, ThinFilmSpec};
+pub use self::conductor::{ConductorBSDFMaterial, Metal, SpectrumSpec};
+pub use self::measured::MeasuredBSDFMaterial;
+pub use self::optics::fresnel_dielectric;
+pub use self::sheen::{SheenBSDFMaterial, SheenLayerBSDFMaterial, SheenAlbedo};
+
+#[derive(Debug)]
+pub enum Material {
+    BSDF(Box<BSDFMaterial>),
+    ///A material whose shading normal comes from a normal or bump map
+    NormalMapped(Box<Material>, NormalMap),
+    ///A material with a scattering medium inside it. Its bsdf is the medium's boundary
+    Subsurface(SubsurfaceMaterial),
+    ///A material that is only present where the opacity texture is. Intersections
+    ///skip the rest of the surface
+    Masked(Box<Material>, Texture)
+}
+
+impl Material {
+    pub fn bsdf(&self) -> &BSDFMaterial {
+        match *self {
+            Material::BSDF(ref bsdf) => bsdf.as_ref(),
+            Material::NormalMapped(ref material, _) => material.bsdf(),
+            Material::Subsurface(ref material) => material,
+            Material::Masked(ref material, _) => material.bsdf()
+        }
+    }
+
+    ///The material whose medium light entering the surface random walks through
+    pub fn subsurface(&self) -> Option<&SubsurfaceMaterial> {
+        match *self {
+            Material::BSDF(_) => None,
+            Material::NormalMapped(ref material, _) => material.subsurface(),
+            Material::Subsurface(ref material) => Some(material),
+            Material::Masked(ref material, _) => material.subsurface()
+        }
+    }
+
+    ///Probability of a ray hitting the surface at the intersection instead of passing through
+    pub fn opacity(&self, record: &IntersectionRecord) -> f32 {
+        match *self {
+            Material::Masked(ref material, ref opacity) =>
+                opacity.value(record).max(0.0).min(1.0) * material.opacity(record),
+            Material::NormalMapped(ref material, _) => material.opacity(record),
+            _ => 1.0
+        }
+    }
+
+    ///Replaces the record's normal with the material's shading normal.
+    ///Must be called before the record is passed to the bsdf
+    pub fn perturb_normal(&self, record: &mut IntersectionRecord) {
+        match *self {
+            Material::NormalMapped(ref material, ref normal_map) => {
+                normal_map.perturb(record);
+                material.perturb_normal(record);
+            },
+            Material::Masked(ref material, _) => material.perturb_normal(record),
+            _ => ()
+        }
+    }
+}
+
+pub fn default_material() -> Material {
+    DiffuseBSDFMaterial::new(Color3::new(1.0, 1.0, 1.0).into()).into()
+}
+
+pub struct LightDirectionPair<'a> {
+    pub incoming: &'a UnitVec3,
+    pub outgoing: &'a UnitVec3
+}
+
+///Roughness below which a dielectric is treated as perfectly smooth
+const SMOOTH_ROUGHNESS_THRESHOLD: f32 = MIN_GGX_ALPHA;
+
+///True if a roughness parameter gives a perfectly smooth surface everywhere
+fn is_smooth(roughness: &Option<TextureSpec>) -> bool {
+    match *roughness {
+        None => true,
+        Some(TextureSpec::Scalar(roughness)) => roughness <= SMOOTH_ROUGHNESS_THRESHOLD,
+        Some(_) => false
+    }
+}
+
+///Texture for an optional scalar parameter
+fn scalar_texture_or(spec: &Option<TextureSpec>, default: f32) -> Result<Texture, String> {
+    match *spec {
+        Some(ref spec) => spec.to_scalar_texture(),
+        None => Ok(Color3::new(default, default, default).into())
+    }
+}
+
+///Roughness for the microfacet shaders, anisotropic if roughness_y is given
+fn microfacet_roughness(
+    roughness: &TextureSpec, roughness_y: &Option<TextureSpec>, tangent_rotation: Option<f32>
+) -> Result<MicrofacetRoughness, String> {
+    Ok(MicrofacetRoughness {
+        x: roughness.to_scalar_texture()?,
+        y: match *roughness_y {
+            Some(ref roughness_y) => Some(roughness_y.to_scalar_texture()?),
+            None => None
+        },
+        tangent_rotation: tangent_rotation.unwrap_or(0.0)
+    })
+}
+
+fn thin_film_or_none(spec: &Option<ThinFilmSpec>) -> Result<Option<ThinFilm>, String> {
+    match *spec {
+        Some(ref spec) => Ok(Some(spec.to_thin_film()?)),
+        None => Ok(None)
+    }
+}
+
+///GGX alpha from a roughness texture
+fn ggx_alpha(roughness: &Texture, record: &IntersectionRecord) -> f32 {
+    roughness.value(record).max(MIN_GGX_ALPHA)
+}
+
+///A shader entry in the scene spec: a material, an optional normal or bump map and
+///an optional opacity mask.
+///Layered materials ignore the normal maps of the materials they contain
+#[derive(Deserialize)]
+pub struct MaterialEntrySpec {
+    #[serde(flatten)]
+    pub material: MaterialSpec,
+    ///tangent space normal map. Image maps are not sRGB decoded by default
+    pub normal_map: Option<TextureSpec>,
+    ///scalar height map, in units of bump_scale (default 1)
+    pub bump_map: Option<TextureSpec>,
+    pub bump_scale: Option<f32>,
+    ///probability of rays hitting the surface. Images use their alpha channel if they have one
+    pub opacity: Option<TextureSpec>
+}
+
+impl From<MaterialSpec> for MaterialEntrySpec {
+    fn from(material: MaterialSpec) -> MaterialEntrySpec {
+        MaterialEntrySpec {
+            material, normal_map: None, bump_map: None, bump_scale: None, opacity: None
+        }
+    }
+}
+
+///A material in the scene spec. Mix, Coated and Sheen refer to other entries by name.
+///Colors, roughnesses and the mix weight can be constants or textures
+#[derive(Deserialize)]
+#[serde(tag = "kind")]
+pub enum MaterialSpec {
+    Diffuse { color: TextureSpec },
+    ///rough diffuse. sigma in [0, 1], 0 matches Diffuse
+    OrenNayar { color: TextureSpec, sigma: TextureSpec },
+    ///roughness_y makes the roughness along the bitangent differ from the one along the
+    ///tangent. The tangent follows the texture's u axis, rotated by tangent_rotation radians.
+    ///thin_film coats the surface with an iridescent film
+    Microfacet {
+        color: TextureSpec,
+        ior: f32,
+        roughness: TextureSpec,
+        roughness_y: Option<TextureSpec>,
+        tangent_rotation: Option<f32>,
+        thin_film: Option<ThinFilmSpec>
+    },
+    Mirror { color: TextureSpec },
+    ///color tints transmitted light. A missing or zero roughness gives smooth glass
+    Dielectric { ior: f32, roughness: Option<TextureSpec>, color: Option<TextureSpec> },
+    ///weight 0 gives only first, weight 1 gives only second
+    Mix { first: String, second: String, weight: TextureSpec },
+    ///clearcoat over base, polished like a varnish unless roughness is above 0
+    Coated { base: String, ior: f32, roughness: Option<TextureSpec> },
+    ///fuzzy reflection of cloth fibers, brightest at grazing angles. roughness in [0, 1]
+    Cloth { color: TextureSpec, roughness: TextureSpec },
+    ///Cloth's sheen over base, which receives the light the sheen doesn't reflect
+    Sheen { base: String, color: TextureSpec, roughness: TextureSpec },
+    ///Disney's principled bsdf. Missing parameters get Blender's defaults
+    Principled {
+        base_color: TextureSpec,
+        metallic: Option<TextureSpec>,
+        roughness: Option<TextureSpec>,
+        specular: Option<TextureSpec>,
+        specular_tint: Option<TextureSpec>,
+        sheen: Option<TextureSpec>,
+        sheen_tint: Option<TextureSpec>,
+        clearcoat: Option<TextureSpec>,
+        clearcoat_gloss: Option<TextureSpec>,
+        anisotropic: Option<TextureSpec>,
+        tangent_rotation: Option<f32>,
+        transmission: Option<TextureSpec>,
+        ior: Option<f32>
+    },
+    ///translucent material for closed meshes. albedo is the color of thick regions,
+    ///mean_free_path how far light travels inside per channel, in scene units
+    Subsurface {
+        albedo: TextureSpec,
+        mean_free_path: CodableWrapper<Color3>,
+        ior: Option<f32>
+    },
+    ///metal with the complex index of refraction eta + ik, from a preset or given per channel
+    ///or as [wavelength in nm, value] samples. eta and k override the preset's values.
+    ///The metal is polished unless roughness is above 0, and roughness_y needs a roughness.
+    ///thin_film works as for Microfacet
+    Conductor {
+        metal: Option<Metal>,
+        eta: Option<SpectrumSpec>,
+        k: Option<SpectrumSpec>,
+        roughness: Option<TextureSpec>,
+        roughness_y: Option<TextureSpec>,
+        tangent_rotation: Option<f32>,
+        thin_film: Option<ThinFilmSpec>
+    },
+    ///isotropic brdf measured by MERL, loaded from a .binary file
+    Measured { file: String }
+}
+
+///Builds every material in specs, resolving the names used by layered materials
+pub fn build_materials(specs: &HashMap<String, MaterialEntrySpec>)
+    -> Result<HashMap<String, Rc<Material>>, String>
+{
+    let mut materials = HashMap::<String, Rc<Material>>::new();
+    let mut sheen_albedo = None;
+    for name in specs.keys() {
+        build_material(name, specs, &mut materials, &mut sheen_albedo, &mut Vec::new())?;
+    }
+    Ok(materials)
+}
+
+///pending holds the names currently being built, to catch materials that contain themselves.
+///sheen_albedo is built by the first Sheen material and shared with the others
+fn build_material(
+    name: &str, specs: &HashMap<String, MaterialEntrySpec>,
+    materials: &mut HashMap<String, Rc<Material>>,
+    sheen_albedo: &mut Option<Rc<SheenAlbedo>>, pending: &mut Vec<String>
+) -> Result<Rc<Material>, String> {
+    use self::MaterialSpec::*;
+    if let Some(material) = materials.get(name) {
+        return Ok(material.clone())
+    }
+    if pending.iter().any(|pending_name| pending_name == name) {
+        return Err(format!("shader {} contains itself", name))
+    }
+    let entry_spec = specs.get(name)
+        .ok_or_else(|| format!("shader {} not found", name))?;
+
+    pending.push(name.to_string());
+    let material: Material = match entry_spec.material {
+        Diffuse { ref color } =>
+            DiffuseBSDFMaterial::new(color.to_color_texture()?).into(),
+        OrenNayar { ref color, ref sigma } =>
+            OrenNayarBSDFMaterial::new(
+                color.to_color_texture()?, sigma.to_scalar_texture()?
+            ).into(),
+        Microfacet {
+            ref color, ior, ref roughness, ref roughness_y, tangent_rotation, ref thin_film
+        } => {
+            let roughness = microfacet_roughness(roughness, roughness_y, tangent_rotation)?;
+            MicrofacetReflectiveBSDFMaterial::new(
+                ior, roughness, color.to_color_texture()?, thin_film_or_none(thin_film)?
+            ).into()
+        },
+        Mirror { ref color } =>
+            MirrorBSDFMaterial::new(color.to_color_texture()?).into(),
+        Dielectric { ior, ref roughness, ref color } => {
+            let color = match *color {
+                Some(ref color) => color.to_color_texture()?,
+                None => Color3::new(1.0, 1.0, 1.0).into()
+            };
+            match *roughness {
+                Some(ref roughness_spec) if !is_smooth(roughness) => {
+                    let roughness = roughness_spec.to_scalar_texture()?;
+                    RoughDielectricBSDFMaterial::new(ior, roughness, color).into()
+                },
+                _ => DielectricBSDFMaterial::new(ior, color).into()
+            }
+        },
+        Mix { ref first, ref second, ref weight } => {
+            let first = build_material(first, specs, materials, sheen_albedo, pending)?;
+            let second = build_material(second, specs, materials, sheen_albedo, pending)?;
+            MixBSDFMaterial::new(first, second, weight.to_scalar_texture()?).into()
+        },
+        Coated { ref base, ior, ref roughness } => {
+            let base = build_material(base, specs, materials, sheen_albedo, pending)?;
+            let roughness = match *roughness {
+                Some(ref roughness_spec) if !is_smooth(roughness) =>
+                    Some(roughness_spec.to_scalar_texture()?),
+                _ => None
+            };
+            CoatedBSDFMaterial::new(base, ior, roughness).into()
+        },
+        Cloth { ref color, ref roughness } =>
+            SheenBSDFMaterial::new(
+                color.to_color_texture()?, roughness.to_scalar_texture()?
+            ).into(),
+        Sheen { ref base, ref color, ref roughness } => {
+            let base = build_material(base, specs, materials, sheen_albedo, pending)?;
+            let albedo = sheen_albedo.get_or_insert_with(|| Rc::new(SheenAlbedo::new())).clone();
+            SheenLayerBSDFMaterial::new(
+                base, albedo, color.to_color_texture()?, roughness.to_scalar_texture()?
+            ).into()
+        },
+        Principled {
+            ref base_color, ref metallic, ref roughness, ref specular, ref specular_tint,
+            ref sheen, ref sheen_tint, ref clearcoat, ref clearcoat_gloss, ref anisotropic,
+            tangent_rotation, ref transmission, ior
+        } => {
+            let transmission = match *transmission {
+                Some(TextureSpec::Scalar(transmission)) if transmission <= 0.0 => None,
+                Some(ref transmission) => Some(transmission.to_scalar_texture()?),
+                None => None
+            };
+            PrincipledBSDFMaterial {
+                base_color: base_color.to_color_texture()?,
+                metallic: scalar_texture_or(metallic, 0.0)?,
+                roughness: scalar_texture_or(roughness, 0.5)?,
+                specular: scalar_texture_or(specular, 0.5)?,
+                specular_tint: scalar_texture_or(specular_tint, 0.0)?,
+                sheen: scalar_texture_or(sheen, 0.0)?,
+                sheen_tint: scalar_texture_or(sheen_tint, 0.5)?,
+                clearcoat: scalar_texture_or(clearcoat, 0.0)?,
+                clearcoat_gloss: scalar_texture_or(clearcoat_gloss, 1.0)?,
+                anisotropic: scalar_texture_or(anisotropic, 0.0)?,
+                tangent_rotation: tangent_rotation.unwrap_or(0.0),
+                transmission,
+                index_of_refraction: ior.unwrap_or(1.5)
+            }.into()
+        },
+        Subsurface { ref albedo, ref mean_free_path, ior } =>
+            Material::Subsurface(SubsurfaceMaterial::new(
+                albedo.to_color_texture()?, mean_free_path.get(), ior.unwrap_or(1.4)
+            )),
+        Conductor {
+            metal, ref eta, ref k, ref roughness, ref roughness_y, tangent_rotation, ref thin_film
+        } => {
+            let preset = metal.map(|metal| metal.complex_ior());
+            let eta = match (eta, preset) {
+                (&Some(ref eta), _) => eta.to_rgb()?,
+                (&None, Some((eta, _))) => eta,
+                (&None, None) => return Err(format!("shader {} needs a metal or eta", name))
+            };
+            let k = match (k, preset) {
+                (&Some(ref k), _) => k.to_rgb()?,
+                (&None, Some((_, k))) => k,
+                (&None, None) => return Err(format!("shader {} needs a metal or k", name))
+            };
+            let roughness = match (roughness, roughness_y) {
+                (&Some(ref roughness_spec), _) if !is_smooth(roughness) || roughness_y.is_some() =>
+                    Some(microfacet_roughness(roughness_spec, roughness_y, tangent_rotation)?),
+                (&None, &Some(_)) =>
+                    return Err(format!("shader {} needs a roughness for roughness_y", name)),
+                _ => None
+            };
+            ConductorBSDFMaterial::new(eta, k, roughness, thin_film_or_none(thin_film)?).into()
+        },
+        Measured { ref file } =>
+            MeasuredBSDFMaterial::load(file)?.into()
+    };
+    pending.pop();
+
+    let material = match (&entry_spec.normal_map, &entry_spec.bump_map) {
+        (&Some(_), &Some(_)) =>
+            return Err(format!("shader {} has both a normal map and a bump map", name)),
+        (&Some(ref normal_map), &None) =>
+            Material::NormalMapped(Box::new(material),
+                                   NormalMap::TangentSpace(normal_map.to_scalar_texture()?)),
+        (&None, &Some(ref bump_map)) =>
+            Material::NormalMapped(Box::new(material), NormalMap::Bump {
+                height: bump_map.to_scalar_texture()?,
+                scale: entry_spec.bump_scale.unwrap_or(1.0)
+            }),
+        (&None, &None) => material
+    };
+    let material = match entry_spec.opacity {
+        Some(TextureSpec::Scalar(opacity)) if opacity >= 1.0 => material,
+        Some(ref opacity) => Material::Masked(Box::new(material), opacity.to_opacity_texture()?),
+        None => material
+    };
+    let material_ptr = Rc::new(material);
+    materials.insert(name.to_string(), material_ptr.clone());
+    Ok(material_ptr)
+}
+
+///A sampled incoming light direction.
+///For delta lobes, pdf is the probability of choosing the lobe and value is
+///the coefficient of its dirac delta, so value / pdf is still the sample's weight
+pub struct BSDFSampleResult {
+    pub direction: UnitVec3,
+    pub pdf: f32,
+    ///brdf * |n dot direction|
+    pub value: Color3,
+    ///true if the direction was sampled from a delta lobe
+    pub from_delta_lobe: bool
+}
+
+impl BSDFSampleResult {
+    ///Evaluates the pdf and value of a direction sampled from a non delta lobe
+    pub fn from_direction(
+        bsdf: &BSDFMaterial, record: &IntersectionRecord, direction: UnitVec3,
+        outgoing_light_direction: &UnitVec3
+    ) -> BSDFSampleResult {
+        let (pdf, value) = {
+            let light_directions = LightDirectionPair {
+                incoming: &direction,
+                outgoing: outgoing_light_direction
+            };
+            let cosine = direction.value().dot(*record.normal.value()).abs();
+            (bsdf.sample_pdf(record, &light_directions),
+             bsdf.brdf(record, &light_directions) * cosine)
+        };
+        BSDFSampleResult { direction, pdf, value, from_delta_lobe: false }
+    }
+
+    ///A sample that contributes nothing
+    pub fn rejected(direction: UnitVec3) -> BSDFSampleResult {
+        BSDFSampleResult { direction, pdf: 0.0, value: Color3::zero(), from_delta_lobe: false }
+    }
+
+    ///value / pdf, or zero if the direction can't be sampled
+    pub fn weight(&self) -> Color3 {
+        if self.pdf > 0.0 {
+            self.value / self.pdf
+        } else {
+            Color3::zero()
+        }
+    }
+}
+
+pub trait BSDFMaterial: Debug {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult;
+
+    ///Probability density of sample returning light_directions.incoming.
+    ///Always 0 for delta lobes
+    fn sample_pdf(
+        &self, record: &IntersectionRecord, light_directions: &LightDirectionPair
+    ) -> f32;
+
+    ///Value of the bsdf, without the cosine term.
+    ///Ensures that all components of Color3 are positive. Always 0 for delta lobes
+    fn brdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> Color3;
+
+    ///Returns true if light can arrive from below the surface
+    fn is_transmissive(&self) -> bool {
+        false
+    }
+
+    ///Returns true if all of the bsdf's lobes are delta distributions.
+    ///Such bsdfs only scatter light in directions returned by sample,
+    ///so connecting them to lights is pointless
+    fn is_delta(&self) -> bool {
+        false
+    }
+}
+impl<T> From<T> for Material
+    where T: BSDFMaterial + 'static
+{
+    fn from(bsdf_material: T) -> Self {
+        Material::BSDF(Box::new(bsdf_material))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diffuse_spec() -> MaterialSpec {
+        MaterialSpec::Diffuse {
+            color: TextureSpec::Color(CodableWrapper(Color3::new(0.5, 0.5, 0.5)))
+        }
+    }
+
+    #[test]
+    fn test_build_layered_materials() {
+        let mut specs = HashMap::<String, MaterialEntrySpec>::new();
+        specs.insert("base".into(), diffuse_spec().into());
+        specs.insert("coated".into(),
+                     MaterialSpec::Coated { base: "base".into(), ior: 1.5, roughness: None }.into());
+        specs.insert("mix".into(), MaterialSpec::Mix {
+            first: "base".into(), second: "coated".into(), weight: TextureSpec::Scalar(0.5)
+        }.into());
+        let materials = build_materials(&specs).ok().unwrap();
+        assert_eq!(materials.len(), 3);
+        //layered materials share the materials they refer to
+        assert_eq!(Rc::strong_count(&materials["base"]), 3);
+
+        specs.insert("missing".into(),
+                     MaterialSpec::Coated { base: "nothing".into(), ior: 1.5, roughness: None }
+                         .into());
+        assert!(build_materials(&specs).is_err());
+        specs.remove("missing");
+
+        specs.insert("cycle".into(), MaterialSpec::Mix {
+            first: "base".into(), second: "cycle".into(), weight: TextureSpec::Scalar(0.5)
+        }.into());
+        assert!(build_materials(&specs).is_err());
+    }
+
+    #[test]
+    fn test_build_opacity_masks() {
+        let mut specs = HashMap::<String, MaterialEntrySpec>::new();
+        let mut translucent: MaterialEntrySpec = diffuse_spec().into();
+        translucent.opacity = Some(TextureSpec::Scalar(0.25));
+        specs.insert("translucent".into(), translucent);
+        let mut opaque: MaterialEntrySpec = diffuse_spec().into();
+        opaque.opacity = Some(TextureSpec::Scalar(1.0));
+        specs.insert("opaque".into(), opaque);
+        let materials = build_materials(&specs).ok().unwrap();
+
+        let record = IntersectionRecord::no_intersection();
+        assert_eq!(materials["translucent"].opacity(&record), 0.25);
+        assert_eq!(materials["opaque"].opacity(&record), 1.0);
+        match *materials["opaque"] {
+            Material::BSDF(_) => (),
+            _ => panic!("fully opaque materials don't need a mask")
+        }
+    }
+
+    #[test]
+    fn test_build_anisotropic_conductor() {
+        let conductor = |roughness: Option<f32>| MaterialSpec::Conductor {
+            metal: Some(Metal::Au),
+            eta: None,
+            k: None,
+            roughness: roughness.map(TextureSpec::Scalar),
+            roughness_y: Some(TextureSpec::Scalar(0.3)),
+            tangent_rotation: None,
+            thin_film: None
+        };
+        let mut specs = HashMap::<String, MaterialEntrySpec>::new();
+        specs.insert("brushed".into(), conductor(Some(0.0)).into());
+        let materials = build_materials(&specs).ok().unwrap();
+        assert!(!materials["brushed"].bsdf().is_delta());
+
+        specs.insert("missing_roughness".into(), conductor(None).into());
+        assert!(build_materials(&specs).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use utilities::math::*;
+
+use engine::probability::transform_into;
+use engine::intersectable::IntersectionRecord;
+use engine::texture::Texture;
+
+///Step in texture coordinates used to difference bump maps
+const BUMP_DELTA: f32 = 0.0005;
+
+///Replaces the interpolated normal of an intersection with a shading normal
+#[derive(Debug)]
+pub enum NormalMap {
+    ///Colors in [0, 1] map to coordinates in [-1, 1] along dp/du, dp/dv and the normal
+    TangentSpace(Texture),
+    ///Heights times scale displace the surface along the normal
+    Bump { height: Texture, scale: f32 }
+}
+
+impl NormalMap {
+    pub fn perturb(&self, record: &mut IntersectionRecord) {
+        let normal = *record.normal.value();
+        let (tangent, dp_dv) = surface_tangents(record);
+        let perturbed = match *self {
+            NormalMap::TangentSpace(ref texture) => {
+                //MikkTSpace style frame. The bitangent keeps the side of dp/dv, so that
+                //maps on mirrored uvs are mirrored too
+                let tangent = tangent.normalize();
+                let bitangent = normal.cross(tangent);
+                let bitangent = if bitangent.dot(dp_dv) < 0.0 { -bitangent } else { bitangent };
+                let coordinates = texture.color(record) * 2.0 - Vec3::new(1.0, 1.0, 1.0);
+                tangent * coordinates.x + bitangent * coordinates.y + normal * coordinates.z
+            },
+            NormalMap::Bump { ref height, scale } => {
+                let center = height.value(record);
+                let du = height.value(&offset_record(record, BUMP_DELTA, 0.0)) - center;
+                let dv = height.value(&offset_record(record, 0.0, BUMP_DELTA)) - center;
+                let tangent = tangent + normal * (du * scale / BUMP_DELTA);
+                let bitangent = dp_dv + normal * (dv * scale / BUMP_DELTA);
+                let perturbed = tangent.cross(bitangent);
+                if perturbed.dot(normal) < 0.0 { -perturbed } else { perturbed }
+            }
+        };
+        if perturbed.magnitude2() > 0.0 && perturbed.magnitude2().is_finite() {
+            record.normal = perturbed.unit();
+        }
+    }
+}
+
+///dp/du and dp/dv projected onto the plane of the interpolated normal
+fn surface_tangents(record: &IntersectionRecord) -> (Vec3, Vec3) {
+    let normal = *record.normal.value();
+    let project = |vector: Vec3| vector - normal * normal.dot(vector);
+    let tangent = project(record.tangents[0]);
+    let bitangent = project(record.tangents[1]);
+    if tangent.magnitude2() > 0.0 && bitangent.magnitude2() > 0.0 {
+        (tangent, bitangent)
+    } else {
+        //degenerate uvs
+        (*transform_into(&record.normal, &Vec3::new(1.0, 0.0, 0.0)).value(),
+         *transform_into(&record.normal, &Vec3::new(0.0, 0.0, -1.0)).value())
+    }
+}
+
+///The record moved by (du, dv) in texture coordinates along the surface
+fn offset_record(record: &IntersectionRecord, du: f32, dv: f32) -> IntersectionRecord {
+    let mut offset = record.clone();
+    offset.uv += Vec2::new(du, dv);
+    offset.position += record.tangents[0] * du + record.tangents[1] * dv;
+    offset.object_position += record.object_tangents[0] * du + record.object_tangents[1] * dv;
+    offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate serde_yaml;
+    use utilities::color::Color3;
+    use engine::meshutils::tangents_from_texcoords;
+    use engine::texture::TextureSpec;
+
+    fn record_on_plane() -> IntersectionRecord {
+        let mut record = IntersectionRecord::no_intersection();
+        //uv (0, 0) is at the origin, u runs along x and v along -z so the frame is right handed
+        record.tangents = [Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)];
+        record.object_tangents = record.tangents;
+        record.t = 1.0;
+        record
+    }
+
+    ///The mirrored half of a symmetric model, where u runs along -x
+    fn record_on_mirrored_plane() -> IntersectionRecord {
+        let positions =
+            [Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)];
+        let texcoords = [Vec2::new(1.0, 0.0), Vec2::new(0.0, 0.0), Vec2::new(1.0, 1.0)];
+        let mut record = record_on_plane();
+        record.tangents = tangents_from_texcoords(&positions, &texcoords);
+        record.object_tangents = record.tangents;
+        record
+    }
+
+    #[test]
+    fn test_tangent_space_normal_map() {
+        let mut record = record_on_plane();
+        NormalMap::TangentSpace(Color3::new(0.5, 0.5, 1.0).into()).perturb(&mut record);
+        assert!(record.normal.value().y > 0.9999);
+
+        let mut record = record_on_plane();
+        NormalMap::TangentSpace(Color3::new(1.0, 0.5, 0.5).into()).perturb(&mut record);
+        assert!(record.normal.value().x > 0.9999);
+
+        let mut record = record_on_plane();
+        NormalMap::TangentSpace(Color3::new(0.5, 1.0, 0.5).into()).perturb(&mut record);
+        assert!(record.normal.value().z < -0.9999);
+    }
+
+    #[test]
+    fn test_mirrored_uv_normal_map() {
+        let mut record = record_on_mirrored_plane();
+        NormalMap::TangentSpace(Color3::new(1.0, 0.5, 0.5).into()).perturb(&mut record);
+        assert!(record.normal.value().x < -0.9999);
+
+        //v still runs along -z, so green tilts the normal the same way as on the other half
+        let mut record = record_on_mirrored_plane();
+        NormalMap::TangentSpace(Color3::new(0.5, 1.0, 0.5).into()).perturb(&mut record);
+        assert!(record.normal.value().z < -0.9999);
+    }
+
+    #[test]
+    fn test_bump_map() {
+        let mut record = record_on_plane();
+        NormalMap::Bump { height: Color3::new(0.3, 0.3, 0.3).into(), scale: 2.0 }
+            .perturb(&mut record);
+        assert!(record.normal.value().y > 0.9999);
+
+        //heights rising along u tilt the normal away from u, whichever way u runs
+        let height_spec: TextureSpec =
+            serde_yaml::from_str("{node: Channel, input: {node: UV}, index: 0}").unwrap();
+        let slope = || NormalMap::Bump {
+            height: height_spec.to_scalar_texture().ok().unwrap(), scale: 1.0
+        };
+        let half = 0.5f32.sqrt();
+        let mut record = record_on_plane();
+        slope().perturb(&mut record);
+        assert!(apprx_eq(record.normal.value().x, -half, 1e-3), "{:?}", record.normal);
+        let mut record = record_on_mirrored_plane();
+        slope().perturb(&mut record);
+        assert!(apprx_eq(record.normal.value().x, half, 1e-3), "{:?}", record.normal);
+    }
+}
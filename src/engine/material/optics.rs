@@ -0,0 +1,89 @@
+//!Reflection, refraction and fresnel helpers shared by materials
+
+use utilities::math::*;
+
+pub fn half_vector(a: &UnitVec3, b: &UnitVec3) -> UnitVec3 {
+    (a.value() + b.value()).unit()
+}
+
+pub fn reflection(light_outgoing: &UnitVec3, normal: &UnitVec3) -> UnitVec3 {
+    let wo = *light_outgoing.value();
+    let n = *normal.value();
+    { -wo + n * (2.0 * wo.dot(n)) }.unit()
+}
+
+///Refracts the outgoing direction through the surface.
+///normal must be on the same side as light_outgoing and eta is
+///the ior on the outgoing side over the ior on the other side.
+///returns None on total internal reflection
+pub fn refraction(light_outgoing: &UnitVec3, normal: &UnitVec3, eta: f32) -> Option<UnitVec3> {
+    let wo = *light_outgoing.value();
+    let n = *normal.value();
+    let cos_outgoing = wo.dot(n);
+    let sin2_transmitted = eta.powi(2) * (1.0 - cos_outgoing.powi(2)).max(0.0);
+    if sin2_transmitted >= 1.0 {
+        return None
+    }
+    let cos_transmitted = (1.0 - sin2_transmitted).sqrt();
+    Some({ -wo * eta + n * (eta * cos_outgoing - cos_transmitted) }.unit())
+}
+
+///Returns the normal flipped to face the outgoing direction, along with the
+///index of refraction on the outgoing side and on the opposite side
+pub fn facing_interface(normal: &UnitVec3, outgoing_light_direction: &UnitVec3,
+                        index_of_refraction: f32) -> (UnitVec3, f32, f32) {
+    if outgoing_light_direction.value().dot(*normal.value()) >= 0.0 {
+        (normal.clone(), 1.0, index_of_refraction)
+    } else {
+        (-normal.clone(), index_of_refraction, 1.0)
+    }
+}
+
+pub fn fresnel_schlick_at_normal(index_of_refraction: f32) -> f32 {
+    let n = index_of_refraction;
+    ((n - 1.0) / (n + 1.0)).powi(2)
+}
+
+pub fn fresnel_schlick(
+    incoming_light_direction: &UnitVec3,
+    half_vector: &UnitVec3,
+    normal_reflectance: f32
+) -> f32 {
+    let f0 = normal_reflectance;
+    let wi = incoming_light_direction.value();
+    let m = half_vector.value();
+    f0 + (1.0 - f0) * ((1.0 - wi.dot(*m)).powi(5))
+}
+
+///Exact fresnel reflectance for unpolarized light at a dielectric interface.
+///cos_incident is measured on the side with ior eta_incident
+pub fn fresnel_dielectric(cos_incident: f32, eta_incident: f32, eta_transmitted: f32) -> f32 {
+    let cos_i = cos_incident.max(0.0).min(1.0);
+    let sin_t = eta_incident / eta_transmitted * (1.0 - cos_i.powi(2)).max(0.0).sqrt();
+    if sin_t >= 1.0 {
+        return 1.0 //total internal reflection
+    }
+    let cos_t = (1.0 - sin_t.powi(2)).max(0.0).sqrt();
+    let r_parallel = (eta_transmitted * cos_i - eta_incident * cos_t) /
+        (eta_transmitted * cos_i + eta_incident * cos_t);
+    let r_perpendicular = (eta_incident * cos_i - eta_transmitted * cos_t) /
+        (eta_incident * cos_i + eta_transmitted * cos_t);
+    (r_parallel.powi(2) + r_perpendicular.powi(2)) / 2.0
+}
+
+///Exact fresnel reflectance for unpolarized light arriving from vacuum at a conductor
+///with complex index of refraction eta + ik
+pub fn fresnel_conductor(cos_incident: f32, eta: f32, k: f32) -> f32 {
+    let cos2 = cos_incident.max(0.0).min(1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let t0 = eta.powi(2) - k.powi(2) - sin2;
+    let a2_plus_b2 = (t0.powi(2) + 4.0 * eta.powi(2) * k.powi(2)).sqrt();
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * cos2.sqrt() * a;
+    let r_perpendicular = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2.powi(2);
+    let t4 = t2 * sin2;
+    let r_parallel = r_perpendicular * (t3 - t4) / (t3 + t4);
+    (r_parallel + r_perpendicular) / 2.0
+}
@@ -0,0 +1,132 @@
+use utilities::math::*;
+use utilities::color::*;
+use utilities::sampler::NumberSequenceSampler;
+
+use engine::probability::*;
+use engine::intersectable::IntersectionRecord;
+use engine::texture::Texture;
+use super::{BSDFMaterial, BSDFSampleResult, LightDirectionPair};
+
+use std::f32::consts::PI;
+
+const FON_CONSTANT_1: f32 = 0.5 - 2.0 / (3.0 * PI);
+const FON_CONSTANT_2: f32 = 2.0 / 3.0 - 28.0 / (15.0 * PI);
+
+///Rough diffuse reflection: Fujii's improved Oren-Nayar model plus a multiple scattering
+///term that restores the energy it loses (Portsmouth et al., EON).
+///sigma is in [0, 1], 0 is lambertian
+#[derive(Debug)]
+pub struct OrenNayarBSDFMaterial {
+    color: Texture,
+    sigma: Texture
+}
+impl OrenNayarBSDFMaterial {
+    pub fn new(color: Texture, sigma: Texture) -> OrenNayarBSDFMaterial {
+        OrenNayarBSDFMaterial { color, sigma }
+    }
+}
+
+///Fraction of light leaving in any direction when lit from cos_theta, for a white surface
+fn single_scattering_albedo(cos_theta: f32, sigma: f32) -> f32 {
+    let cos_theta = cos_theta.max(1e-4).min(1.0);
+    let a = 1.0 / (1.0 + FON_CONSTANT_1 * sigma);
+    let b = sigma * a;
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    let g = sin_theta * (cos_theta.acos() - sin_theta * cos_theta) +
+        2.0 / 3.0 * ((sin_theta / cos_theta) * (1.0 - sin_theta.powi(3)) - sin_theta);
+    a + b / PI * g
+}
+
+impl BSDFMaterial for OrenNayarBSDFMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        let untransformed_sample = CosineHemisphereWarper.sample(sampler);
+        let sample = transform_into(&record.normal, &untransformed_sample);
+        BSDFSampleResult::from_direction(self, record, sample, outgoing_light_direction)
+    }
+
+    fn sample_pdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> f32 {
+        let sample = transform_from(&record.normal, light_directions.incoming.value());
+        CosineHemisphereWarper.pdf(sample.value())
+    }
+
+    fn brdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> Color3 {
+        let normal = record.normal.value();
+        let (incoming, outgoing) =
+            (light_directions.incoming.value(), light_directions.outgoing.value());
+        let cos_incoming = incoming.dot(*normal);
+        let cos_outgoing = outgoing.dot(*normal);
+        if cos_outgoing < 0.0 || cos_incoming < 0.0 {
+            return Color3::zero()
+        }
+
+        let color = self.color.color(record);
+        let sigma = self.sigma.value(record).max(0.0).min(1.0);
+        let a = 1.0 / (1.0 + FON_CONSTANT_1 * sigma);
+
+        let s = incoming.dot(*outgoing) - cos_incoming * cos_outgoing;
+        let s_over_t = if s > 0.0 { s / cos_incoming.max(cos_outgoing) } else { s };
+        let single_scattering = color * (a * (1.0 + sigma * s_over_t) / PI);
+
+        let average_albedo = a * (1.0 + FON_CONSTANT_2 * sigma);
+        let one = Color3::new(1.0, 1.0, 1.0);
+        let multiple_albedo = (color.mul_element_wise(color) * average_albedo)
+            .div_element_wise(one - color * (1.0 - average_albedo));
+        let missing = |cosine: f32| (1.0 - single_scattering_albedo(cosine, sigma)).max(1e-7);
+        let multiple_scattering = multiple_albedo *
+            (missing(cos_incoming) * missing(cos_outgoing) /
+             (1.0 - average_albedo).max(1e-7) / PI);
+
+        single_scattering + multiple_scattering
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engine::validation::{brdf_albedo, hemisphere_integral, outgoing_at};
+
+    fn oren_nayar(color: f32, sigma: f32) -> OrenNayarBSDFMaterial {
+        OrenNayarBSDFMaterial::new(
+            Color3::new(color, color, color).into(), Color3::new(sigma, sigma, sigma).into()
+        )
+    }
+
+    #[test]
+    fn test_oren_nayar_limits() {
+        let record = IntersectionRecord::no_intersection();
+        let incoming = outgoing_at(0.3);
+        let mirrored = Vec3::new(-incoming.value().x, incoming.value().y, -incoming.value().z)
+            .unit();
+        let brdf = |material: &OrenNayarBSDFMaterial, outgoing: &UnitVec3|
+            material.brdf(&record, &LightDirectionPair { incoming: &incoming, outgoing }).x;
+
+        //sigma 0 is lambertian
+        let smooth = oren_nayar(0.7, 0.0);
+        for outgoing in [&incoming, &mirrored, &outgoing_at(1.0)].iter() {
+            assert!(apprx_eq(brdf(&smooth, outgoing), 0.7 / PI, 1e-5));
+        }
+
+        //rough surfaces reflect more back towards the light than forwards
+        let rough = oren_nayar(0.7, 1.0);
+        assert!(brdf(&rough, &incoming) > 1.5 * brdf(&rough, &mirrored));
+
+        //the closed form average albedo is the cosine weighted average of the albedo
+        for &sigma in &[0.3f32, 1.0] {
+            let average = hemisphere_integral(|direction| {
+                let cosine = direction.value().y;
+                single_scattering_albedo(cosine, sigma) * cosine / PI
+            });
+            let expected = (1.0 + FON_CONSTANT_2 * sigma) / (1.0 + FON_CONSTANT_1 * sigma);
+            assert!(apprx_eq(average, expected, 1e-3), "{} instead of {}", average, expected);
+        }
+
+        //the multiple scattering term restores the energy lost by single scattering
+        for &cos_outgoing in &[1.0f32, 0.5, 0.1] {
+            let albedo = brdf_albedo(&oren_nayar(1.0, 1.0), &outgoing_at(cos_outgoing));
+            assert!(apprx_eq(albedo, 1.0, 0.01), "albedo {} at cos {}", albedo, cos_outgoing);
+        }
+    }
+}
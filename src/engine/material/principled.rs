@@ -0,0 +1,313 @@
+use utilities::math::*;
+use utilities::color::*;
+use utilities::sampler::{Sampler, NumberSequenceSampler};
+
+use engine::probability::*;
+use engine::intersectable::IntersectionRecord;
+use engine::texture::Texture;
+use super::{BSDFMaterial, BSDFSampleResult, LightDirectionPair};
+use super::frame::ShadingFrame;
+use super::optics::*;
+use super::microfacet::{sample_ggx_reflection, ggx_reflection_pdf};
+use super::dielectric::{sample_rough_dielectric, rough_dielectric_pdf, rough_dielectric_bsdf};
+
+use std::f32::consts::PI;
+
+///GGX alpha of the clearcoat's masking term, fixed by the Disney model
+const CLEARCOAT_GEOMETRY_ALPHA: f32 = 0.25;
+
+///Disney's principled bsdf. Burley 2012, "Physically Based Shading at Disney",
+///with the specular transmission of Burley 2015, "Extending the Disney BRDF to a BSDF
+///with Integrated Subsurface Scattering".
+///Every parameter but base_color is a scalar in [0, 1]. Like Blender and glTF,
+///roughness is squared to get the GGX alpha
+#[derive(Debug)]
+pub struct PrincipledBSDFMaterial {
+    pub base_color: Texture,
+    pub metallic: Texture,
+    pub roughness: Texture,
+    ///scales the reflectance at normal incidence of non metals, 0.5 is 4%
+    pub specular: Texture,
+    pub specular_tint: Texture,
+    pub sheen: Texture,
+    pub sheen_tint: Texture,
+    pub clearcoat: Texture,
+    pub clearcoat_gloss: Texture,
+    ///stretches the specular highlight along the tangent
+    pub anisotropic: Texture,
+    ///radians the tangent is rotated by around the normal, starting from dp/du
+    pub tangent_rotation: f32,
+    ///None for opaque materials
+    pub transmission: Option<Texture>,
+    ///used by the transmission lobe
+    pub index_of_refraction: f32
+}
+
+///The parameters at an intersection
+struct Parameters {
+    base_color: Color3,
+    roughness: f32,
+    ///isotropic alpha of the transmission lobe
+    alpha: f32,
+    specular_distribution: GGXDistribution,
+    ///reflectance of the specular lobe at normal incidence
+    specular_color: Color3,
+    sheen_color: Color3,
+    clearcoat: f32,
+    clearcoat_alpha: f32,
+    diffuse_weight: f32,
+    specular_weight: f32,
+    transmission_weight: f32
+}
+
+///Index of each lobe in Parameters::lobe_probabilities
+const DIFFUSE: usize = 0;
+const SPECULAR: usize = 1;
+const CLEARCOAT: usize = 2;
+const TRANSMISSION: usize = 3;
+
+impl Parameters {
+    ///Probabilities of sampling each lobe. All zero if nothing can be sampled
+    fn lobe_probabilities(&self, cos_outgoing: f32) -> [f32; 4] {
+        let mut probabilities = [0.0; 4];
+        probabilities[TRANSMISSION] = self.transmission_weight;
+        if cos_outgoing > 0.0 {
+            let fresnel_weight = schlick_weight(cos_outgoing);
+            probabilities[DIFFUSE] = self.diffuse_weight *
+                (luminance(&self.base_color) + luminance(&self.sheen_color));
+            probabilities[SPECULAR] = self.specular_weight *
+                lerp(luminance(&self.specular_color), 1.0, fresnel_weight);
+            probabilities[CLEARCOAT] = 0.25 * self.clearcoat * lerp(0.04, 1.0, fresnel_weight);
+        }
+        let total: f32 = probabilities.iter().sum();
+        if total > 0.0 {
+            for probability in probabilities.iter_mut() {
+                *probability /= total;
+            }
+        }
+        probabilities
+    }
+}
+
+impl PrincipledBSDFMaterial {
+    fn parameters(&self, record: &IntersectionRecord) -> Parameters {
+        let scalar = |texture: &Texture| texture.value(record).max(0.0).min(1.0);
+        let base_color = self.base_color.color(record);
+        let metallic = scalar(&self.metallic);
+        let roughness = scalar(&self.roughness);
+        let transmission = self.transmission.as_ref().map(&scalar).unwrap_or(0.0);
+
+        let white = Color3::new(1.0, 1.0, 1.0);
+        let base_luminance = luminance(&base_color);
+        let tint = if base_luminance > 0.0 { base_color / base_luminance } else { white };
+        let alpha = roughness.powi(2);
+        let aspect = (1.0 - 0.9 * scalar(&self.anisotropic)).sqrt();
+        let dielectric_specular = lerp_color(white, tint, scalar(&self.specular_tint)) *
+            (0.08 * scalar(&self.specular));
+
+        Parameters {
+            base_color,
+            roughness,
+            alpha: alpha.max(MIN_GGX_ALPHA),
+            specular_distribution: GGXDistribution {
+                alpha_x: (alpha / aspect).max(MIN_GGX_ALPHA),
+                alpha_y: (alpha * aspect).max(MIN_GGX_ALPHA)
+            },
+            specular_color: lerp_color(dielectric_specular, base_color, metallic),
+            sheen_color: lerp_color(white, tint, scalar(&self.sheen_tint)) * scalar(&self.sheen),
+            clearcoat: scalar(&self.clearcoat),
+            clearcoat_alpha: lerp(0.1, 0.001, scalar(&self.clearcoat_gloss)),
+            diffuse_weight: (1.0 - metallic) * (1.0 - transmission),
+            specular_weight: 1.0 - (1.0 - metallic) * transmission,
+            transmission_weight: (1.0 - metallic) * transmission
+        }
+    }
+}
+
+impl BSDFMaterial for PrincipledBSDFMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        let normal = &record.normal;
+        let parameters = self.parameters(record);
+        let cos_outgoing = outgoing_light_direction.value().dot(*normal.value());
+        let probabilities = parameters.lobe_probabilities(cos_outgoing);
+
+        let mut choice = sampler.get_f32();
+        let mut lobe = None;
+        for (index, probability) in probabilities.iter().enumerate() {
+            if *probability > 0.0 {
+                lobe = Some(index);
+                if choice < *probability {
+                    break
+                }
+                choice -= *probability;
+            }
+        }
+
+        let direction = match lobe {
+            Some(DIFFUSE) => transform_into(normal, &CosineHemisphereWarper.sample(sampler)),
+            Some(SPECULAR) => {
+                let frame = ShadingFrame::along_surface(record, self.tangent_rotation);
+                match sample_ggx_reflection(&frame, parameters.specular_distribution,
+                                            outgoing_light_direction, sampler) {
+                    Some(direction) => direction,
+                    None => return BSDFSampleResult::rejected(outgoing_light_direction.clone())
+                }
+            },
+            Some(CLEARCOAT) => {
+                let half = transform_into(
+                    normal, &sample_gtr1_half_vector(parameters.clearcoat_alpha, sampler)
+                );
+                let direction = reflection(outgoing_light_direction, &half);
+                // sample_pdf can't account for half vectors facing away from the outgoing direction
+                if outgoing_light_direction.value().dot(*half.value()) <= 0.0 {
+                    return BSDFSampleResult::rejected(direction)
+                }
+                direction
+            },
+            Some(_) => {
+                let (direction, valid) = sample_rough_dielectric(
+                    normal, outgoing_light_direction, self.index_of_refraction,
+                    parameters.alpha, sampler
+                );
+                if !valid {
+                    return BSDFSampleResult::rejected(direction)
+                }
+                direction
+            },
+            None => return BSDFSampleResult::rejected(outgoing_light_direction.clone())
+        };
+        BSDFSampleResult::from_direction(self, record, direction, outgoing_light_direction)
+    }
+
+    fn sample_pdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> f32 {
+        let normal = &record.normal;
+        let parameters = self.parameters(record);
+        let n = *normal.value();
+        let cos_outgoing = light_directions.outgoing.value().dot(n);
+        let cos_incoming = light_directions.incoming.value().dot(n);
+        let probabilities = parameters.lobe_probabilities(cos_outgoing);
+
+        let mut pdf = 0.0;
+        if probabilities[TRANSMISSION] > 0.0 {
+            pdf += probabilities[TRANSMISSION] * rough_dielectric_pdf(
+                normal, light_directions, self.index_of_refraction, parameters.alpha
+            );
+        }
+        if cos_outgoing <= 0.0 {
+            return pdf
+        }
+        if cos_incoming > 0.0 {
+            pdf += probabilities[DIFFUSE] * cos_incoming / PI;
+        }
+
+        let half = half_vector(light_directions.incoming, light_directions.outgoing);
+        let cos_half = half.value().dot(n);
+        let outgoing_dot_half = light_directions.outgoing.value().dot(*half.value());
+        if cos_half > 0.0 && outgoing_dot_half > 0.0 {
+            pdf += probabilities[CLEARCOAT] * cos_half *
+                gtr1_distribution(cos_half, parameters.clearcoat_alpha) / (4.0 * outgoing_dot_half);
+        }
+        let frame = ShadingFrame::along_surface(record, self.tangent_rotation);
+        pdf += probabilities[SPECULAR] *
+            ggx_reflection_pdf(&frame, parameters.specular_distribution, light_directions);
+        pdf
+    }
+
+    fn brdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> Color3 {
+        let normal = &record.normal;
+        let parameters = self.parameters(record);
+        let mut value = if parameters.transmission_weight > 0.0 {
+            rough_dielectric_bsdf(
+                normal, light_directions, self.index_of_refraction, parameters.alpha,
+                &self.base_color, record
+            ) * parameters.transmission_weight
+        } else {
+            Color3::zero()
+        };
+
+        let n = *normal.value();
+        let cos_outgoing = light_directions.outgoing.value().dot(n);
+        let cos_incoming = light_directions.incoming.value().dot(n);
+        if cos_outgoing <= 0.0 || cos_incoming <= 0.0 {
+            return value
+        }
+
+        let half = half_vector(light_directions.incoming, light_directions.outgoing);
+        let cos_difference = light_directions.incoming.value().dot(*half.value());
+        let difference_weight = schlick_weight(cos_difference);
+
+        // diffuse with retro reflection, and sheen
+        let retro_reflection = 0.5 + 2.0 * parameters.roughness * cos_difference.powi(2);
+        let diffuse =
+            (1.0 + (retro_reflection - 1.0) * schlick_weight(cos_incoming)) *
+            (1.0 + (retro_reflection - 1.0) * schlick_weight(cos_outgoing)) / PI;
+        value += (parameters.base_color * diffuse + parameters.sheen_color * difference_weight) *
+            parameters.diffuse_weight;
+
+        // specular reflection
+        let frame = ShadingFrame::along_surface(record, self.tangent_rotation);
+        let (local_incoming, local_outgoing) =
+            (frame.to_local(light_directions.incoming), frame.to_local(light_directions.outgoing));
+        let local_half = frame.to_local(&half);
+        let distribution = parameters.specular_distribution;
+        let fresnel = lerp_color(
+            parameters.specular_color, Color3::new(1.0, 1.0, 1.0), difference_weight);
+        let specular = distribution.distribution(&local_half) *
+            distribution.smith_g1(&local_incoming, &local_half) *
+            distribution.smith_g1(&local_outgoing, &local_half) /
+            (4.0 * cos_incoming * cos_outgoing);
+        value += fresnel * (specular * parameters.specular_weight);
+
+        // clearcoat
+        if parameters.clearcoat > 0.0 {
+            let clearcoat = 0.25 * parameters.clearcoat *
+                gtr1_distribution(half.value().dot(n), parameters.clearcoat_alpha) *
+                lerp(0.04, 1.0, difference_weight) *
+                ggx_smith_g1(light_directions.incoming, &half, normal, CLEARCOAT_GEOMETRY_ALPHA) *
+                ggx_smith_g1(light_directions.outgoing, &half, normal, CLEARCOAT_GEOMETRY_ALPHA) /
+                (4.0 * cos_incoming * cos_outgoing);
+            value += Color3::new(clearcoat, clearcoat, clearcoat);
+        }
+
+        value.max_elem_wise(&Color3::zero())
+    }
+
+    fn is_transmissive(&self) -> bool {
+        self.transmission.is_some()
+    }
+}
+
+fn lerp(from: f32, to: f32, amount: f32) -> f32 {
+    from + (to - from) * amount
+}
+
+fn lerp_color(from: Color3, to: Color3, amount: f32) -> Color3 {
+    from + (to - from) * amount
+}
+
+///(1 - cosine)^5 from Schlick's fresnel approximation
+fn schlick_weight(cosine: f32) -> f32 {
+    (1.0 - cosine).max(0.0).min(1.0).powi(5)
+}
+
+///Generalized Trowbridge-Reitz distribution with exponent 1, used by the clearcoat
+fn gtr1_distribution(cos_half: f32, alpha: f32) -> f32 {
+    if alpha >= 1.0 {
+        return 1.0 / PI
+    }
+    let a2 = alpha.powi(2);
+    (a2 - 1.0) / (PI * a2.ln() * (1.0 + (a2 - 1.0) * cos_half.powi(2)))
+}
+
+///Samples a half vector around [0, 1, 0] proportionally to gtr1_distribution * cosine
+fn sample_gtr1_half_vector(alpha: f32, sampler: &mut NumberSequenceSampler) -> Vec3 {
+    let (u, v) = sampler.get_2d_f32();
+    let a2 = alpha.powi(2);
+    let cos_theta = ((1.0 - a2.powf(1.0 - u)) / (1.0 - a2)).max(0.0).sqrt();
+    let sin_theta = (1.0 - cos_theta.powi(2)).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vec3::new(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin())
+}
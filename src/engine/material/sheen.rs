@@ -0,0 +1,285 @@
+use utilities::math::*;
+use utilities::color::*;
+use utilities::sampler::{Sampler, NumberSequenceSampler};
+
+use engine::probability::*;
+use engine::intersectable::IntersectionRecord;
+use engine::texture::Texture;
+use super::{Material, BSDFMaterial, BSDFSampleResult, LightDirectionPair};
+
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+///Roughness range covered by Estevez and Kulla's shadowing fit
+const MIN_SHEEN_ROUGHNESS: f32 = 0.07;
+
+///Number of outgoing cosines and roughnesses at which the sheen's albedo is tabulated
+const ALBEDO_RESOLUTION: usize = 16;
+
+///Retro reflective fuzz of cloth: the Charlie microfiber distribution with Estevez and
+///Kulla's shadowing term. roughness is in [0, 1], higher values spread the sheen
+///further from grazing angles
+#[derive(Debug)]
+pub struct SheenBSDFMaterial {
+    color: Texture,
+    roughness: Texture
+}
+
+impl SheenBSDFMaterial {
+    pub fn new(color: Texture, roughness: Texture) -> SheenBSDFMaterial {
+        SheenBSDFMaterial { color, roughness }
+    }
+
+    fn roughness(&self, record: &IntersectionRecord) -> f32 {
+        self.roughness.value(record).max(MIN_SHEEN_ROUGHNESS).min(1.0)
+    }
+}
+
+///Sheen without its color, for unit vectors around the normal [0, 1, 0]
+fn sheen_reflectance(incoming: &Vec3, outgoing: &Vec3, roughness: f32) -> f32 {
+    let (cos_incoming, cos_outgoing) = (incoming.y, outgoing.y);
+    if cos_incoming <= 0.0 || cos_outgoing <= 0.0 {
+        return 0.0
+    }
+    let half = (incoming + outgoing).normalize();
+    let shadowing = 1.0 / (1.0 + sheen_lambda(cos_incoming, roughness) +
+        sheen_lambda(cos_outgoing, roughness));
+    charlie_distribution(half.y, roughness) * shadowing / (4.0 * cos_incoming * cos_outgoing)
+}
+
+///Normal distribution of fibers sticking out of the surface, D = (2 + 1 / r) sin^(1 / r) / 2pi
+fn charlie_distribution(cos_half: f32, roughness: f32) -> f32 {
+    let sin2_half = (1.0 - cos_half * cos_half).max(0.0);
+    (2.0 + 1.0 / roughness) * sin2_half.powf(0.5 / roughness) / (2.0 * PI)
+}
+
+///Estevez and Kulla's fit of the shadowing of the Charlie distribution
+fn sheen_lambda(cosine: f32, roughness: f32) -> f32 {
+    let t = (1.0 - roughness) * (1.0 - roughness);
+    let lerp = |a: f32, b: f32| a + (b - a) * t;
+    let (a, b, c) = (lerp(25.3245, 21.5473), lerp(3.32435, 3.82987), lerp(0.16801, 0.19823));
+    let (d, e) = (lerp(-1.27393, -1.97760), lerp(-4.85967, -4.32054));
+    let fit = |x: f32| a / (1.0 + b * x.powf(c)) + d * x + e;
+    if cosine < 0.5 {
+        fit(cosine).exp()
+    } else {
+        (2.0 * fit(0.5) - fit(1.0 - cosine)).exp()
+    }
+}
+
+impl BSDFMaterial for SheenBSDFMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        let untransformed_sample = UniformHemisphereWarper.sample(sampler);
+        let sample = transform_into(&record.normal, &untransformed_sample);
+        BSDFSampleResult::from_direction(self, record, sample, outgoing_light_direction)
+    }
+
+    fn sample_pdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> f32 {
+        if light_directions.incoming.value().dot(*record.normal.value()) <= 0.0 {
+            return 0.0
+        }
+        UniformHemisphereWarper.pdf(&Vec3::unit_y())
+    }
+
+    fn brdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> Color3 {
+        let incoming = transform_from(&record.normal, light_directions.incoming.value());
+        let outgoing = transform_from(&record.normal, light_directions.outgoing.value());
+        self.color.color(record) *
+            sheen_reflectance(incoming.value(), outgoing.value(), self.roughness(record))
+    }
+}
+
+///Directional albedo of the uncolored sheen, by outgoing cosine and roughness.
+///It doesn't depend on the material, so one table is shared by every sheen layer
+#[derive(Debug)]
+pub struct SheenAlbedo {
+    values: Vec<f32>
+}
+
+impl SheenAlbedo {
+    pub fn new() -> SheenAlbedo {
+        let (theta_steps, phi_steps) = (32, 32);
+        let mut values = Vec::with_capacity(ALBEDO_RESOLUTION * ALBEDO_RESOLUTION);
+        for roughness_index in 0..ALBEDO_RESOLUTION {
+            let roughness = albedo_grid_value(roughness_index).max(MIN_SHEEN_ROUGHNESS);
+            for cosine_index in 0..ALBEDO_RESOLUTION {
+                let cos_outgoing = albedo_grid_value(cosine_index).max(1e-3);
+                let outgoing =
+                    Vec3::new((1.0 - cos_outgoing * cos_outgoing).sqrt(), cos_outgoing, 0.0);
+                let mut albedo = 0.0;
+                for i in 0..theta_steps {
+                    let theta = (i as f32 + 0.5) / theta_steps as f32 * PI / 2.0;
+                    //the sheen is symmetric around the plane of the outgoing direction
+                    for j in 0..phi_steps {
+                        let phi = (j as f32 + 0.5) / phi_steps as f32 * PI;
+                        let incoming = Vec3::new(
+                            theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin()
+                        );
+                        albedo += sheen_reflectance(&incoming, &outgoing, roughness) *
+                            theta.cos() * theta.sin();
+                    }
+                }
+                values.push(albedo * 2.0 * (PI / 2.0 / theta_steps as f32) *
+                            (PI / phi_steps as f32));
+            }
+        }
+        SheenAlbedo { values }
+    }
+
+    ///Bilinear interpolation of the table
+    fn lookup(&self, cosine: f32, roughness: f32) -> f32 {
+        let position = |value: f32| {
+            let position = (value.max(0.0).min(1.0) * (ALBEDO_RESOLUTION - 1) as f32)
+                .min((ALBEDO_RESOLUTION - 1) as f32 - 1e-3);
+            (position as usize, position.fract())
+        };
+        let (row, row_fraction) = position(roughness);
+        let (column, column_fraction) = position(cosine);
+        let value = |row: usize, column: usize| self.values[row * ALBEDO_RESOLUTION + column];
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        lerp(
+            lerp(value(row, column), value(row, column + 1), column_fraction),
+            lerp(value(row + 1, column), value(row + 1, column + 1), column_fraction),
+            row_fraction
+        )
+    }
+}
+
+fn albedo_grid_value(index: usize) -> f32 {
+    index as f32 / (ALBEDO_RESOLUTION - 1) as f32
+}
+
+///Sheen over another material. The base receives the light the sheen doesn't reflect
+#[derive(Debug)]
+pub struct SheenLayerBSDFMaterial {
+    base: Rc<Material>,
+    sheen: SheenBSDFMaterial,
+    albedo: Rc<SheenAlbedo>
+}
+
+impl SheenLayerBSDFMaterial {
+    pub fn new(base: Rc<Material>, albedo: Rc<SheenAlbedo>, color: Texture, roughness: Texture)
+        -> SheenLayerBSDFMaterial
+    {
+        SheenLayerBSDFMaterial {
+            base,
+            sheen: SheenBSDFMaterial::new(color, roughness),
+            albedo
+        }
+    }
+
+    ///Per channel fraction of the light leaving towards outgoing that is reflected by the sheen
+    fn sheen_albedo(&self, record: &IntersectionRecord, outgoing: &UnitVec3) -> Color3 {
+        let cosine = outgoing.value().dot(*record.normal.value());
+        let color = self.sheen.color.color(record).max_elem_wise(&Color3::new(0.0, 0.0, 0.0));
+        (color * self.albedo.lookup(cosine, self.sheen.roughness(record)))
+            .min_elem_wise(&Color3::new(1.0, 1.0, 1.0))
+    }
+
+    fn sheen_probability(&self, record: &IntersectionRecord, outgoing: &UnitVec3) -> f32 {
+        average(&self.sheen_albedo(record, outgoing))
+    }
+}
+
+impl BSDFMaterial for SheenLayerBSDFMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        let probability = self.sheen_probability(record, outgoing_light_direction);
+        if sampler.get_f32() < probability {
+            let sample = self.sheen.sample(record, outgoing_light_direction, sampler);
+            return BSDFSampleResult::from_direction(
+                self, record, sample.direction, outgoing_light_direction
+            )
+        }
+
+        let sample = self.base.bsdf().sample(record, outgoing_light_direction, sampler);
+        if sample.from_delta_lobe || sample.pdf <= 0.0 {
+            let one = Color3::new(1.0, 1.0, 1.0);
+            let transmittance = one - self.sheen_albedo(record, outgoing_light_direction);
+            return BSDFSampleResult {
+                pdf: sample.pdf * (1.0 - probability),
+                value: sample.value.mul_element_wise(transmittance),
+                ..sample
+            }
+        }
+        BSDFSampleResult::from_direction(self, record, sample.direction, outgoing_light_direction)
+    }
+
+    fn sample_pdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> f32 {
+        let probability = self.sheen_probability(record, light_directions.outgoing);
+        self.sheen.sample_pdf(record, light_directions) * probability +
+            self.base.bsdf().sample_pdf(record, light_directions) * (1.0 - probability)
+    }
+
+    fn brdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> Color3 {
+        let one = Color3::new(1.0, 1.0, 1.0);
+        let transmittance = one - self.sheen_albedo(record, light_directions.outgoing);
+        self.sheen.brdf(record, light_directions) +
+            self.base.bsdf().brdf(record, light_directions).mul_element_wise(transmittance)
+    }
+
+    fn is_transmissive(&self) -> bool {
+        self.base.bsdf().is_transmissive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engine::material::DiffuseBSDFMaterial;
+    use engine::validation::{brdf_albedo, hemisphere_integral, outgoing_at};
+
+    #[test]
+    fn test_charlie_grazing_peak() {
+        for &roughness in &[0.2f32, 0.5, 1.0] {
+            //no fibers face the normal, and most lie along the surface
+            assert_eq!(charlie_distribution(1.0, roughness), 0.0);
+            let cosines = [0.0f32, 0.3, 0.6, 0.9];
+            for pair in cosines.windows(2) {
+                assert!(charlie_distribution(pair[0], roughness) >
+                        charlie_distribution(pair[1], roughness));
+            }
+            //the fibers' projected area is the surface's
+            let projected_area = hemisphere_integral(|half| {
+                charlie_distribution(half.value().y, roughness) * half.value().y
+            });
+            assert!(apprx_eq(projected_area, 1.0, 0.01), "{}", projected_area);
+
+            let white = Color3::new(1.0, 1.0, 1.0);
+            let sheen = SheenBSDFMaterial::new(
+                white.into(), Color3::new(roughness, roughness, roughness).into()
+            );
+            let grazing = brdf_albedo(&sheen, &outgoing_at(0.1));
+            let normal = brdf_albedo(&sheen, &outgoing_at(1.0));
+            assert!(grazing > 2.0 * normal, "albedo {} at grazing and {} at normal",
+                    grazing, normal);
+        }
+    }
+
+    #[test]
+    fn test_sheen_layer_energy() {
+        let white = Color3::new(1.0, 1.0, 1.0);
+        let albedo = Rc::new(SheenAlbedo::new());
+        for &roughness in &[0.2f32, 0.5, 1.0] {
+            let layer = SheenLayerBSDFMaterial::new(
+                Rc::new(DiffuseBSDFMaterial::new(white.into()).into()), albedo.clone(),
+                white.into(), Color3::new(roughness, roughness, roughness).into()
+            );
+            for &cos_outgoing in &[1.0f32, 0.5, 0.1] {
+                let outgoing = outgoing_at(cos_outgoing);
+                let sheen = brdf_albedo(&layer.sheen, &outgoing);
+                let expected = albedo.lookup(cos_outgoing, roughness);
+                assert!(apprx_eq(sheen, expected, 0.02),
+                        "sheen albedo {} instead of {}", sheen, expected);
+                //a white sheen over a white base neither creates nor loses energy
+                let total = brdf_albedo(&layer, &outgoing);
+                assert!(apprx_eq(total, 1.0, 0.02), "albedo {}", total);
+            }
+        }
+    }
+}
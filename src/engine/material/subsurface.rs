@@ -0,0 +1,244 @@
+use utilities::math::*;
+use utilities::color::*;
+use utilities::sampler::{Sampler, NumberSequenceSampler};
+
+use engine::probability::*;
+use engine::intersectable::IntersectionRecord;
+use engine::texture::Texture;
+use super::{BSDFMaterial, BSDFSampleResult, LightDirectionPair, DiffuseBSDFMaterial};
+use super::optics::*;
+
+use std::f32::consts::PI;
+
+///Translucent material scattering light inside closed meshes. Light is either
+///reflected by the smooth surface or enters the mesh diffusely, random walks through
+///the medium and leaves it diffusely where the walk reaches the surface again.
+///The integrator performs the walk, the bsdf only handles the surface
+#[derive(Debug)]
+pub struct SubsurfaceMaterial {
+    ///color of thick regions of the material, after all scattering
+    albedo: Texture,
+    ///average distance light travels between scattering events, per channel
+    mean_free_path: Color3,
+    index_of_refraction: f32,
+    exit: DiffuseBSDFMaterial
+}
+
+///Scattering properties of the inside of a mesh
+pub struct SubsurfaceMedium {
+    ///probability per unit length of light interacting with the medium
+    pub extinction: Color3,
+    ///probability of an interaction scattering light instead of absorbing it
+    pub scattering_albedo: Color3
+}
+
+impl SubsurfaceMaterial {
+    pub fn new(albedo: Texture, mean_free_path: Color3, index_of_refraction: f32)
+        -> SubsurfaceMaterial
+    {
+        SubsurfaceMaterial {
+            albedo,
+            mean_free_path,
+            index_of_refraction,
+            exit: DiffuseBSDFMaterial::new(Color3::new(1.0, 1.0, 1.0).into())
+        }
+    }
+
+    ///The medium below the intersection
+    pub fn medium(&self, record: &IntersectionRecord) -> SubsurfaceMedium {
+        let albedo = self.albedo.color(record);
+        let mean_free_path = self.mean_free_path;
+        SubsurfaceMedium {
+            extinction: Color3::new(1.0 / mean_free_path.x.max(1e-6),
+                                    1.0 / mean_free_path.y.max(1e-6),
+                                    1.0 / mean_free_path.z.max(1e-6)),
+            scattering_albedo: Color3::new(scattering_albedo(albedo.x),
+                                           scattering_albedo(albedo.y),
+                                           scattering_albedo(albedo.z))
+        }
+    }
+
+    ///Bsdf of the point where the walk leaves the mesh, with the normal pointing out of it
+    pub fn exit_bsdf(&self) -> &BSDFMaterial {
+        &self.exit
+    }
+
+    fn reflectance(&self, normal: &UnitVec3, outgoing_light_direction: &UnitVec3) -> f32 {
+        let cosine = outgoing_light_direction.value().dot(*normal.value());
+        fresnel_dielectric(cosine, 1.0, self.index_of_refraction)
+    }
+}
+
+///Single scattering albedo giving the multiple scattering albedo of a semi-infinite
+///medium. Van de Hulst's inversion, as used by Cycles
+fn scattering_albedo(albedo: f32) -> f32 {
+    let albedo = albedo.max(0.0).min(0.999);
+    1.0 - (4.09712 + 4.20863 * albedo -
+        (9.59217 + 41.6808 * albedo + 17.7126 * albedo.powi(2)).sqrt()).powi(2)
+}
+
+fn transmittance(extinction: Color3, distance: f32) -> Color3 {
+    Color3::new((-extinction.x * distance).exp(),
+                (-extinction.y * distance).exp(),
+                (-extinction.z * distance).exp())
+}
+
+impl SubsurfaceMedium {
+    ///Channel whose extinction samples every distance of a walk, chosen uniformly
+    ///once when the walk enters the medium
+    pub fn sample_channel<TSpl: Sampler>(&self, sampler: &mut TSpl) -> usize {
+        sampler.get_usize_from_f32(3)
+    }
+
+    ///Distance to the next interaction, sampled from the walk's channel
+    pub fn sample_distance<TSpl: Sampler>(&self, channel: usize, sampler: &mut TSpl) -> f32 {
+        -(1.0 - sampler.get_f32()).ln() / self.extinction[channel]
+    }
+
+    ///Value and per channel probability density of scattering at a sampled distance.
+    ///Since a single channel samples the whole walk, its weight is the product of its
+    ///values over the average of the channels' products of densities
+    pub fn scattering(&self, distance: f32) -> (Color3, Color3) {
+        let transmittance = transmittance(self.extinction, distance);
+        let pdfs = self.extinction.mul_element_wise(transmittance);
+        (pdfs.mul_element_wise(self.scattering_albedo), pdfs)
+    }
+
+    ///Value and per channel probability of a sampled distance exceeding the
+    ///distance to a surface
+    pub fn reaching_surface(&self, distance: f32) -> (Color3, Color3) {
+        let transmittance = transmittance(self.extinction, distance);
+        (transmittance, transmittance)
+    }
+}
+
+impl BSDFMaterial for SubsurfaceMaterial {
+    fn sample(
+        &self, record: &IntersectionRecord, outgoing_light_direction: &UnitVec3,
+        sampler: &mut NumberSequenceSampler
+    ) -> BSDFSampleResult {
+        let normal = &record.normal;
+        if outgoing_light_direction.value().dot(*normal.value()) <= 0.0 {
+            return BSDFSampleResult::rejected(outgoing_light_direction.clone())
+        }
+        let reflectance = self.reflectance(normal, outgoing_light_direction);
+        if sampler.get_f32() < reflectance {
+            return BSDFSampleResult {
+                direction: reflection(outgoing_light_direction, normal),
+                pdf: reflectance,
+                value: Color3::new(reflectance, reflectance, reflectance),
+                from_delta_lobe: true
+            }
+        }
+        let inside = normal.clone().neg();
+        let direction = transform_into(&inside, &CosineHemisphereWarper.sample(sampler));
+        BSDFSampleResult::from_direction(self, record, direction, outgoing_light_direction)
+    }
+
+    fn sample_pdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> f32 {
+        let normal = record.normal.value();
+        let cos_incoming = light_directions.incoming.value().dot(*normal);
+        if cos_incoming >= 0.0 || light_directions.outgoing.value().dot(*normal) <= 0.0 {
+            return 0.0
+        }
+        (1.0 - self.reflectance(&record.normal, light_directions.outgoing)) * -cos_incoming / PI
+    }
+
+    ///Only light entering the mesh. What arrives from below is the radiance the walk collects
+    fn brdf(&self, record: &IntersectionRecord, light_directions: &LightDirectionPair) -> Color3 {
+        let normal = record.normal.value();
+        if light_directions.incoming.value().dot(*normal) >= 0.0 ||
+            light_directions.outgoing.value().dot(*normal) <= 0.0 {
+            return Color3::zero()
+        }
+        let transmittance = (1.0 - self.reflectance(&record.normal, light_directions.outgoing)) / PI;
+        Color3::new(transmittance, transmittance, transmittance)
+    }
+
+    fn is_transmissive(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utilities::sampler::PseudorandomSampler;
+
+    fn weight((value, pdfs): (Color3, Color3)) -> Color3 {
+        value / average(&pdfs)
+    }
+
+    #[test]
+    fn test_walk_weights() {
+        let medium = SubsurfaceMedium {
+            extinction: Color3::new(1.0, 2.0, 4.0),
+            scattering_albedo: Color3::new(1.0, 1.0, 1.0)
+        };
+        //a step towards a surface at distance 0.5 either scatters before it or reaches it,
+        //with the probabilities of the channels' exponential distributions
+        let surface_distance = 0.5;
+        let samples = 200000;
+        let (mut scattered, mut reached) = (Color3::zero(), Color3::zero());
+        for _ in 0..samples {
+            let channel = medium.sample_channel(&mut PseudorandomSampler);
+            let distance = medium.sample_distance(channel, &mut PseudorandomSampler);
+            if distance < surface_distance {
+                scattered += weight(medium.scattering(distance));
+            } else {
+                reached += weight(medium.reaching_surface(surface_distance));
+            }
+        }
+        let (scattered, reached) = (scattered / samples as f32, reached / samples as f32);
+        let expected_reached = transmittance(medium.extinction, surface_distance);
+        for channel in 0..3 {
+            let (scattered, reached) = (scattered[channel], reached[channel]);
+            assert!(apprx_eq(reached, expected_reached[channel], 0.02), "reached {}", reached);
+            assert!(apprx_eq(scattered, 1.0 - expected_reached[channel], 0.02),
+                    "scattered {}", scattered);
+        }
+        //white materials don't absorb
+        assert!(apprx_eq(scattering_albedo(0.999), 1.0, 1e-3));
+        assert!(scattering_albedo(0.5) > 0.5 && scattering_albedo(0.5) < 1.0);
+    }
+
+    #[test]
+    fn test_multiple_step_walk_weights() {
+        let medium = SubsurfaceMedium {
+            extinction: Color3::new(1.0, 2.0, 4.0),
+            scattering_albedo: Color3::new(0.8, 0.5, 0.9)
+        };
+        //a walk scattering only forwards reaches a surface at distance 1 after a Poisson
+        //number of interactions, each keeping albedo of the light
+        let surface_distance = 1.0;
+        let samples = 200000;
+        let mut total = Color3::zero();
+        for _ in 0..samples {
+            let channel = medium.sample_channel(&mut PseudorandomSampler);
+            let (mut value, mut pdfs) = (Color3::new(1.0, 1.0, 1.0), Color3::new(1.0, 1.0, 1.0));
+            let mut remaining = surface_distance;
+            loop {
+                let distance = medium.sample_distance(channel, &mut PseudorandomSampler);
+                let (step_value, step_pdfs) = if distance < remaining {
+                    medium.scattering(distance)
+                } else {
+                    medium.reaching_surface(remaining)
+                };
+                value.mul_assign_element_wise(step_value);
+                pdfs.mul_assign_element_wise(step_pdfs);
+                if distance >= remaining {
+                    break;
+                }
+                remaining -= distance;
+            }
+            total += weight((value, pdfs));
+        }
+        let mean = total / samples as f32;
+        for channel in 0..3 {
+            let expected = (-medium.extinction[channel] * surface_distance *
+                (1.0 - medium.scattering_albedo[channel])).exp();
+            assert!(apprx_eq(mean[channel], expected, 0.02),
+                    "channel {}: {} != {}", channel, mean[channel], expected);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use utilities::math::*;
+use utilities::color::*;
+
+use engine::intersectable::IntersectionRecord;
+use engine::texture::{Texture, TextureSpec};
+
+use std::f32::consts::PI;
+use std::ops::{Add, Sub, Mul, Div};
+
+///Wavelengths in nanometers at which film reflectance is evaluated
+const FIRST_WAVELENGTH: f32 = 380.0;
+const LAST_WAVELENGTH: f32 = 780.0;
+const WAVELENGTH_COUNT: usize = 32;
+
+///Thin dielectric film on top of a surface. Light reflected by the top and the bottom
+///of the film interferes, tinting reflections depending on the film's thickness
+#[derive(Debug)]
+pub struct ThinFilm {
+    ///thickness in nanometers
+    thickness: Texture,
+    index_of_refraction: f32,
+    ///linear rgb weight of each wavelength, each channel summing to one
+    wavelength_weights: Vec<(f32, Color3)>
+}
+
+///thickness in nanometers, which may vary over the surface
+#[derive(Deserialize)]
+pub struct ThinFilmSpec {
+    pub thickness: TextureSpec,
+    pub ior: f32
+}
+
+impl ThinFilmSpec {
+    pub fn to_thin_film(&self) -> Result<ThinFilm, String> {
+        Ok(ThinFilm::new(self.thickness.to_scalar_texture()?, self.ior))
+    }
+}
+
+///What is under the film
+pub enum Substrate {
+    Dielectric(f32),
+    ///eta and k at 650, 550 and 450 nm, in the red, green and blue channels
+    Conductor { eta: Color3, k: Color3 }
+}
+
+impl ThinFilm {
+    pub fn new(thickness: Texture, index_of_refraction: f32) -> ThinFilm {
+        let mut wavelength_weights: Vec<(f32, Color3)> = (0..WAVELENGTH_COUNT)
+            .map(|index| {
+                let wavelength = FIRST_WAVELENGTH + (LAST_WAVELENGTH - FIRST_WAVELENGTH) *
+                    (index as f32 + 0.5) / WAVELENGTH_COUNT as f32;
+                (wavelength, xyz_to_linear_rgb(&cie_color_matching(wavelength)))
+            })
+            .collect();
+        //a film reflecting every wavelength equally reflects white
+        let total = wavelength_weights.iter()
+            .fold(Color3::new(0.0, 0.0, 0.0), |total, &(_, weight)| total + weight);
+        for &mut (_, ref mut weight) in wavelength_weights.iter_mut() {
+            *weight = weight.div_element_wise(total);
+        }
+        ThinFilm { thickness, index_of_refraction, wavelength_weights }
+    }
+
+    ///Reflectance of the film and substrate for light arriving from vacuum.
+    ///Replaces the fresnel reflectance of the substrate alone
+    pub fn reflectance(&self, record: &IntersectionRecord, cos_incident: f32,
+                       substrate: &Substrate) -> Color3 {
+        let thickness = self.thickness.value(record).max(0.0);
+        let cos_incident = cos_incident.max(0.0).min(1.0);
+        let reflectance = self.wavelength_weights.iter()
+            .fold(Color3::new(0.0, 0.0, 0.0), |total, &(wavelength, weight)| {
+                let substrate_ior = match *substrate {
+                    Substrate::Dielectric(ior) => Complex::real(ior),
+                    Substrate::Conductor { ref eta, ref k } => Complex {
+                        re: rgb_at_wavelength(eta, wavelength),
+                        im: rgb_at_wavelength(k, wavelength)
+                    }
+                };
+                total + weight * airy_reflectance(
+                    cos_incident, self.index_of_refraction, substrate_ior, thickness, wavelength
+                )
+            });
+        reflectance.max_elem_wise(&Color3::new(0.0, 0.0, 0.0))
+    }
+}
+
+///Reflectance of a film of thickness and wavelength in nm, from the sum of the
+///amplitudes of the light bouncing inside it, averaged over both polarizations
+fn airy_reflectance(cos_incident: f32, film_ior: f32, substrate_ior: Complex,
+                    thickness: f32, wavelength: f32) -> f32 {
+    let sin2_incident = 1.0 - cos_incident * cos_incident;
+    let cos2_film = 1.0 - sin2_incident / (film_ior * film_ior);
+    if cos2_film <= 0.0 {
+        return 1.0 //total internal reflection at the top of the film
+    }
+    let cos_film = cos2_film.sqrt();
+    //substrate ior times the cosine of the transmitted angle, which is complex for conductors
+    let substrate_cos = (substrate_ior * substrate_ior - Complex::real(sin2_incident)).sqrt();
+
+    let phase = 4.0 * PI * film_ior * thickness * cos_film / wavelength;
+    let delay = Complex { re: phase.cos(), im: phase.sin() };
+
+    let airy = |top: Complex, bottom: Complex| {
+        let bottom = bottom * delay;
+        ((top + bottom) / (Complex::real(1.0) + top * bottom)).norm2()
+    };
+    let film_cos = Complex::real(film_ior * cos_film);
+    let perpendicular = airy(
+        Complex::real((cos_incident - film_ior * cos_film) / (cos_incident + film_ior * cos_film)),
+        (film_cos - substrate_cos) / (film_cos + substrate_cos)
+    );
+    let substrate_ior2 = substrate_ior * substrate_ior;
+    let parallel = airy(
+        Complex::real((film_ior * cos_incident - cos_film) / (film_ior * cos_incident + cos_film)),
+        (substrate_ior2 * Complex::real(cos_film) - Complex::real(film_ior) * substrate_cos) /
+            (substrate_ior2 * Complex::real(cos_film) + Complex::real(film_ior) * substrate_cos)
+    );
+    ((perpendicular + parallel) / 2.0).min(1.0)
+}
+
+///Linear interpolation of a value given at 650, 550 and 450 nm
+fn rgb_at_wavelength(color: &Color3, wavelength: f32) -> f32 {
+    if wavelength >= 650.0 {
+        color.x
+    } else if wavelength >= 550.0 {
+        color.y + (color.x - color.y) * (wavelength - 550.0) / 100.0
+    } else if wavelength >= 450.0 {
+        color.z + (color.y - color.z) * (wavelength - 450.0) / 100.0
+    } else {
+        color.z
+    }
+}
+
+///CIE 1931 color matching functions, from the multi lobe fit of Wyman et al.
+fn cie_color_matching(wavelength: f32) -> Color3 {
+    let lobe = |mean: f32, below: f32, above: f32| {
+        let deviation = (wavelength - mean) / if wavelength < mean { below } else { above };
+        (-0.5 * deviation * deviation).exp()
+    };
+    Color3::new(
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7) -
+            0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8)
+    )
+}
+
+fn xyz_to_linear_rgb(xyz: &Color3) -> Color3 {
+    Color3::new(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Complex {
+    re: f32,
+    im: f32
+}
+
+impl Complex {
+    fn real(re: f32) -> Complex {
+        Complex { re, im: 0.0 }
+    }
+
+    fn norm2(&self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+
+    ///Principal square root
+    fn sqrt(&self) -> Complex {
+        let norm = self.norm2().sqrt();
+        let re = ((norm + self.re) / 2.0).max(0.0).sqrt();
+        let im = ((norm - self.re) / 2.0).max(0.0).sqrt();
+        Complex { re, im: if self.im < 0.0 { -im } else { im } }
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex {
+        Complex { re: self.re + other.re, im: self.im + other.im }
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, other: Complex) -> Complex {
+        Complex { re: self.re - other.re, im: self.im - other.im }
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re
+        }
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+    fn div(self, other: Complex) -> Complex {
+        let norm2 = other.norm2();
+        Complex {
+            re: (self.re * other.re + self.im * other.im) / norm2,
+            im: (self.im * other.re - self.re * other.im) / norm2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::optics::{fresnel_dielectric, fresnel_conductor};
+
+    #[test]
+    fn test_thin_film_reflectance() {
+        let record = IntersectionRecord::no_intersection();
+        //a film of zero thickness leaves the substrate's fresnel reflectance
+        let film = ThinFilm::new(Color3::new(0.0, 0.0, 0.0).into(), 1.33);
+        for &cosine in &[1.0, 0.6, 0.2] {
+            let reflectance = film.reflectance(&record, cosine, &Substrate::Dielectric(1.5));
+            let expected = fresnel_dielectric(cosine, 1.0, 1.5);
+            assert!(apprx_eq(reflectance.y, expected, 1e-4), "{} {}", reflectance.y, expected);
+
+            let (eta, k) = (Color3::new(0.2, 0.2, 0.2), Color3::new(3.9, 3.9, 3.9));
+            let reflectance = film.reflectance(&record, cosine, &Substrate::Conductor { eta, k });
+            let expected = fresnel_conductor(cosine, 0.2, 3.9);
+            assert!(apprx_eq(reflectance.y, expected, 1e-4), "{} {}", reflectance.y, expected);
+        }
+
+        //a quarter wave film on glass reflects less than the bare glass at its wavelength
+        let thickness = 550.0 / (4.0 * 1.25);
+        let coated = airy_reflectance(1.0, 1.25, Complex::real(1.5), thickness, 550.0);
+        assert!(coated < 0.01);
+        let quarter_wave = ThinFilm::new(Color3::new(thickness, thickness, thickness).into(), 1.25);
+        let reflectance = quarter_wave.reflectance(&record, 1.0, &Substrate::Dielectric(1.5));
+        assert!(reflectance.y < fresnel_dielectric(1.0, 1.0, 1.5));
+    }
+}
@@ -1,33 +1,77 @@
 use std::fmt;
 use std::rc::Rc;
+use std::f32::consts::PI;
 
-use utilities::math::{Vec3, Matrix4};
+use utilities::math::{Vec2, Vec3, Matrix4, HasUnit, InnerSpace};
+use utilities::color::Color3;
+#[cfg(test)]
+use utilities::math::apprx_eq;
+
+use super::probability::transform_into;
 
 use super::intersectable::Triangle;
-use super::shader::Shader;
+use super::material::Material;
 use super::transformable::Transformable;
 
+///Index of a mesh in the scene spec's mesh list.
+///Primitives are numbered after the meshes, in the order of their list
+pub type MeshId = usize;
+
+///indices for (position, normal, texcoord). Meshes without texcoords have None
+pub type TriangleIndices = ([usize; 3], [usize; 3], Option<[usize; 3]>);
+
+///TriangleIndices of faces that may have no normals in the file
+pub type PartialTriangleIndices = ([usize; 3], Option<[usize; 3]>, Option<[usize; 3]>);
+
+///How normals are made for faces that have none
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(tag = "kind")]
+pub enum NormalsSpec {
+    ///area weighted average of the normals of the faces around each vertex, leaving out
+    ///faces at more than max_angle radians from the shaded face. Defaults to 60 degrees
+    Smooth { max_angle: Option<f32> },
+    Flat
+}
+
+impl Default for NormalsSpec {
+    fn default() -> Self {
+        NormalsSpec::Smooth { max_angle: None }
+    }
+}
+
 pub struct MeshInfo {
     pub positions: Vec<Vec3>,
     pub normals: Vec<Vec3>,
-    pub triangles: Vec<([usize; 3], [usize; 3])>, // vector of indices for (position, normal)
+    pub texcoords: Vec<Vec2>,
+    ///color of each position, or empty if the file has none
+    pub colors: Vec<Color3>,
+    pub triangles: Vec<TriangleIndices>,
+    ///usemtl name of each material group, None for faces before the first usemtl
+    pub material_groups: Vec<Option<String>>,
+    ///index into material_groups for each triangle
+    pub triangle_materials: Vec<usize>,
+    ///mtllib files, relative to the obj
+    pub material_libraries: Vec<String>
 }
 
 pub struct MeshObject {
-    pub triangles: Vec<Triangle>,
-    pub shader: Rc<Shader>
+    pub id: MeshId,
+    pub triangles: Vec<Triangle>
 }
 
 impl MeshObject {
-    pub fn new(mesh_info: &MeshInfo, shader: &Rc<Shader>) -> Option<MeshObject> {
-
+    ///materials holds the material of each of mesh_info's material groups
+    pub fn new(mesh_info: &MeshInfo, materials: &[Rc<Material>], id: MeshId)
+        -> Option<MeshObject>
+    {
         let mut mesh_object = MeshObject {
-            triangles: Vec::<Triangle>::new(),
-            shader: shader.clone()
+            id,
+            triangles: Vec::<Triangle>::new()
         };
 
         {
-            for &(positions, normals) in &mesh_info.triangles {
+            for (&(positions, normals, texcoords), &group) in
+                mesh_info.triangles.iter().zip(&mesh_info.triangle_materials) {
                 //positions
                 if let (Some(pos0), Some(pos1), Some(pos2),
                     Some(norm0), Some(norm1), Some(norm2)) = (
@@ -38,10 +82,30 @@ impl MeshObject {
                     mesh_info.normals.get(normals[1]),
                     mesh_info.normals.get(normals[2]),
                 ) {
+                    let texcoords = match texcoords {
+                        Some(texcoords) => [
+                            *mesh_info.texcoords.get(texcoords[0])?,
+                            *mesh_info.texcoords.get(texcoords[1])?,
+                            *mesh_info.texcoords.get(texcoords[2])?
+                        ],
+                        None => [Vec2::new(0.0, 0.0); 3]
+                    };
+                    let white = Color3::new(1.0, 1.0, 1.0);
+                    let color = |index: usize| *mesh_info.colors.get(index).unwrap_or(&white);
+                    let colors = [color(positions[0]), color(positions[1]), color(positions[2])];
+                    let positions = [*pos0, *pos1, *pos2];
+                    let tangents = tangents_from_texcoords(&positions, &texcoords);
                     let triangle = Triangle {
-                        positions: [*pos0, *pos1, *pos2],
+                        positions,
                         normals: [*norm0, *norm1, *norm2],
-                        shader: shader.clone()
+                        texcoords,
+                        colors,
+                        object_positions: positions,
+                        tangents,
+                        object_tangents: tangents,
+                        double_sided: false,
+                        material: materials.get(group)?.clone(),
+                        mesh_id: id
                     };
                     mesh_object.triangles.push(triangle);
                 } else {
@@ -52,6 +116,150 @@ impl MeshObject {
 
         Some(mesh_object)
     }
+
+    pub fn set_double_sided(&mut self, double_sided: bool) {
+        for triangle in self.triangles.iter_mut() {
+            triangle.double_sided = double_sided;
+        }
+    }
+}
+
+///Splits a planar polygon into triangles by ear clipping, keeping its winding.
+///Returns indices into polygon. Polygons that can't be clipped, such as
+///self intersecting ones, are split as a fan
+pub fn triangulate(positions: &[Vec3], polygon: &[usize]) -> Vec<[usize; 3]> {
+    let fan = |corners: &[usize]| -> Vec<[usize; 3]> {
+        (1..corners.len().saturating_sub(1))
+            .map(|i| [corners[0], corners[i], corners[i + 1]])
+            .collect()
+    };
+    if polygon.len() <= 3 {
+        return fan(&(0..polygon.len()).collect::<Vec<usize>>());
+    }
+
+    //Newell's method, robust for concave and slightly non planar polygons
+    let points: Vec<Vec3> = polygon.iter().map(|&index| positions[index]).collect();
+    let mut normal = Vec3::new(0.0, 0.0, 0.0);
+    for (i, current) in points.iter().enumerate() {
+        let next = points[(i + 1) % points.len()];
+        normal.x += (current.y - next.y) * (current.z + next.z);
+        normal.y += (current.z - next.z) * (current.x + next.x);
+        normal.z += (current.x - next.x) * (current.y + next.y);
+    }
+    if normal.magnitude2() == 0.0 {
+        return fan(&(0..polygon.len()).collect::<Vec<usize>>());
+    }
+
+    //the signed area of a corner, positive where the polygon turns the way it winds
+    let turn = |a: usize, b: usize, c: usize| {
+        (points[b] - points[a]).cross(points[c] - points[b]).dot(normal)
+    };
+    let inside = |triangle: [usize; 3], point: usize| {
+        let p = points[point];
+        (0..3).all(|i| {
+            let (a, b) = (points[triangle[i]], points[triangle[(i + 1) % 3]]);
+            (b - a).cross(p - a).dot(normal) > 0.0
+        })
+    };
+
+    let mut remaining: Vec<usize> = (0..polygon.len()).collect();
+    let mut triangles = Vec::with_capacity(polygon.len() - 2);
+    while remaining.len() > 3 {
+        let count = remaining.len();
+        let ear = (0..count).find(|&i| {
+            let triangle = [remaining[(i + count - 1) % count], remaining[i],
+                remaining[(i + 1) % count]];
+            turn(triangle[0], triangle[1], triangle[2]) > 0.0 &&
+                !remaining.iter().any(|&other| !triangle.contains(&other) &&
+                    inside(triangle, other))
+        });
+        match ear {
+            Some(i) => {
+                triangles.push([remaining[(i + count - 1) % count], remaining[i],
+                    remaining[(i + 1) % count]]);
+                remaining.remove(i);
+            },
+            None => break
+        }
+    }
+    triangles.extend(fan(&remaining));
+    triangles
+}
+
+///Fills in the normal indices of triangles without them, appending the generated
+///normals to normals
+pub fn generate_missing_normals(
+    positions: &[Vec3], normals: &mut Vec<Vec3>,
+    triangles: &[PartialTriangleIndices], spec: NormalsSpec
+) -> Vec<TriangleIndices> {
+    //not normalized, so that they are weighted by area
+    let face_normals: Vec<Vec3> = triangles.iter()
+        .map(|&(p, _, _)| (positions[p[1]] - positions[p[0]])
+            .cross(positions[p[2]] - positions[p[0]]))
+        .collect();
+
+    let mut faces_around_position = vec![Vec::<usize>::new(); positions.len()];
+    if let NormalsSpec::Smooth { .. } = spec {
+        for (face, &(p, normal_indices, _)) in triangles.iter().enumerate() {
+            if normal_indices.is_none() {
+                for &position in p.iter() {
+                    faces_around_position[position].push(face);
+                }
+            }
+        }
+    }
+
+    let unit_or_up = |normal: Vec3| if normal.magnitude2() > 0.0 {
+        normal.normalize()
+    } else {
+        Vec3::new(0.0, 1.0, 0.0)
+    };
+
+    triangles.iter().zip(&face_normals).map(|(&(p, normal_indices, texcoords), &face_normal)| {
+        if let Some(normal_indices) = normal_indices {
+            return (p, normal_indices, texcoords);
+        }
+        let mut generated = [0; 3];
+        for (corner, &position) in p.iter().enumerate() {
+            let normal = match spec {
+                NormalsSpec::Flat => face_normal,
+                NormalsSpec::Smooth { max_angle } => {
+                    let min_cosine = max_angle.unwrap_or(PI / 3.0).cos();
+                    let unit_face_normal = unit_or_up(face_normal);
+                    faces_around_position[position].iter()
+                        .map(|&face| face_normals[face])
+                        .filter(|other| unit_or_up(*other).dot(unit_face_normal) >= min_cosine)
+                        .fold(Vec3::new(0.0, 0.0, 0.0), |sum, other| sum + other)
+                }
+            };
+            generated[corner] = normals.len();
+            normals.push(unit_or_up(normal));
+        }
+        (p, generated, texcoords)
+    }).collect()
+}
+
+///dp/du and dp/dv of a triangle. Triangles without usable texcoords get
+///an arbitrary orthonormal frame around their face normal
+pub fn tangents_from_texcoords(positions: &[Vec3; 3], texcoords: &[Vec2; 3]) -> [Vec3; 2] {
+    let edge1 = positions[1] - positions[0];
+    let edge2 = positions[2] - positions[0];
+    let delta_uv1 = texcoords[1] - texcoords[0];
+    let delta_uv2 = texcoords[2] - texcoords[0];
+    let determinant = delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x;
+    if determinant.abs() > 1e-12 {
+        [(edge1 * delta_uv2.y - edge2 * delta_uv1.y) / determinant,
+         (edge2 * delta_uv1.x - edge1 * delta_uv2.x) / determinant]
+    } else {
+        let face_normal = edge1.cross(edge2);
+        let face_normal = if face_normal.magnitude2() > 0.0 {
+            face_normal.unit()
+        } else {
+            Vec3::new(0.0, 1.0, 0.0).unit()
+        };
+        [*transform_into(&face_normal, &Vec3::new(1.0, 0.0, 0.0)).value(),
+         *transform_into(&face_normal, &Vec3::new(0.0, 0.0, 1.0)).value()]
+    }
 }
 
 impl Transformable for MeshObject {
@@ -67,3 +275,54 @@ impl fmt::Debug for MeshObject {
         write!(f, "MeshObject")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(positions: &[Vec3], triangle: [usize; 3]) -> Vec3 {
+        (positions[triangle[1]] - positions[triangle[0]])
+            .cross(positions[triangle[2]] - positions[triangle[0]]) / 2.0
+    }
+
+    #[test]
+    fn test_triangulate_and_generate_normals() {
+        //an L shaped hexagon, concave at index 3, winding around +y
+        let positions = vec![
+            Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 2.0), Vec3::new(2.0, 0.0, 2.0),
+            Vec3::new(2.0, 0.0, 1.0), Vec3::new(1.0, 0.0, 1.0), Vec3::new(1.0, 0.0, 0.0)
+        ];
+        let polygon: Vec<usize> = (0..6).collect();
+        let triangles = triangulate(&positions, &polygon);
+        assert_eq!(triangles.len(), 4);
+        //a fan from vertex 0 would overlap outside of the polygon, changing the total area
+        let total = triangles.iter().fold(Vec3::new(0.0, 0.0, 0.0), |sum, &triangle| {
+            let triangle_area = area(&positions, triangle);
+            assert!(triangle_area.y > 0.0, "{:?} is flipped", triangle);
+            sum + triangle_area
+        });
+        assert!(apprx_eq(total.y, 3.0, 1e-5), "{:?}", total);
+
+        //two faces of a cube sharing an edge, and a third nearly coplanar with the second
+        let positions = vec![
+            Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, -1.0, 0.0), Vec3::new(1.0, 0.1, 1.0)
+        ];
+        let faces: Vec<PartialTriangleIndices> = vec![
+            ([0, 1, 2], None, None), ([0, 3, 1], None, None), ([0, 4, 1], None, None)
+        ];
+        let mut normals = vec![];
+        let smooth = generate_missing_normals(
+            &positions, &mut normals, &faces, NormalsSpec::Smooth { max_angle: None }
+        );
+        //the shared corner of the top faces is smoothed between them only
+        let top = normals[smooth[0].1[0]];
+        assert!(top.y > 0.99 && top.z < 0.0, "{:?}", top);
+        let side = normals[smooth[1].1[0]];
+        assert!((side - Vec3::new(0.0, 0.0, 1.0)).magnitude() < 1e-5, "{:?}", side);
+
+        let mut normals = vec![];
+        let flat = generate_missing_normals(&positions, &mut normals, &faces, NormalsSpec::Flat);
+        assert_eq!(normals[flat[0].1[0]], Vec3::new(0.0, 1.0, 0.0));
+    }
+}
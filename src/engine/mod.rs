@@ -4,10 +4,15 @@ use super::utilities::color;
 
 mod intersectable;
 mod transformable;
+mod primitive;
 
 mod bvh;
 mod meshutils;
+mod mtl;
+mod ply;
+mod stl;
 mod integrator;
+mod light_sampling;
 mod probability;
 
 pub mod camera;
@@ -15,7 +20,10 @@ pub mod camera;
 pub mod scene;
 pub mod scene_builder;
 
-pub mod shader;
 pub mod material;
+mod texture;
 pub mod renderer;
 
+#[cfg(test)]
+mod validation;
+
@@ -0,0 +1,163 @@
+extern crate obj;
+
+use utilities::codable::CodableWrapper;
+use utilities::color::*;
+
+use super::material::{MaterialSpec, MaterialEntrySpec};
+use super::texture::TextureSpec;
+
+use self::obj::raw::material::{Material as MtlMaterial, MtlColor, MtlTextureMap};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+///Loads the shaders of every material in the obj's material libraries,
+///which are found relative to the obj
+pub fn load_mtl_materials(obj_path: &str, libraries: &[String])
+    -> Result<HashMap<String, MaterialEntrySpec>, String>
+{
+    let directory = Path::new(obj_path).parent().unwrap_or(Path::new(""));
+    let mut specs = HashMap::new();
+    for library in libraries {
+        let path = directory.join(library);
+        let reader = File::open(&path).map(BufReader::new)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        let mtl = obj::raw::parse_mtl(reader)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        let mut emissive = vec![];
+        for (name, material) in mtl.materials.iter() {
+            let spec = mtl_material_spec(material, directory).map_err(|message|
+                format!("material {} in {}: {}", name, path.display(), message)
+            )?;
+            if is_emissive(material) {
+                emissive.push(name.as_str());
+            }
+            specs.insert(name.clone(), spec);
+        }
+        if !emissive.is_empty() {
+            emissive.sort();
+            println!("{}: emission is not supported, Ke is ignored for {}",
+                     path.display(), emissive.join(", "));
+        }
+    }
+    Ok(specs)
+}
+
+///A principled shader approximating an mtl material. Ns maps to roughness, Ks to the
+///specular level, d below 1 to transmission with Ni as the ior, and map_Kd replaces Kd.
+///Emission (Ke) is not supported and is left out
+fn mtl_material_spec(material: &MtlMaterial, directory: &Path) -> Result<MaterialEntrySpec, String> {
+    let base_color = match material.diffuse_map {
+        Some(ref map) => texture_map_spec(map, directory)?,
+        None => {
+            let color = mtl_color(&material.diffuse)?.unwrap_or(Color3::new(0.8, 0.8, 0.8));
+            TextureSpec::Color(CodableWrapper(color))
+        }
+    };
+    let specular = mtl_color(&material.specular)?
+        .map(|color| color.x.max(color.y).max(color.z).min(1.0))
+        .unwrap_or(0.0);
+    //blinn-phong exponent to the roughness of a GGX lobe of similar width
+    let roughness = material.specular_exponent
+        .map(|exponent| (2.0 / (exponent.max(0.0) + 2.0)).powf(0.25));
+    //exporters write Ni 1 for materials without an ior
+    let ior = material.optical_density.and_then(|ior| if ior > 1.0 { Some(ior) } else { None });
+    let transmission = material.dissolve
+        .and_then(|dissolve| if dissolve < 1.0 { Some(1.0 - dissolve.max(0.0)) } else { None });
+    Ok(MaterialSpec::Principled {
+        base_color,
+        metallic: None,
+        roughness: roughness.map(TextureSpec::Scalar),
+        specular: Some(TextureSpec::Scalar(specular)),
+        specular_tint: None,
+        sheen: None,
+        sheen_tint: None,
+        clearcoat: None,
+        clearcoat_gloss: None,
+        anisotropic: None,
+        tangent_rotation: None,
+        transmission: transmission.map(TextureSpec::Scalar),
+        ior
+    }.into())
+}
+
+///Spectral emission counts as emissive, since it can't be checked for black
+fn is_emissive(material: &MtlMaterial) -> bool {
+    match mtl_color(&material.emissive) {
+        Ok(Some(emission)) => emission.x > 0.0 || emission.y > 0.0 || emission.z > 0.0,
+        Ok(None) => false,
+        Err(_) => true
+    }
+}
+
+fn mtl_color(color: &Option<MtlColor>) -> Result<Option<Color3>, String> {
+    match *color {
+        Some(MtlColor::Rgb(r, g, b)) => Ok(Some(Color3::new(r, g, b))),
+        Some(MtlColor::Xyz(x, y, z)) => Ok(Some(Color3::new(
+            3.2406 * x - 1.5372 * y - 0.4986 * z,
+            -0.9689 * x + 1.8758 * y + 0.0415 * z,
+            0.0557 * x - 0.2040 * y + 1.0570 * z
+        ))),
+        Some(MtlColor::Spectral(ref file, _)) =>
+            Err(format!("spectral colors ({}) are not supported", file)),
+        None => Ok(None)
+    }
+}
+
+fn texture_map_spec(map: &MtlTextureMap, directory: &Path) -> Result<TextureSpec, String> {
+    let path = directory.join(&map.file);
+    let image = path.to_str()
+        .ok_or_else(|| format!("invalid texture path {}", path.display()))?;
+    Ok(TextureSpec::Image { image: image.into(), wrap: None, srgb: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mtl_material_spec() {
+        let mtl = obj::raw::parse_mtl("
+            newmtl glass
+            Kd 0.1 0.2 0.3
+            Ks 0.5 0.5 0.5
+            Ns 98
+            Ni 1.33
+            d 0.25
+            newmtl wood
+            map_Kd wood.png
+            Ke 0 0 0
+            newmtl lamp
+            Ke 1 1 0.5
+        ".as_bytes()).ok().unwrap();
+        let directory = Path::new("models");
+
+        let glass = mtl_material_spec(&mtl.materials["glass"], directory).ok().unwrap();
+        match glass.material {
+            MaterialSpec::Principled {
+                base_color: TextureSpec::Color(ref color),
+                roughness: Some(TextureSpec::Scalar(roughness)),
+                specular: Some(TextureSpec::Scalar(specular)),
+                transmission: Some(TextureSpec::Scalar(transmission)),
+                ior: Some(ior), ..
+            } => {
+                assert_eq!(color.get(), Color3::new(0.1, 0.2, 0.3));
+                assert!((roughness - 0.02f32.powf(0.25)).abs() < 1e-5);
+                assert_eq!((specular, transmission, ior), (0.5, 0.75, 1.33));
+            },
+            _ => panic!("glass should be a principled shader with transmission")
+        }
+
+        let wood = mtl_material_spec(&mtl.materials["wood"], directory).ok().unwrap();
+        match wood.material {
+            MaterialSpec::Principled { base_color: TextureSpec::Image { ref image, .. }, .. } =>
+                assert_eq!(Path::new(image), directory.join("wood.png")),
+            _ => panic!("wood should use its diffuse map")
+        }
+
+        assert!(!is_emissive(&mtl.materials["glass"]) && !is_emissive(&mtl.materials["wood"]));
+        assert!(is_emissive(&mtl.materials["lamp"]));
+    }
+}
@@ -0,0 +1,403 @@
+use utilities::math::*;
+
+use super::meshutils::*;
+
+use std::io::BufRead;
+use std::str::SplitWhitespace;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scalar {
+    Char,
+    UChar,
+    Short,
+    UShort,
+    Int,
+    UInt,
+    Float,
+    Double
+}
+
+impl Scalar {
+    fn from_name(name: &str) -> Result<Scalar, String> {
+        Ok(match name {
+            "char" | "int8" => Scalar::Char,
+            "uchar" | "uint8" => Scalar::UChar,
+            "short" | "int16" => Scalar::Short,
+            "ushort" | "uint16" => Scalar::UShort,
+            "int" | "int32" => Scalar::Int,
+            "uint" | "uint32" => Scalar::UInt,
+            "float" | "float32" => Scalar::Float,
+            "double" | "float64" => Scalar::Double,
+            _ => return Err(format!("unknown property type {}", name))
+        })
+    }
+
+    fn size(&self) -> usize {
+        match *self {
+            Scalar::Char | Scalar::UChar => 1,
+            Scalar::Short | Scalar::UShort => 2,
+            Scalar::Int | Scalar::UInt | Scalar::Float => 4,
+            Scalar::Double => 8
+        }
+    }
+
+    fn decode(&self, bits: u64) -> f64 {
+        match *self {
+            Scalar::Char => bits as u8 as i8 as f64,
+            Scalar::Short => bits as u16 as i16 as f64,
+            Scalar::Int => bits as u32 as i32 as f64,
+            Scalar::UChar | Scalar::UShort | Scalar::UInt => bits as f64,
+            Scalar::Float => f32::from_bits(bits as u32) as f64,
+            Scalar::Double => f64::from_bits(bits)
+        }
+    }
+
+    ///integer colors span the whole range of their type
+    fn color_scale(&self) -> f64 {
+        match *self {
+            Scalar::UChar => 1.0 / 255.0,
+            Scalar::UShort => 1.0 / 65535.0,
+            _ => 1.0
+        }
+    }
+}
+
+enum Property {
+    Scalar(Scalar, String),
+    List { count: Scalar, item: Scalar, name: String }
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>
+}
+
+#[derive(Debug, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian
+}
+
+///Values after the header, read one at a time
+enum Body<'a> {
+    Ascii(SplitWhitespace<'a>),
+    Binary { bytes: &'a [u8], big_endian: bool }
+}
+
+impl<'a> Body<'a> {
+    fn read(&mut self, scalar: Scalar) -> Result<f64, String> {
+        match *self {
+            Body::Ascii(ref mut tokens) => {
+                let token = tokens.next().ok_or("unexpected end of data")?;
+                token.parse::<f64>().map_err(|_| format!("invalid number {}", token))
+            },
+            Body::Binary { ref mut bytes, big_endian } => {
+                let all: &'a [u8] = *bytes;
+                if all.len() < scalar.size() {
+                    return Err("unexpected end of data".into());
+                }
+                let (value, rest) = all.split_at(scalar.size());
+                *bytes = rest;
+                let bits = if big_endian {
+                    value.iter().fold(0, |bits, &byte| (bits << 8) | byte as u64)
+                } else {
+                    value.iter().rev().fold(0, |bits, &byte| (bits << 8) | byte as u64)
+                };
+                Ok(scalar.decode(bits))
+            }
+        }
+    }
+}
+
+fn read_header<R: BufRead>(reader: &mut R) -> Result<(Format, Vec<Element>), String> {
+    let mut line = String::new();
+    let mut next_line = |line: &mut String| -> Result<(), String> {
+        line.clear();
+        match reader.read_line(line) {
+            Ok(0) => Err("missing end_header".into()),
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.to_string())
+        }
+    };
+
+    next_line(&mut line)?;
+    if line.trim() != "ply" {
+        return Err("not a ply file".into());
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+    loop {
+        next_line(&mut line)?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match (words.first().cloned(), words.len()) {
+            (Some("end_header"), 1) => break,
+            (Some("format"), 3) => format = Some(match words[1] {
+                "ascii" => Format::Ascii,
+                "binary_little_endian" => Format::BinaryLittleEndian,
+                "binary_big_endian" => Format::BinaryBigEndian,
+                name => return Err(format!("unknown format {}", name))
+            }),
+            (Some("element"), 3) => elements.push(Element {
+                name: words[1].to_string(),
+                count: words[2].parse().map_err(|_| format!("invalid count {}", words[2]))?,
+                properties: vec![]
+            }),
+            (Some("property"), 5) if words[1] == "list" => elements.last_mut()
+                .ok_or("property before any element")?
+                .properties.push(Property::List {
+                    count: Scalar::from_name(words[2])?,
+                    item: Scalar::from_name(words[3])?,
+                    name: words[4].to_string()
+                }),
+            (Some("property"), 3) => elements.last_mut()
+                .ok_or("property before any element")?
+                .properties.push(
+                    Property::Scalar(Scalar::from_name(words[1])?, words[2].to_string())
+                ),
+            (Some("comment"), _) | (Some("obj_info"), _) | (None, _) => {},
+            _ => return Err(format!("invalid header line {}", line.trim()))
+        }
+    }
+
+    Ok((format.ok_or("missing format")?, elements))
+}
+
+///Counts and indices are read as numbers, which must be whole and not negative
+fn whole_number(value: f64) -> Option<usize> {
+    if value >= 0.0 && value.fract() == 0.0 && value <= usize::max_value() as f64 {
+        Some(value as usize)
+    } else {
+        None
+    }
+}
+
+///Vertex properties kept from the file, in the order of the values they fill
+const VERTEX_FIELDS: [&[&str]; 11] = [
+    &["x"], &["y"], &["z"],
+    &["nx"], &["ny"], &["nz"],
+    &["u", "s", "texture_u", "texture_s"], &["v", "t", "texture_v", "texture_t"],
+    &["red", "diffuse_red"], &["green", "diffuse_green"], &["blue", "diffuse_blue"]
+];
+
+///Reads an ascii or binary ply file's vertices and faces. Vertex normals, texture
+///coordinates and colors are kept when the file has all of their components
+pub fn read_ply<R: BufRead>(mut reader: R, normals_spec: NormalsSpec) -> Result<MeshInfo, String> {
+    let (format, elements) = read_header(&mut reader)?;
+    let mut data = vec![];
+    reader.read_to_end(&mut data).map_err(|err| err.to_string())?;
+    let mut body = match format {
+        Format::Ascii => Body::Ascii(
+            ::std::str::from_utf8(&data).map_err(|err| err.to_string())?.split_whitespace()
+        ),
+        _ => Body::Binary { bytes: &data, big_endian: format == Format::BinaryBigEndian }
+    };
+
+    let mut positions = vec![];
+    let mut normals = vec![];
+    let mut texcoords = vec![];
+    let mut colors = vec![];
+    let mut polygons: Vec<Vec<usize>> = vec![];
+    let mut fields_found = [false; 11];
+
+    for element in elements.iter() {
+        //the field each scalar property fills, and the scale applied to it
+        let targets: Vec<Option<(usize, f64)>> = element.properties.iter()
+            .map(|property| match *property {
+                Property::Scalar(scalar, ref name) if element.name == "vertex" =>
+                    VERTEX_FIELDS.iter().position(|names| names.contains(&name.as_str()))
+                        .map(|field| {
+                            fields_found[field] = true;
+                            (field, if field >= 8 { scalar.color_scale() } else { 1.0 })
+                        }),
+                _ => None
+            })
+            .collect();
+
+        for _ in 0..element.count {
+            let mut fields = [0.0f64; 11];
+            for (property, target) in element.properties.iter().zip(targets.iter()) {
+                match *property {
+                    Property::Scalar(scalar, _) => {
+                        let value = body.read(scalar)?;
+                        if let Some((field, scale)) = *target {
+                            fields[field] = value * scale;
+                        }
+                    },
+                    Property::List { count, item, ref name } => {
+                        let count = body.read(count)?;
+                        let count = whole_number(count)
+                            .ok_or_else(|| format!("invalid list count {}", count))?;
+                        //not preallocated, since a corrupt count can be huge
+                        let mut items = vec![];
+                        for _ in 0..count {
+                            items.push(body.read(item)?);
+                        }
+                        let is_face = element.name == "face" &&
+                            (name == "vertex_indices" || name == "vertex_index");
+                        if is_face {
+                            let polygon = items.iter()
+                                .map(|&index| whole_number(index)
+                                    .ok_or("face refers to a missing vertex"))
+                                .collect::<Result<Vec<usize>, &str>>()?;
+                            polygons.push(polygon);
+                        }
+                    }
+                }
+            }
+
+            if element.name == "vertex" {
+                let vec3 = |start: usize| Vec3::new(
+                    fields[start] as f32, fields[start + 1] as f32, fields[start + 2] as f32
+                );
+                positions.push(vec3(0));
+                normals.push(vec3(3));
+                texcoords.push(Vec2::new(fields[6] as f32, fields[7] as f32));
+                colors.push(vec3(8));
+            }
+        }
+    }
+
+    if !fields_found[0..3].iter().all(|&found| found) {
+        return Err("vertices need x, y and z".into());
+    }
+    let has_normals = fields_found[3..6].iter().all(|&found| found);
+    let has_texcoords = fields_found[6..8].iter().all(|&found| found);
+    let has_colors = fields_found[8..11].iter().all(|&found| found);
+    if !has_normals {
+        normals.clear();
+    }
+    if !has_colors {
+        colors.clear();
+    }
+
+    let mut triangles = Vec::<PartialTriangleIndices>::new();
+    for polygon in polygons.iter() {
+        if polygon.iter().any(|&index| index >= positions.len()) {
+            return Err("face refers to a missing vertex".into());
+        }
+        for corners in triangulate(&positions, polygon) {
+            let indices = [polygon[corners[0]], polygon[corners[1]], polygon[corners[2]]];
+            triangles.push((
+                indices,
+                if has_normals { Some(indices) } else { None },
+                if has_texcoords { Some(indices) } else { None }
+            ));
+        }
+    }
+    let triangles = generate_missing_normals(&positions, &mut normals, &triangles, normals_spec);
+
+    Ok(MeshInfo {
+        positions,
+        normals,
+        texcoords,
+        colors,
+        triangle_materials: vec![0; triangles.len()],
+        triangles,
+        material_groups: vec![None],
+        material_libraries: vec![]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utilities::color::Color3;
+
+    #[test]
+    fn test_read_ply() {
+        let ascii = "ply
+format ascii 1.0
+comment a quad and a triangle
+element vertex 5
+property float x
+property float y
+property float z
+property float s
+property float t
+property uchar red
+property uchar green
+property uchar blue
+element face 2
+property list uchar int vertex_indices
+property uchar flags
+end_header
+0 0 0 0 0 255 0 0
+1 0 0 1 0 0 255 0
+1 0 -1 1 1 0 0 255
+0 0 -1 0 1 255 255 255
+0 1 0 0 0 0 0 0
+4 0 1 2 3 7
+3 0 4 1 0
+";
+        let mesh_info = read_ply(ascii.as_bytes(), NormalsSpec::Flat).ok().unwrap();
+        assert_eq!(mesh_info.positions.len(), 5);
+        assert_eq!(mesh_info.triangles.len(), 3);
+        assert_eq!(mesh_info.texcoords[2], Vec2::new(1.0, 1.0));
+        assert_eq!(mesh_info.colors[1], Color3::new(0.0, 1.0, 0.0));
+        let (positions, normals, texcoords) = mesh_info.triangles[0];
+        assert_eq!(texcoords, Some(positions));
+        assert_eq!(mesh_info.normals[normals[0]], Vec3::new(0.0, 1.0, 0.0));
+
+        //the same quad in binary, with normals and without colors
+        let mut binary = b"ply
+format binary_little_endian 1.0
+element vertex 4
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+element face 1
+property list uchar uint vertex_indices
+end_header
+".to_vec();
+        let vertices = [[0.0f32, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 0.0, -1.0], [0.0, 0.0, -1.0]];
+        for vertex in vertices.iter() {
+            for &value in vertex.iter().chain([0.0f32, 1.0, 0.0].iter()) {
+                binary.extend((0..4).map(|byte| (value.to_bits() >> (8 * byte)) as u8));
+            }
+        }
+        binary.push(4);
+        for index in 0..4u32 {
+            binary.extend((0..4).map(|byte| (index >> (8 * byte)) as u8));
+        }
+        let mesh_info = read_ply(binary.as_slice(), NormalsSpec::Flat).ok().unwrap();
+        assert_eq!(mesh_info.positions[2], Vec3::new(1.0, 0.0, -1.0));
+        assert_eq!(mesh_info.triangles.len(), 2);
+        assert!(mesh_info.colors.is_empty());
+        let (positions, normals, texcoords) = mesh_info.triangles[1];
+        assert_eq!((normals, texcoords), (positions, None));
+        assert_eq!(mesh_info.normals.len(), 4);
+
+        //truncated data
+        assert!(read_ply(&binary[..binary.len() - 1], NormalsSpec::Flat).is_err());
+        //a corrupt face with a huge count fails without allocating it
+        let mut huge_count = b"ply
+format binary_little_endian 1.0
+element vertex 0
+property float x
+property float y
+property float z
+element face 1
+property list uint uint vertex_indices
+end_header
+".to_vec();
+        huge_count.extend(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+        assert!(read_ply(huge_count.as_slice(), NormalsSpec::Flat).is_err());
+
+        //negative and fractional indices and counts
+        let header = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\n\
+            property float y\nproperty float z\nelement face 1\n\
+            property list uchar int vertex_indices\nend_header\n0 0 0\n1 0 0\n0 0 -1\n";
+        for face in ["3 0 1 -1", "3 0 1 1.5", "-3 0 1 2", "2.5 0 1 2"].iter() {
+            let ply = format!("{}{}\n", header, face);
+            assert!(read_ply(ply.as_bytes(), NormalsSpec::Flat).is_err(), "{}", face);
+        }
+        let ply = format!("{}3 0 1 2\n", header);
+        assert_eq!(read_ply(ply.as_bytes(), NormalsSpec::Flat).ok().unwrap().triangles.len(), 1);
+    }
+}
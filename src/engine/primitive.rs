@@ -0,0 +1,541 @@
+extern crate cgmath;
+
+use std::rc::Rc;
+use std::cmp::Ordering;
+use std::f32;
+use std::f32::consts::PI;
+
+use utilities::math::*;
+use utilities::codable::CodableWrapper;
+use utilities::color::Color3;
+
+use super::bvh::*;
+use super::intersectable::*;
+use super::material::Material;
+use super::meshutils::MeshId;
+
+use self::cgmath::Transform;
+
+///Surfaces with exact intersections, centered on the origin of their object space.
+///Flat shapes lie on the xz plane, facing +y, and round shapes are around the y axis
+#[derive(Debug, Clone, Copy)]
+pub enum Shape {
+    Sphere { radius: f32 },
+    ///infinite without a size
+    Plane { half_size: Option<Vec2> },
+    Disk { radius: f32 },
+    Box { half_size: Vec3 },
+    ///closed by a disk at each end
+    Cylinder { radius: f32, half_height: f32 }
+}
+
+///Shape geometry at a point on its surface, in object space
+struct SurfacePoint {
+    normal: Vec3,
+    uv: Vec2,
+    ///dp/du and dp/dv
+    tangents: [Vec3; 2]
+}
+
+///Roots of a*t^2 + b*t + c, computed without cancellation
+fn solve_quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    let discriminant = b * b - 4.0 * a * c;
+    if a == 0.0 || discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let q = if b < 0.0 { -0.5 * (b - root) } else { -0.5 * (b + root) };
+    if q == 0.0 {
+        return Some((0.0, 0.0));
+    }
+    Some((q / a, c / q))
+}
+
+///texture coordinates and tangents of a flat shape spanning size on the xz plane
+fn flat_surface(point: Vec3, size: Vec2, normal: Vec3) -> SurfacePoint {
+    //v runs along -z on the top so that dp/du x dp/dv is the normal
+    let v_sign = -normal.y.signum();
+    SurfacePoint {
+        normal,
+        uv: Vec2::new(point.x / size.x + 0.5, v_sign * point.z / size.y + 0.5),
+        tangents: [Vec3::new(size.x, 0.0, 0.0), Vec3::new(0.0, 0.0, v_sign * size.y)]
+    }
+}
+
+///u around the y axis and dp/du at point, a distance radial_distance from the axis
+fn around_y_axis(point: Vec3, radial_distance: f32) -> (f32, Vec3, f32, f32) {
+    let phi = point.x.atan2(point.z);
+    let (sin_phi, cos_phi) = phi.sin_cos();
+    let u = (phi + PI) / (2.0 * PI);
+    let dp_du = Vec3::new(cos_phi, 0.0, -sin_phi) * (2.0 * PI * radial_distance);
+    (u, dp_du, sin_phi, cos_phi)
+}
+
+impl Shape {
+    ///Distances along the ray to every crossing of the surface, in any order.
+    ///Returns how many of distances were filled
+    fn distances(&self, origin: Vec3, direction: Vec3, distances: &mut [f32; 4]) -> usize {
+        let mut count = 0;
+        {
+            let mut push = |t: f32| {
+                distances[count] = t;
+                count += 1;
+            };
+            let flat_distance = || if direction.y != 0.0 {
+                Some(-origin.y / direction.y)
+            } else {
+                None
+            };
+
+            match *self {
+                Shape::Sphere { radius } => {
+                    if let Some((t0, t1)) = solve_quadratic(
+                        direction.dot(direction),
+                        2.0 * origin.dot(direction),
+                        origin.dot(origin) - radius * radius
+                    ) {
+                        push(t0);
+                        push(t1);
+                    }
+                },
+                Shape::Plane { half_size } => {
+                    if let Some(t) = flat_distance() {
+                        let point = origin + direction * t;
+                        let inside = half_size.map(|half_size|
+                            point.x.abs() <= half_size.x && point.z.abs() <= half_size.y
+                        ).unwrap_or(true);
+                        if inside {
+                            push(t);
+                        }
+                    }
+                },
+                Shape::Disk { radius } => {
+                    if let Some(t) = flat_distance() {
+                        let point = origin + direction * t;
+                        if point.x * point.x + point.z * point.z <= radius * radius {
+                            push(t);
+                        }
+                    }
+                },
+                Shape::Box { half_size } => {
+                    let (mut t_near, mut t_far) = (-f32::INFINITY, f32::INFINITY);
+                    for axis in 0..3 {
+                        if direction[axis] == 0.0 {
+                            if origin[axis].abs() > half_size[axis] {
+                                return 0;
+                            }
+                            continue;
+                        }
+                        let t1 = (-half_size[axis] - origin[axis]) / direction[axis];
+                        let t2 = (half_size[axis] - origin[axis]) / direction[axis];
+                        t_near = t_near.max(t1.min(t2));
+                        t_far = t_far.min(t1.max(t2));
+                    }
+                    if t_near <= t_far {
+                        push(t_near);
+                        push(t_far);
+                    }
+                },
+                Shape::Cylinder { radius, half_height } => {
+                    if let Some((t0, t1)) = solve_quadratic(
+                        direction.x * direction.x + direction.z * direction.z,
+                        2.0 * (origin.x * direction.x + origin.z * direction.z),
+                        origin.x * origin.x + origin.z * origin.z - radius * radius
+                    ) {
+                        for &t in &[t0, t1] {
+                            if (origin.y + direction.y * t).abs() <= half_height {
+                                push(t);
+                            }
+                        }
+                    }
+                    if direction.y != 0.0 {
+                        for &height in &[-half_height, half_height] {
+                            let t = (height - origin.y) / direction.y;
+                            let point = origin + direction * t;
+                            if point.x * point.x + point.z * point.z <= radius * radius {
+                                push(t);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        count
+    }
+
+    fn surface(&self, point: Vec3) -> SurfacePoint {
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        match *self {
+            Shape::Sphere { radius } => {
+                //v goes from 0 at the bottom to 1 at the top
+                let radial_distance = (point.x * point.x + point.z * point.z).sqrt();
+                let (u, dp_du, sin_phi, cos_phi) =
+                    around_y_axis(point, radial_distance.max(1e-4 * radius));
+                let theta = (point.y / radius).max(-1.0).min(1.0).acos();
+                SurfacePoint {
+                    normal: point / radius,
+                    uv: Vec2::new(u, 1.0 - theta / PI),
+                    tangents: [
+                        dp_du,
+                        Vec3::new(-point.y * sin_phi, radial_distance, -point.y * cos_phi) * PI
+                    ]
+                }
+            },
+            Shape::Plane { half_size } => match half_size {
+                Some(half_size) => flat_surface(point, half_size * 2.0, up),
+                //one texture repetition per unit
+                None => flat_surface(point, Vec2::new(1.0, 1.0), up)
+            },
+            Shape::Disk { radius } => flat_surface(point, Vec2::new(radius, radius) * 2.0, up),
+            Shape::Box { half_size } => {
+                //the face is the one the point is relatively closest to
+                let relative = point.div_element_wise(half_size);
+                let axis = if relative.x.abs() >= relative.y.abs() &&
+                    relative.x.abs() >= relative.z.abs() {
+                    0
+                } else if relative.y.abs() >= relative.z.abs() {
+                    1
+                } else {
+                    2
+                };
+                let sign = relative[axis].signum();
+                //the other two axes in cyclic order, so that u x v is the face normal
+                let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+                let mut normal = Vec3::new(0.0, 0.0, 0.0);
+                normal[axis] = sign;
+                let mut dp_du = Vec3::new(0.0, 0.0, 0.0);
+                dp_du[u_axis] = sign * 2.0 * half_size[u_axis];
+                let mut dp_dv = Vec3::new(0.0, 0.0, 0.0);
+                dp_dv[v_axis] = 2.0 * half_size[v_axis];
+                SurfacePoint {
+                    normal,
+                    uv: Vec2::new(sign * relative[u_axis] / 2.0 + 0.5, relative[v_axis] / 2.0 + 0.5),
+                    tangents: [dp_du, dp_dv]
+                }
+            },
+            Shape::Cylinder { radius, half_height } => {
+                let radial_distance = (point.x * point.x + point.z * point.z).sqrt();
+                if point.y.abs() / half_height >= radial_distance / radius {
+                    let normal = Vec3::new(0.0, point.y.signum(), 0.0);
+                    flat_surface(point, Vec2::new(radius, radius) * 2.0, normal)
+                } else {
+                    let (u, dp_du, _, _) = around_y_axis(point, radius);
+                    SurfacePoint {
+                        normal: Vec3::new(point.x, 0.0, point.z) / radial_distance,
+                        uv: Vec2::new(u, point.y / (2.0 * half_height) + 0.5),
+                        tangents: [dp_du, Vec3::new(0.0, 2.0 * half_height, 0.0)]
+                    }
+                }
+            }
+        }
+    }
+
+    ///None for unbounded shapes
+    fn bounds(&self) -> Option<AABoundingBox> {
+        let half_size = match *self {
+            Shape::Sphere { radius } => Vec3::new(radius, radius, radius),
+            Shape::Plane { half_size } => match half_size {
+                Some(half_size) => Vec3::new(half_size.x, 0.0, half_size.y),
+                None => return None
+            },
+            Shape::Disk { radius } => Vec3::new(radius, 0.0, radius),
+            Shape::Box { half_size } => half_size,
+            Shape::Cylinder { radius, half_height } => Vec3::new(radius, half_height, radius)
+        };
+        Some(AABoundingBox { lower: -half_size, upper: half_size })
+    }
+
+    fn surface_area(&self) -> f32 {
+        match *self {
+            Shape::Sphere { radius } => 4.0 * PI * radius * radius,
+            Shape::Plane { half_size } => half_size
+                .map(|half_size| 4.0 * half_size.x * half_size.y)
+                .unwrap_or(f32::INFINITY),
+            Shape::Disk { radius } => PI * radius * radius,
+            Shape::Box { half_size } => 8.0 * (
+                half_size.x * half_size.y + half_size.y * half_size.z + half_size.z * half_size.x
+            ),
+            Shape::Cylinder { radius, half_height } =>
+                2.0 * PI * radius * (2.0 * half_height + radius)
+        }
+    }
+}
+
+///Shape placed in the scene by a transformation
+#[derive(Debug, Clone)]
+pub struct Primitive {
+    shape: Shape,
+    object_to_world: Matrix4,
+    world_to_object: Matrix4,
+    ///inverse transpose of object_to_world, for normals
+    normal_transform: Matrix4,
+    ///if true, rays hitting the back of flat shapes see them from the front
+    double_sided: bool,
+    material: Rc<Material>,
+    mesh_id: MeshId,
+    aa_bounding_box: AABoundingBox,
+    surface_area: f32
+}
+
+impl Primitive {
+    ///None if transform isn't invertible
+    pub fn new(shape: Shape, transform: Matrix4, material: Rc<Material>,
+               double_sided: bool, mesh_id: MeshId) -> Option<Primitive>
+    {
+        let world_to_object = transform.invert()?;
+        let aa_bounding_box = match shape.bounds() {
+            Some(bounds) => {
+                let mut world_bounds = AABoundingBox::empty();
+                for corner in 0..8 {
+                    let pick = |bit: usize, axis: usize|
+                        if corner & bit == 0 { bounds.lower[axis] } else { bounds.upper[axis] };
+                    let point = Vec3::new(pick(1, 0), pick(2, 1), pick(4, 2));
+                    let point = (transform * point.extend(1.0)).truncate();
+                    world_bounds = AABoundingBox {
+                        lower: world_bounds.lower.min_elem_wise(&point),
+                        upper: world_bounds.upper.max_elem_wise(&point)
+                    };
+                }
+                world_bounds
+            },
+            None => AABoundingBox {
+                lower: Vec3::new(-f32::INFINITY, -f32::INFINITY, -f32::INFINITY),
+                upper: Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY)
+            }
+        };
+        //exact for uniform scaling
+        let scale = Matrix3::from_cols(transform.x.truncate(), transform.y.truncate(),
+                                       transform.z.truncate()).determinant().abs();
+        Some(Primitive {
+            shape,
+            object_to_world: transform,
+            world_to_object,
+            normal_transform: world_to_object.transpose(),
+            double_sided,
+            material,
+            mesh_id,
+            aa_bounding_box,
+            surface_area: shape.surface_area() * scale.powf(2.0 / 3.0)
+        })
+    }
+
+    pub fn mesh_id(&self) -> MeshId {
+        self.mesh_id
+    }
+
+    ///Infinite planes can't be put in a bounding volume hierarchy
+    pub fn is_bounded(&self) -> bool {
+        self.shape.bounds().is_some()
+    }
+}
+
+impl HasAABoundingBox for Primitive {
+    fn aa_bounding_box_ref(&self) -> &AABoundingBox {
+        &self.aa_bounding_box
+    }
+}
+
+impl HasSurfaceArea for Primitive {
+    fn surface_area(&self) -> f32 {
+        self.surface_area
+    }
+}
+
+impl Intersectable for Primitive {
+    fn intersect(&self, args: IntersectionArgs) -> bool {
+        let ray = args.ray;
+        let record = args.record;
+
+        let (position, direction): (Vec3, Vec3) = if_avx!(
+            avx = (ray.position.into(), ray.direction.into()),
+            noavx = (ray.position, *ray.direction.value())
+        );
+
+        //the ray is not normalized in object space, so distances along it stay the same
+        let origin = (self.world_to_object * position.extend(1.0)).truncate();
+        let object_direction = self.world_to_object.transform_vector(direction);
+
+        let mut distances = [0.0; 4];
+        let count = self.shape.distances(origin, object_direction, &mut distances);
+        let distances = &mut distances[..count];
+        distances.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+        for &t in distances.iter() {
+            if t < ray.t_range.start || ray.t_range.end <= t || t >= record.t {
+                continue;
+            }
+
+            let object_position = origin + object_direction * t;
+            let surface = self.shape.surface(object_position);
+            let mut normal = self.normal_transform.transform_vector(surface.normal);
+            if self.double_sided && normal.dot(direction) > 0.0 {
+                normal = -normal;
+            }
+            let normal = normal.unit();
+
+            let hit = IntersectionRecord {
+                position: position + direction * t,
+                normal: normal.clone(),
+                geometric_normal: normal,
+                uv: surface.uv,
+                vertex_color: Color3::new(1.0, 1.0, 1.0),
+                object_position,
+                tangents: [self.object_to_world.transform_vector(surface.tangents[0]),
+                    self.object_to_world.transform_vector(surface.tangents[1])],
+                object_tangents: surface.tangents,
+                ray_direction: direction,
+                t,
+                material: Some(self.material.clone()),
+                mesh_id: Some(self.mesh_id)
+            };
+
+            if is_cut_out(&hit) {
+                continue;
+            }
+
+            *record = hit;
+            return true;
+        }
+        false
+    }
+}
+
+///Shapes of analytic primitives in the scene file. Sizes default to 1
+#[derive(Deserialize)]
+#[serde(tag = "kind")]
+pub enum ShapeSpec {
+    Sphere { radius: Option<f32> },
+    ///size along x and z. Infinite without a size
+    Plane { size: Option<(f32, f32)> },
+    Disk { radius: Option<f32> },
+    ///size along x, y and z
+    Box { size: Option<CodableWrapper<Vec3>> },
+    ///height along y
+    Cylinder { radius: Option<f32>, height: Option<f32> }
+}
+
+impl ShapeSpec {
+    pub fn to_shape(&self) -> Result<Shape, String> {
+        let positive = |value: Option<f32>, name: &str| {
+            let value = value.unwrap_or(1.0);
+            if value > 0.0 {
+                Ok(value)
+            } else {
+                Err(format!("{} must be positive, got {}", name, value))
+            }
+        };
+        let shape = match *self {
+            ShapeSpec::Sphere { radius } => Shape::Sphere { radius: positive(radius, "radius")? },
+            ShapeSpec::Plane { size } => Shape::Plane {
+                half_size: match size {
+                    Some((x, z)) => Some(
+                        Vec2::new(positive(Some(x), "size")?, positive(Some(z), "size")?) / 2.0
+                    ),
+                    None => None
+                }
+            },
+            ShapeSpec::Disk { radius } => Shape::Disk { radius: positive(radius, "radius")? },
+            ShapeSpec::Box { ref size } => {
+                let size = size.as_ref().map(|size| size.get())
+                    .unwrap_or(Vec3::new(1.0, 1.0, 1.0));
+                Shape::Box { half_size: Vec3::new(
+                    positive(Some(size.x), "size")?,
+                    positive(Some(size.y), "size")?,
+                    positive(Some(size.z), "size")?
+                ) / 2.0 }
+            },
+            ShapeSpec::Cylinder { radius, height } => Shape::Cylinder {
+                radius: positive(radius, "radius")?,
+                half_height: positive(height, "height")? / 2.0
+            }
+        };
+        Ok(shape)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engine::material::default_material;
+    use super::cgmath::Rad;
+    #[cfg(target_feature = "avx")]
+    use utilities::simd::SimdRay;
+
+    fn intersect(primitive: &Primitive, position: Vec3, direction: Vec3) -> IntersectionRecord {
+        let ray = RayUnit::new(position, direction.unit());
+        #[cfg(target_feature = "avx")]
+        let ray = &SimdRay::new(&ray);
+        #[cfg(not(target_feature = "avx"))]
+        let ray = &ray;
+        let mut record = IntersectionRecord::no_intersection();
+        primitive.intersect(IntersectionArgs {
+            ray,
+            record: &mut record,
+            intersection_order: IntersectionOrderKind::FirstIntersection
+        });
+        record
+    }
+
+    fn assert_vec_eq(a: Vec3, b: Vec3) {
+        assert!((a - b).magnitude() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_primitive_intersection() {
+        let material = Rc::new(default_material());
+        let primitive = |shape: Shape, transform: Matrix4|
+            Primitive::new(shape, transform, material.clone(), false, 0).unwrap();
+
+        //a sphere of radius 2 centered at (0, 0, -5)
+        let sphere = primitive(
+            Shape::Sphere { radius: 1.0 },
+            Matrix4::from_translation(Vec3::new(0.0, 0.0, -5.0)) * Matrix4::from_scale(2.0)
+        );
+        let hit = intersect(&sphere, Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(apprx_eq(hit.t, 3.0, 1e-4), "{}", hit.t);
+        assert_vec_eq(*hit.normal.value(), Vec3::new(0.0, 0.0, 1.0));
+        assert_vec_eq(hit.object_position, Vec3::new(0.0, 0.0, 1.0));
+        let tangent_normal = hit.tangents[0].cross(hit.tangents[1]).normalize();
+        assert_vec_eq(tangent_normal, *hit.normal.value());
+        //from inside, the far side is hit with the normal still pointing out
+        let hit = intersect(&sphere, Vec3::new(0.0, 0.0, -5.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(apprx_eq(hit.t, 2.0, 1e-4), "{}", hit.t);
+        assert_vec_eq(*hit.normal.value(), Vec3::new(1.0, 0.0, 0.0));
+        assert!(!intersect(&sphere, Vec3::new(0.0, 2.1, 0.0), Vec3::new(0.0, 0.0, -1.0))
+            .intersected());
+
+        //normals of non uniformly scaled shapes use the inverse transpose
+        let squashed = primitive(
+            Shape::Box { half_size: Vec3::new(0.5, 0.5, 0.5) },
+            Matrix4::from_angle_z(Rad(PI / 4.0)) * Matrix4::from_nonuniform_scale(4.0, 1.0, 1.0)
+        );
+        let hit = intersect(&squashed, Vec3::new(-1.0, 1.0, 0.0) * 3.0, Vec3::new(1.0, -1.0, 0.0));
+        assert_vec_eq(*hit.normal.value(), Vec3::new(-1.0, 1.0, 0.0).normalize());
+        assert!(apprx_eq(hit.t, 18f32.sqrt() - 0.5, 1e-4), "{}", hit.t);
+
+        let cylinder = primitive(
+            Shape::Cylinder { radius: 1.0, half_height: 1.0 }, <Matrix4 as One>::one()
+        );
+        let hit = intersect(&cylinder, Vec3::new(0.5, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(apprx_eq(hit.t, 4.0, 1e-4), "{}", hit.t);
+        assert_vec_eq(*hit.normal.value(), Vec3::new(0.0, 1.0, 0.0));
+        let hit = intersect(&cylinder, Vec3::new(-5.0, 0.5, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(apprx_eq(hit.t, 4.0, 1e-4), "{}", hit.t);
+        assert_vec_eq(*hit.normal.value(), Vec3::new(-1.0, 0.0, 0.0));
+
+        //planes are one sided unless double sided, like meshes
+        let plane = primitive(Shape::Plane { half_size: None }, <Matrix4 as One>::one());
+        assert!(!plane.is_bounded());
+        let hit = intersect(&plane, Vec3::new(100.0, 1.0, -300.0), Vec3::new(1.0, -1.0, 0.0));
+        assert!(apprx_eq(hit.t, 2f32.sqrt(), 1e-4), "{}", hit.t);
+        let hit = intersect(&plane, Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_vec_eq(*hit.normal.value(), Vec3::new(0.0, 1.0, 0.0));
+        let double_sided = Primitive::new(
+            Shape::Disk { radius: 1.0 }, <Matrix4 as One>::one(), material.clone(), true, 0
+        ).unwrap();
+        let hit = intersect(&double_sided, Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_vec_eq(*hit.normal.value(), Vec3::new(0.0, -1.0, 0.0));
+        assert!(!intersect(&double_sided, Vec3::new(1.5, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0))
+            .intersected());
+    }
+}
@@ -1,8 +1,5 @@
-extern crate cgmath;
 extern crate rand;
 
-use self::cgmath::Rad;
-
 use utilities::math::*;
 use std::f32::consts::PI;
 use std::f32;
@@ -110,30 +107,99 @@ impl Warper for CosineHemisphereWarper {
     }
 }
 
-/// Samples half vectors for ggx
+///Smallest ggx alpha used by shaders. The distribution becomes numerically
+///unstable below this, so smoother surfaces should use delta shaders instead
+pub const MIN_GGX_ALPHA: f32 = 0.001;
+
+///Anisotropic GGX distribution of microfacet normals. Directions are in the
+///local frame where the macro normal is [0, 1, 0], alpha_x is the roughness
+///along the tangent [1, 0, 0] and alpha_y along the bitangent [0, 0, 1]
+#[derive(Debug, Clone, Copy)]
+pub struct GGXDistribution {
+    pub alpha_x: f32,
+    pub alpha_y: f32
+}
+
+impl GGXDistribution {
+    pub fn isotropic(alpha: f32) -> GGXDistribution {
+        GGXDistribution { alpha_x: alpha, alpha_y: alpha }
+    }
+
+    ///Density of microfacet normals, projected onto the macro surface
+    pub fn distribution(&self, half_vector: &Vec3) -> f32 {
+        if half_vector.y <= 0.0 {
+            return 0.0
+        }
+        let (ax, ay) = (self.alpha_x, self.alpha_y);
+        let stretched = (half_vector.x / ax).powi(2) + (half_vector.z / ay).powi(2) +
+            half_vector.y.powi(2);
+        1.0 / (PI * ax * ay * stretched.powi(2))
+    }
+
+    fn lambda(&self, v: &Vec3) -> f32 {
+        let cos2 = v.y.powi(2);
+        if cos2 == 0.0 {
+            return f32::INFINITY
+        }
+        let alpha2_tan2 = ((self.alpha_x * v.x).powi(2) + (self.alpha_y * v.z).powi(2)) / cos2;
+        ((1.0 + alpha2_tan2).sqrt() - 1.0) / 2.0
+    }
+
+    ///Smith masking term for a single direction v, which may be on either side of the surface
+    pub fn smith_g1(&self, v: &Vec3, half_vector: &Vec3) -> f32 {
+        if v.y == 0.0 || v.dot(*half_vector) / v.y <= 0.0 {
+            return 0.0
+        }
+        1.0 / (1.0 + self.lambda(v))
+    }
+
+    ///Density of the microfacet normals visible from outgoing, which must be above the surface
+    pub fn visible_distribution(&self, outgoing: &Vec3, half_vector: &Vec3) -> f32 {
+        if outgoing.y <= 0.0 {
+            return 0.0
+        }
+        self.smith_g1(outgoing, half_vector) * outgoing.dot(*half_vector).max(0.0) *
+            self.distribution(half_vector) / outgoing.y
+    }
+}
+
+///Samples microfacet normals visible from a direction above the surface.
+///Heitz 2018, "Sampling the GGX Distribution of Visible Normals"
 #[derive(Debug)]
-pub struct GGXNormalHalfVectorWarper {
-    pub alpha: f32
+pub struct GGXVisibleNormalWarper {
+    pub distribution: GGXDistribution,
+    pub outgoing: Vec3
 }
-impl Warper for GGXNormalHalfVectorWarper {
+impl Warper for GGXVisibleNormalWarper {
     type Output = Vec3;
 
     fn warp(&self, from: &Vec2) -> Self::Output {
-        //xi is like a fancy squiggly E
-        let xi = from.x;
-        let phi = Rad(2.0 * PI * from.y);
-        let theta = Rad(
-            ( self.alpha * (xi / (1.0 - xi)).sqrt() ).atan()
-        );
-        let rotation =
-            Matrix3::from_angle_y(phi) *
-            Matrix3::from_angle_z(theta);
-        rotation * Vec3::new(0.0, 1.0, 0.0)
+        let (ax, ay) = (self.distribution.alpha_x, self.distribution.alpha_y);
+        //the paper's frame is z up, so y and z are swapped going in and out
+        let view = Vec3::new(ax * self.outgoing.x, ay * self.outgoing.z, self.outgoing.y)
+            .normalize();
+        let length2 = view.x.powi(2) + view.y.powi(2);
+        let tangent1 = if length2 > 0.0 {
+            Vec3::new(-view.y, view.x, 0.0) / length2.sqrt()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let tangent2 = view.cross(tangent1);
+
+        let radius = from.x.sqrt();
+        let phi = 2.0 * PI * from.y;
+        let t1 = radius * phi.cos();
+        let s = 0.5 * (1.0 + view.z);
+        let t2 = (1.0 - s) * (1.0 - t1.powi(2)).max(0.0).sqrt() + s * radius * phi.sin();
+        let hemisphere_normal = tangent1 * t1 + tangent2 * t2 +
+            view * (1.0 - t1.powi(2) - t2.powi(2)).max(0.0).sqrt();
+
+        Vec3::new(ax * hemisphere_normal.x, hemisphere_normal.z.max(0.0), ay * hemisphere_normal.y)
+            .normalize()
     }
 
     fn pdf(&self, sample: &Self::Output) -> f32 {
-        let normal = Vec3::new(0.0, 1.0, 0.0).unit();
-        ggx_distribution(&sample.unit(), &normal, self.alpha) * normal.value().dot(*sample)
+        self.distribution.visible_distribution(&self.outgoing, sample)
     }
 }
 
@@ -181,6 +247,19 @@ pub fn ggx_distribution(half_vector: &UnitVec3, normal: &UnitVec3, alpha: f32) -
     a2 / denom
 }
 
+///Smith masking term for a single direction v with the GGX distribution.
+///v may be on either side of the normal
+pub fn ggx_smith_g1(v: &UnitVec3, half_vector: &UnitVec3, normal: &UnitVec3, alpha: f32) -> f32 {
+    let v = *v.value();
+    let cos_v = v.dot(*normal.value());
+    if cos_v == 0.0 || chi_plus(v.dot(*half_vector.value()) / cos_v) == 0.0 {
+        return 0.0
+    }
+    let cos2 = cos_v.powi(2);
+    let tan2 = (1.0 - cos2).max(0.0) / cos2;
+    2.0 / (1.0 + (1.0 + alpha.powi(2) * tan2).sqrt())
+}
+
 /* TODO REPLACE GGX WITH THIS
 float distribution_of_normals_ggx(vec3 half_vector, vec3 normal, float roughness) {
     float angle = acos(dot(half_vector, normal));
@@ -218,3 +297,32 @@ pub fn chi_plus(v: f32) -> f32 {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utilities::sampler::PseudorandomSampler;
+
+    #[test]
+    fn test_ggx_visible_normal_pdf() {
+        let distribution = GGXDistribution { alpha_x: 0.5, alpha_y: 0.1 };
+        for outgoing in &[Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.6, 0.3, 0.5).normalize()] {
+            let warper = GGXVisibleNormalWarper { distribution, outgoing: *outgoing };
+            //the pdf integrates to one over the hemisphere
+            let samples = 200000;
+            let integral: f32 = (0..samples)
+                .map(|_| {
+                    let direction = UniformHemisphereWarper.sample(&mut PseudorandomSampler);
+                    warper.pdf(&direction) / UniformHemisphereWarper.pdf(&direction)
+                })
+                .sum::<f32>() / samples as f32;
+            assert!((integral - 1.0).abs() < 0.05, "integral {}", integral);
+
+            //sampled normals are visible from outgoing
+            for _ in 0..1000 {
+                let half_vector = warper.sample(&mut PseudorandomSampler);
+                assert!(half_vector.dot(*outgoing) >= 0.0);
+            }
+        }
+    }
+}
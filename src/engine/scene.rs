@@ -11,33 +11,135 @@ use utilities::math::*;
 use utilities::simd::{SimdRay};
 use utilities::color::*;
 
-use super::meshutils::{MeshObject};
+use super::meshutils::{MeshObject, MeshId};
 use super::camera::*;
 use super::intersectable::*;
+use super::primitive::Primitive;
 use super::scene_builder::{SceneBuilder, SceneSpec};
-use super::shader::{Shader};
+use super::material::Material;
 use super::bvh::*;
+use super::light_sampling::LightSampler;
 
 use std::rc::Rc;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::f32;
 
+///Set of meshes referred to by name in the scene file.
+///If include is missing, every mesh is included.
+#[derive(Debug, Deserialize)]
+pub struct MeshLinkSpec {
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>
+}
+
+impl MeshLinkSpec {
+    pub fn to_filter(&self, mesh_ids_by_name: &HashMap<String, Vec<MeshId>>)
+        -> Result<MeshFilter, String>
+    {
+        let resolve = |names: &Vec<String>| -> Result<HashSet<MeshId>, String> {
+            let mut ids = HashSet::new();
+            for name in names {
+                let named_ids = mesh_ids_by_name.get(name)
+                    .ok_or(format!("no mesh named {}", name))?;
+                ids.extend(named_ids.iter().cloned());
+            }
+            Ok(ids)
+        };
+
+        let excluded = match self.exclude {
+            Some(ref names) => resolve(names)?,
+            None => HashSet::new()
+        };
+
+        Ok(match self.include {
+            Some(ref names) => MeshFilter::Only(
+                resolve(names)?.difference(&excluded).cloned().collect()
+            ),
+            None if excluded.is_empty() => MeshFilter::All,
+            None => MeshFilter::AllExcept(excluded)
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MeshFilter {
+    All,
+    Only(HashSet<MeshId>),
+    AllExcept(HashSet<MeshId>)
+}
+
+impl MeshFilter {
+    pub fn contains(&self, mesh_id: MeshId) -> bool {
+        match *self {
+            MeshFilter::All => true,
+            MeshFilter::Only(ref ids) => ids.contains(&mesh_id),
+            MeshFilter::AllExcept(ref ids) => !ids.contains(&mesh_id)
+        }
+    }
+}
+
+impl Default for MeshFilter {
+    fn default() -> Self {
+        MeshFilter::All
+    }
+}
+
+///Meshes that a light interacts with, resolved from the light's link specs
+#[derive(Debug, Clone, Default)]
+pub struct LightLinks {
+    pub illuminated: MeshFilter,
+    pub shadow_casters: MeshFilter
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Light {
     pub position: CodableWrapper<Vec3>,
-    pub intensity: f32
+    pub intensity: f32,
+    ///meshes lit by this light
+    pub illumination_links: Option<MeshLinkSpec>,
+    ///meshes that block this light
+    pub shadow_links: Option<MeshLinkSpec>,
+    #[serde(skip)]
+    pub links: LightLinks
+}
+
+impl Light {
+    pub fn resolve_links(&mut self, mesh_ids_by_name: &HashMap<String, Vec<MeshId>>)
+        -> Result<(), String>
+    {
+        if let Some(ref spec) = self.illumination_links {
+            self.links.illuminated = spec.to_filter(mesh_ids_by_name)?;
+        }
+        if let Some(ref spec) = self.shadow_links {
+            self.links.shadow_casters = spec.to_filter(mesh_ids_by_name)?;
+        }
+        Ok(())
+    }
+
+    pub fn illuminates(&self, mesh_id: Option<MeshId>) -> bool {
+        mesh_id.map(|id| self.links.illuminated.contains(id)).unwrap_or(true)
+    }
+
+    ///Quantity proportional to the total power emitted by the light
+    pub fn power(&self) -> f32 {
+        self.intensity.max(0.0)
+    }
 }
 
 #[derive(Debug)]
 pub struct Scene {
     pub background_color: Color3,
     pub camera: Camera,
-    pub shaders: HashMap<String, Rc<Shader>>,
+    pub materials: HashMap<String, Rc<Material>>,
     //pub meshes: Vec<MeshObject>, //refactor code to maybe include ref to object intersected with
     pub lights: Vec<Light>,
+    pub light_sampler: LightSampler,
     pub intersection_accel: BVHAccelerator,
-    pub triangles: Vec<IntersectableTriangle>
+    ///triangles and bounded primitives, indexed by intersection_accel
+    pub objects: Vec<SceneObject>,
+    ///infinite planes
+    pub unbounded_primitives: Vec<Primitive>
 }
 
 impl_deserialize!(Scene, |deserializer| {
@@ -49,34 +151,38 @@ impl_deserialize!(Scene, |deserializer| {
 
 impl Scene {
     pub fn new_from_builder(builder: SceneBuilder) -> Scene {
-        let mut bb_triangles: Vec<TriangleWithAABoundingBox> = builder.meshes.into_iter()
+        let (bounded_primitives, unbounded_primitives): (Vec<Primitive>, Vec<Primitive>) =
+            builder.primitives.into_iter().partition(|primitive| primitive.is_bounded());
+
+        let mut bb_objects: Vec<SceneObjectWithAABoundingBox> = builder.meshes.into_iter()
             .flat_map(|mesh: MeshObject| mesh.triangles.iter()
-                      .map(|triangle| TriangleWithAABoundingBox::new_from_triangle(triangle))
-                      .collect::<Vec<TriangleWithAABoundingBox>>())
+                      .map(|triangle| SceneObjectWithAABoundingBox::Triangle(
+                          TriangleWithAABoundingBox::new_from_triangle(triangle)
+                      ))
+                      .collect::<Vec<SceneObjectWithAABoundingBox>>())
+            .chain(bounded_primitives.into_iter().map(SceneObjectWithAABoundingBox::Primitive))
             .collect();
 
-        let intersection_accel = BVHAccelerator::new(&mut bb_triangles);
-        let intersectable_triangles: Vec<IntersectableTriangle> = bb_triangles.iter()
-            .map(|bb_triangle| IntersectableTriangle::new_from_triangle(&bb_triangle.triangle))
+        let intersection_accel = BVHAccelerator::new(&mut bb_objects);
+        let objects: Vec<SceneObject> = bb_objects.iter()
+            .map(|bb_object| bb_object.to_scene_object())
             .collect();
 
         Scene {
             background_color: builder.background_color.get(),
             camera: builder.camera,
-            shaders: {
-                let mut shaders = HashMap::<String, Rc<Shader>>::new();
-                for (key, value) in builder.shaders.iter() {
-                    shaders.insert(key.clone(), value.get());
-                }
-                shaders
-            },
+            materials: builder.materials,
+            light_sampler: LightSampler::new(builder.lights.as_slice()),
             lights: builder.lights,
             intersection_accel: intersection_accel,
-            triangles: intersectable_triangles
+            objects,
+            unbounded_primitives
         }
     }
 
-    fn intersect_intern(&self, ray: &RayUnit, obstruction_only: bool) -> IntersectionRecord {
+    fn intersect_intern(&self, ray: &RayUnit, obstruction_only: bool, filter: &MeshFilter)
+        -> IntersectionRecord
+    {
         let index_ranges = self.intersection_accel.intersect_boxes(ray);
         let mut record = IntersectionRecord::no_intersection();
 
@@ -87,7 +193,10 @@ impl Scene {
 
         'outer: for range in index_ranges {
             for i in range {
-                let obj = &self.triangles[i];
+                let obj = &self.objects[i];
+                if !filter.contains(obj.mesh_id()) {
+                    continue;
+                }
 
                 let args = IntersectionArgs {
                     ray: intersection_ray,
@@ -102,25 +211,123 @@ impl Scene {
                 }
             }
         }
+
+        //unbounded primitives are outside of the hierarchy, so every ray is tested against them
+        if !(obstruction_only && record.intersected()) {
+            for primitive in self.unbounded_primitives.iter() {
+                if !filter.contains(primitive.mesh_id()) {
+                    continue;
+                }
+
+                let args = IntersectionArgs {
+                    ray: intersection_ray,
+                    record: &mut record,
+                    intersection_order: IntersectionOrderKind::FirstIntersection
+                };
+
+                if primitive.intersect(args) && obstruction_only {
+                    break;
+                }
+            }
+        }
         record
     }
 
     pub fn intersect(&self, ray: &RayUnit) -> IntersectionRecord {
-        self.intersect_intern(ray, false)
+        self.intersect_intern(ray, false, &MeshFilter::All)
     }
 
     ///detects an intersection between origin and destination. Not necessarily
-    ///the first intersection
+    ///the first intersection. Only meshes in shadow_casters can obstruct
     ///TODO this logic doesn't belong here
     pub fn intersect_for_obstruction(
-        &self, origin: Vec3, destination: Vec3
+        &self, origin: Vec3, destination: Vec3, shadow_casters: &MeshFilter
     ) -> IntersectionRecord {
         let ray = {
             let mut ray = RayUnit::new_epsilon_offset(origin, (destination - origin).unit());
             ray.t_range.end = (destination - origin).magnitude();
             ray
         };
-        self.intersect_intern(&ray, true)
+        self.intersect_intern(&ray, true, shadow_casters)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate serde_yaml;
+
+    fn link_spec(include: Option<&[&str]>, exclude: Option<&[&str]>) -> MeshLinkSpec {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        MeshLinkSpec { include: include.map(&names), exclude: exclude.map(&names) }
+    }
+
+    #[test]
+    fn test_mesh_link_filters() {
+        let mut mesh_ids_by_name = HashMap::new();
+        mesh_ids_by_name.insert("floor".to_string(), vec![0]);
+        mesh_ids_by_name.insert("ball".to_string(), vec![1]);
+        //several meshes may share a name
+        mesh_ids_by_name.insert("rocks".to_string(), vec![2, 3]);
+        let contained = |spec: MeshLinkSpec| -> Vec<MeshId> {
+            let filter = spec.to_filter(&mesh_ids_by_name).ok().unwrap();
+            (0..4).filter(|&id| filter.contains(id)).collect()
+        };
+
+        assert_eq!(contained(link_spec(None, None)), vec![0, 1, 2, 3]);
+        assert_eq!(contained(link_spec(Some(&["rocks"]), None)), vec![2, 3]);
+        assert_eq!(contained(link_spec(None, Some(&["rocks"]))), vec![0, 1]);
+        //exclude wins over include
+        assert_eq!(contained(link_spec(Some(&["ball", "rocks"]), Some(&["rocks"]))), vec![1]);
+
+        assert!(link_spec(Some(&["sky"]), None).to_filter(&mesh_ids_by_name).is_err());
+        assert!(link_spec(None, Some(&["sky"])).to_filter(&mesh_ids_by_name).is_err());
+    }
+
+    #[test]
+    fn test_shadow_links() {
+        //a ball between the origin and lights right above it
+        let yaml = "
+background_color: [0, 0, 0]
+camera:
+  position: [0, 0, 5]
+  direction: [0, 0, -1]
+  up: [0, 1, 0]
+  plane_distance: 1
+  plane_width: 1
+  plane_height: 1
+shaders:
+  white: {kind: Diffuse, color: 1}
+meshes: []
+primitives:
+  - name: 'ball'
+    shape: {kind: Sphere, radius: 0.5}
+    shader: 'white'
+    transformations:
+      - {Translate: [0, 2, 0]}
+  - name: 'floor'
+    shape: {kind: Plane}
+    shader: 'white'
+    transformations:
+      - {Translate: [0, -1, 0]}
+lights:
+  - position: [0, 4, 0]
+    intensity: 1
+  - position: [0, 4, 0]
+    intensity: 1
+    shadow_links: {exclude: ['ball']}
+    illumination_links: {include: ['ball']}
+";
+        let scene: Scene = serde_yaml::from_str(yaml).unwrap();
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        let blocked = |light: &Light| scene.intersect_for_obstruction(
+            origin, light.position.get(), &light.links.shadow_casters
+        ).intersected();
+
+        assert!(blocked(&scene.lights[0]));
+        assert!(!blocked(&scene.lights[1]));
+        assert!(scene.lights[0].illuminates(Some(1)));
+        assert!(scene.lights[1].illuminates(Some(0)) && !scene.lights[1].illuminates(Some(1)));
+    }
+}
@@ -5,13 +5,18 @@ use utilities::math::*;
 
 use super::transformable::*;
 use super::meshutils::*;
+use super::primitive::{Primitive, ShapeSpec};
 use super::scene::*;
 use super::color::*;
 use super::camera::*;
-use super::shader::*;
+use super::material::*;
+use super::mtl::load_mtl_materials;
+use super::ply::read_ply;
+use super::stl::read_stl;
 
-use std::io::BufReader;
+use std::io::{BufRead, BufReader};
 use std::fs::File;
+use std::path::Path;
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::error::Error;
@@ -19,60 +24,155 @@ use std::error::Error;
 
 pub struct SceneError(pub String);
 
-fn polygons_to_triangles(polys: &Vec<obj::raw::object::Polygon>)
-                         -> Result<Vec<([usize; 3], [usize; 3])>, SceneError>
+///Triangulates polys. Also returns the index of each polygon's first triangle,
+///followed by the number of triangles
+fn polygons_to_triangles(polys: &Vec<obj::raw::object::Polygon>, positions: &[Vec3])
+    -> Result<(Vec<PartialTriangleIndices>, Vec<usize>), SceneError>
 {
-    let mut collect = Vec::<([usize; 3], [usize; 3])>::new();
+    use self::obj::raw::object::Polygon;
+
+    let mut collect = Vec::<PartialTriangleIndices>::new();
+    let mut polygon_starts = Vec::with_capacity(polys.len() + 1);
     for poly in polys {
-        let pn_vec = match poly {
-            &obj::raw::object::Polygon::PTN(ref ptn_polygon) => {
-                let pn_vec: Vec<_> = ptn_polygon.iter()
-                    .map(|ptn| (ptn.0, ptn.2))
-                    .collect();
-                pn_vec
-            }
-            &obj::raw::object::Polygon::PN(ref pn_vec) => {
-                pn_vec.clone()
-            },
-            _ => return Err(SceneError("an error occured while converting polys to tris".into()))
+        polygon_starts.push(collect.len());
+        //(position, normal, texcoord)
+        let pnt_vec: Vec<(usize, Option<usize>, Option<usize>)> = match *poly {
+            Polygon::P(ref p_vec) => p_vec.iter()
+                .map(|&p| (p, None, None))
+                .collect(),
+            Polygon::PT(ref pt_vec) => pt_vec.iter()
+                .map(|&(p, t)| (p, None, Some(t)))
+                .collect(),
+            Polygon::PN(ref pn_vec) => pn_vec.iter()
+                .map(|&(p, n)| (p, Some(n), None))
+                .collect(),
+            Polygon::PTN(ref ptn_vec) => ptn_vec.iter()
+                .map(|&(p, t, n)| (p, Some(n), Some(t)))
+                .collect()
         };
 
-        if pn_vec.len() != 3 {
-            return Err(SceneError("Fatal error: polygons must be triangles!".into()));
+        let position_indices: Vec<usize> = pnt_vec.iter().map(|pnt| pnt.0).collect();
+        if position_indices.iter().any(|&index| index >= positions.len()) {
+            return Err(SceneError("polygon refers to a missing vertex".into()));
         }
 
-        let ind = (
-            [pn_vec[0].0, pn_vec[1].0, pn_vec[2].0],
-            [pn_vec[0].1, pn_vec[1].1, pn_vec[2].1]
-        );
-        collect.push(ind);
+        for corners in triangulate(positions, &position_indices) {
+            let (a, b, c) = (pnt_vec[corners[0]], pnt_vec[corners[1]], pnt_vec[corners[2]]);
+            let normals = match (a.1, b.1, c.1) {
+                (Some(n0), Some(n1), Some(n2)) => Some([n0, n1, n2]),
+                _ => None
+            };
+            let texcoords = match (a.2, b.2, c.2) {
+                (Some(t0), Some(t1), Some(t2)) => Some([t0, t1, t2]),
+                _ => None
+            };
+            collect.push(([a.0, b.0, c.0], normals, texcoords));
+        }
     }
+    polygon_starts.push(collect.len());
 
-    Ok(collect)
+    Ok((collect, polygon_starts))
 }
 
-fn parse_mesh_info(filepath: &str) -> Result<MeshInfo, SceneError> {
+///Picks the loader by the file's extension. Files that aren't ply or stl are read as objs
+fn parse_mesh_info(filepath: &str, normals_spec: NormalsSpec) -> Result<MeshInfo, SceneError> {
     let reader = File::open(filepath).map(|file| BufReader::new(file))
         .map_err(|err| SceneError(err.description().into()))?;
+    let extension = Path::new(filepath).extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    let format_error = |message: String| SceneError(format!("{}: {}", filepath, message));
+    match extension.as_ref().map(|extension| extension.as_str()) {
+        Some("ply") => read_ply(reader, normals_spec).map_err(format_error),
+        Some("stl") => read_stl(reader, normals_spec).map_err(format_error),
+        _ => read_obj_mesh_info(reader, normals_spec)
+    }
+}
 
+///Polygons are triangulated, and faces without normals get generated ones
+fn read_obj_mesh_info<R: BufRead>(reader: R, normals_spec: NormalsSpec)
+    -> Result<MeshInfo, SceneError>
+{
     let object = obj::raw::parse_obj(reader)
         .map_err(|err| SceneError(err.description().into()))?;
 
-    let triangles = polygons_to_triangles(&object.polygons)?;
+    let positions: Vec<Vec3> = object.positions.iter()
+        .map(|pos| Vec3::new(pos.0, pos.1, pos.2)).collect();
+    let mut normals: Vec<Vec3> = object.normals.iter()
+        .map(|pos| Vec3::new(pos.0, pos.1, pos.2)).collect();
+
+    let (triangles, polygon_starts) = polygons_to_triangles(&object.polygons, &positions)?;
+    let triangles = generate_missing_normals(&positions, &mut normals, &triangles, normals_spec);
+
+    //faces outside of every usemtl group use group 0
+    let mut material_groups = vec![None];
+    let mut triangle_materials = vec![0; triangles.len()];
+    for (name, group) in object.meshes.iter() {
+        material_groups.push(Some(name.clone()));
+        for range in group.polygons.iter() {
+            let triangle_range = polygon_starts[range.start]..polygon_starts[range.end];
+            for triangle_material in triangle_materials[triangle_range].iter_mut() {
+                *triangle_material = material_groups.len() - 1;
+            }
+        }
+    }
 
     Ok(MeshInfo {
-        positions: object.positions.iter()
-            .map(|pos| Vec3::new(pos.0, pos.1, pos.2)).collect(),
-        normals: object.normals.iter()
-            .map(|pos| Vec3::new(pos.0, pos.1, pos.2)).collect(),
-        triangles: triangles
+        positions,
+        normals,
+        texcoords: object.tex_coords.iter()
+            .map(|tex| Vec2::new(tex.0, tex.1)).collect(),
+        colors: vec![],
+        triangles: triangles,
+        material_groups,
+        triangle_materials,
+        material_libraries: object.material_libraries.clone()
     })
 }
 
+///The shaders of an obj's material groups, made from its mtl files.
+///Groups without a material in the mtl files get the default material
+fn mtl_group_materials(src: &str, mesh_info: &MeshInfo) -> Result<Vec<Rc<Material>>, SceneError> {
+    let specs = load_mtl_materials(src, &mesh_info.material_libraries)
+        .and_then(|specs| build_materials(&specs))
+        .map_err(|message| SceneError(format!("Invalid mtl material: {}", message)))?;
+    let default = Rc::new(default_material());
+    Ok(mesh_info.material_groups.iter()
+        .map(|name| name.as_ref()
+            .and_then(|name| specs.get(name))
+            .unwrap_or(&default)
+            .clone())
+        .collect())
+}
+
 #[derive(Deserialize)]
 pub struct MeshSpec {
+    ///used by lights to refer to this mesh
+    pub name: Option<String>,
+    ///obj, ply or stl file
     pub src: String,
-    pub shader: String,
+    ///shader for the whole mesh. Without it, each usemtl group gets a shader
+    ///made from the obj's mtl files, and other formats get the default shader
+    #[serde(rename = "shader")]
+    pub material: Option<String>,
+    ///shade the back of the mesh's faces like the front. For open surfaces such as
+    ///planes; closed transmissive meshes need their back faces to be seen from inside
+    pub double_sided: Option<bool>,
+    ///how normals are made for faces without them. Smooth by default
+    pub normals: Option<NormalsSpec>,
+    pub transformations: Option<TransformationSpecList>
+}
+
+///Analytic shape, intersected exactly instead of through a triangle mesh
+#[derive(Deserialize)]
+pub struct PrimitiveSpec {
+    ///used by lights to refer to this primitive, like a mesh name
+    pub name: Option<String>,
+    pub shape: ShapeSpec,
+    #[serde(rename = "shader")]
+    pub material: String,
+    ///shade the back of flat shapes like the front
+    pub double_sided: Option<bool>,
     pub transformations: Option<TransformationSpecList>
 }
 
@@ -80,20 +180,38 @@ pub struct MeshSpec {
 pub struct SceneSpec {
     pub background_color: CodableWrapper<Color3>,
     pub camera: Camera,
-    pub shaders: HashMap<String, CodableWrapper<Rc<Shader>>>,
+    #[serde(rename = "shaders")]
+    pub materials: HashMap<String, MaterialEntrySpec>,
     pub meshes: Vec<MeshSpec>,
+    pub primitives: Option<Vec<PrimitiveSpec>>,
     pub lights: Vec<Light>
 }
 
 impl SceneSpec {
-    fn make_meshes(&self) -> Result<Vec<MeshObject>, SceneError> {
+    fn make_materials(&self) -> Result<HashMap<String, Rc<Material>>, SceneError> {
+        build_materials(&self.materials)
+            .map_err(|message| SceneError(format!("Invalid shader: {}", message)))
+    }
+
+    fn make_meshes(&self, materials: &HashMap<String, Rc<Material>>)
+        -> Result<Vec<MeshObject>, SceneError>
+    {
         let mut result_meshes: Vec<MeshObject> = vec![];
-        for mesh_spec in &self.meshes {
-            let shader = self.shaders.get(&mesh_spec.shader)
-                .ok_or(SceneError("Shader not found".into()))?;
-            let mesh_info = parse_mesh_info(mesh_spec.src.as_str())?;
-            let mut mesh = MeshObject::new(&mesh_info, &shader.get().clone())
+        for (mesh_id, mesh_spec) in self.meshes.iter().enumerate() {
+            let mesh_info = parse_mesh_info(
+                mesh_spec.src.as_str(), mesh_spec.normals.unwrap_or_default()
+            )?;
+            let group_materials = match mesh_spec.material {
+                Some(ref name) => {
+                    let material = materials.get(name)
+                        .ok_or(SceneError("Material not found".into()))?;
+                    vec![material.clone(); mesh_info.material_groups.len()]
+                },
+                None => mtl_group_materials(&mesh_spec.src, &mesh_info)?
+            };
+            let mut mesh = MeshObject::new(&mesh_info, &group_materials, mesh_id)
                 .ok_or(SceneError("MeshObject failed to build.".into()))?;
+            mesh.set_double_sided(mesh_spec.double_sided.unwrap_or(false));
             if let Some(transformations) = mesh_spec.transformations.as_ref()
                 .map(transformation_list_to_mat4) {
                 mesh.transform_in_place(&transformations);
@@ -103,13 +221,62 @@ impl SceneSpec {
         Ok(result_meshes)
     }
 
-    pub fn to_builder(self) -> Result<SceneBuilder, SceneError> {
-        let meshes = self.make_meshes()?;
+    fn primitive_specs(&self) -> &[PrimitiveSpec] {
+        self.primitives.as_ref().map(|primitives| primitives.as_slice()).unwrap_or(&[])
+    }
+
+    ///Primitives' mesh ids follow the meshes'
+    fn make_primitives(&self, materials: &HashMap<String, Rc<Material>>)
+        -> Result<Vec<Primitive>, SceneError>
+    {
+        let mut primitives = vec![];
+        for (index, spec) in self.primitive_specs().iter().enumerate() {
+            let shape = spec.shape.to_shape()
+                .map_err(|message| SceneError(format!("Invalid primitive: {}", message)))?;
+            let material = materials.get(&spec.material)
+                .ok_or(SceneError("Material not found".into()))?;
+            let transformations = spec.transformations.as_ref()
+                .map(transformation_list_to_mat4)
+                .unwrap_or(Matrix4::one());
+            let primitive = Primitive::new(
+                shape,
+                transformations,
+                material.clone(),
+                spec.double_sided.unwrap_or(false),
+                self.meshes.len() + index
+            ).ok_or(SceneError("Primitive transformations must be invertible".into()))?;
+            primitives.push(primitive);
+        }
+        Ok(primitives)
+    }
+
+    fn mesh_ids_by_name(&self) -> HashMap<String, Vec<MeshId>> {
+        let mut mesh_ids = HashMap::<String, Vec<MeshId>>::new();
+        let names = self.meshes.iter().map(|mesh_spec| &mesh_spec.name)
+            .chain(self.primitive_specs().iter().map(|primitive_spec| &primitive_spec.name));
+        for (mesh_id, name) in names.enumerate() {
+            if let Some(ref name) = *name {
+                mesh_ids.entry(name.clone()).or_insert_with(Vec::new).push(mesh_id);
+            }
+        }
+        mesh_ids
+    }
+
+    pub fn to_builder(mut self) -> Result<SceneBuilder, SceneError> {
+        let materials = self.make_materials()?;
+        let meshes = self.make_meshes(&materials)?;
+        let primitives = self.make_primitives(&materials)?;
+        let mesh_ids_by_name = self.mesh_ids_by_name();
+        for light in self.lights.iter_mut() {
+            light.resolve_links(&mesh_ids_by_name)
+                .map_err(|message| SceneError(format!("Invalid light link: {}", message)))?;
+        }
         Ok(SceneBuilder::new()
            .background_color(self.background_color)
            .camera(self.camera)
-           .shaders(self.shaders)
+           .materials(materials)
            .meshes(meshes)
+           .primitives(primitives)
            .lights(self.lights))
     }
 }
@@ -117,8 +284,9 @@ impl SceneSpec {
 pub struct SceneBuilder {
     pub background_color: CodableWrapper<Color3>,
     pub camera: Camera,
-    pub shaders: HashMap<String, CodableWrapper<Rc<Shader>>>,
+    pub materials: HashMap<String, Rc<Material>>,
     pub meshes: Vec<MeshObject>,
+    pub primitives: Vec<Primitive>,
     pub lights: Vec<Light>
 }
 
@@ -136,8 +304,9 @@ impl SceneBuilder {
         SceneBuilder {
             background_color: Color3::new(0.0,0.0,0.0).into(),
             camera: Camera::new_default(),
-            shaders: HashMap::new(),
+            materials: HashMap::new(),
             meshes: Vec::new(),
+            primitives: Vec::new(),
             lights: Vec::new()
         }
     }
@@ -148,8 +317,57 @@ impl SceneBuilder {
 
     builder_param!(background_color, CodableWrapper<Color3>);
     builder_param!(camera, Camera);
-    builder_param!(shaders, HashMap<String, CodableWrapper<Rc<Shader>>>);
+    builder_param!(materials, HashMap<String, Rc<Material>>);
     builder_param!(meshes, Vec<MeshObject>);
+    builder_param!(primitives, Vec<Primitive>);
     builder_param!(lights, Vec<Light>);
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_polygon_mesh() {
+        let obj = "
+            v 0 0 0
+            v 1 0 0
+            v 1 0 -1
+            v 0 0 -1
+            v 0.5 1 -0.5
+            vt 0 0
+            vt 1 0
+            vt 1 1
+            vt 0 1
+            usemtl base
+            f 1/1 4/4 3/3 2/2
+            usemtl sides
+            f 1 2 5
+            f 2 3 5
+            f 3 4 5
+            f 4 1 5
+        ";
+        let mesh_info = read_obj_mesh_info(obj.as_bytes(), NormalsSpec::Flat).ok().unwrap();
+        assert_eq!(mesh_info.triangles.len(), 6);
+        let group_names: Vec<&str> = mesh_info.triangle_materials.iter()
+            .map(|&group| mesh_info.material_groups[group].as_ref().unwrap().as_str())
+            .collect();
+        assert_eq!(group_names, vec!["base", "base", "sides", "sides", "sides", "sides"]);
+        assert_eq!(mesh_info.triangles[0].2.is_some(), true);
+        assert_eq!(mesh_info.triangles[2].2, None);
+
+        //the quad faces down, with one normal per corner
+        let (_, base_normals, _) = mesh_info.triangles[1];
+        for &index in base_normals.iter() {
+            assert_eq!(mesh_info.normals[index], Vec3::new(0.0, -1.0, 0.0));
+        }
+
+        let material = Rc::new(default_material());
+        let materials = vec![material; mesh_info.material_groups.len()];
+        assert!(MeshObject::new(&mesh_info, &materials, 0).is_some());
+
+        let missing_vertex = "v 0 0 0\nv 1 0 0\nf 1 2 3\n";
+        assert!(read_obj_mesh_info(missing_vertex.as_bytes(), NormalsSpec::Flat).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use utilities::math::*;
+
+use super::meshutils::*;
+
+use std::io::Read;
+use std::collections::HashMap;
+
+const BINARY_HEADER_SIZE: usize = 84;
+const BINARY_TRIANGLE_SIZE: usize = 50;
+
+fn little_endian(bytes: &[u8]) -> u32 {
+    bytes.iter().rev().fold(0, |bits, &byte| (bits << 8) | byte as u32)
+}
+
+///Corners of every facet of an ascii stl file
+fn read_ascii_corners(text: &str) -> Result<Vec<Vec3>, String> {
+    //also catches truncated binary files whose header starts with solid
+    if !text.contains("endsolid") {
+        return Err("missing endsolid".into());
+    }
+    let mut corners = vec![];
+    let mut tokens = text.split_whitespace();
+    while let Some(token) = tokens.next() {
+        if token == "vertex" {
+            let mut coordinate = || -> Result<f32, String> {
+                let token = tokens.next().ok_or("unexpected end of file")?;
+                token.parse::<f32>().map_err(|_| format!("invalid number {}", token))
+            };
+            corners.push(Vec3::new(coordinate()?, coordinate()?, coordinate()?));
+        }
+    }
+    Ok(corners)
+}
+
+fn read_binary_corners(bytes: &[u8]) -> Vec<Vec3> {
+    bytes[BINARY_HEADER_SIZE..].chunks(BINARY_TRIANGLE_SIZE)
+        //each triangle starts with a normal, which is ignored
+        .flat_map(|triangle| triangle[12..48].chunks(12))
+        .map(|corner| Vec3::new(
+            f32::from_bits(little_endian(&corner[0..4])),
+            f32::from_bits(little_endian(&corner[4..8])),
+            f32::from_bits(little_endian(&corner[8..12]))
+        ))
+        .collect()
+}
+
+///Reads an ascii or binary stl file. Corners at the same position are merged so that
+///normals can be smoothed across facets, and normals are always generated
+pub fn read_stl<R: Read>(mut reader: R, normals_spec: NormalsSpec) -> Result<MeshInfo, String> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes).map_err(|err| err.to_string())?;
+
+    //binary files may also start with solid, but their size always matches their count
+    let is_binary = bytes.len() >= BINARY_HEADER_SIZE &&
+        BINARY_HEADER_SIZE + little_endian(&bytes[80..84]) as usize * BINARY_TRIANGLE_SIZE ==
+            bytes.len();
+
+    let corners = if is_binary {
+        read_binary_corners(&bytes)
+    } else if bytes.starts_with(b"solid") {
+        read_ascii_corners(::std::str::from_utf8(&bytes).map_err(|err| err.to_string())?)?
+    } else {
+        return Err("not an stl file".into())
+    };
+    if corners.len() % 3 != 0 {
+        return Err("facets need three vertices".into());
+    }
+
+    let mut positions = vec![];
+    let mut position_indices = HashMap::<[u32; 3], usize>::new();
+    let corner_indices: Vec<usize> = corners.iter()
+        .map(|corner| {
+            //+ 0.0 turns -0.0 into 0.0
+            let key = [(corner.x + 0.0).to_bits(), (corner.y + 0.0).to_bits(),
+                (corner.z + 0.0).to_bits()];
+            *position_indices.entry(key).or_insert_with(|| {
+                positions.push(*corner);
+                positions.len() - 1
+            })
+        })
+        .collect();
+
+    let triangles: Vec<PartialTriangleIndices> = corner_indices.chunks(3)
+        .map(|triangle| ([triangle[0], triangle[1], triangle[2]], None, None))
+        .collect();
+    let mut normals = vec![];
+    let triangles = generate_missing_normals(&positions, &mut normals, &triangles, normals_spec);
+
+    Ok(MeshInfo {
+        positions,
+        normals,
+        texcoords: vec![],
+        colors: vec![],
+        triangle_materials: vec![0; triangles.len()],
+        triangles,
+        material_groups: vec![None],
+        material_libraries: vec![]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_stl() {
+        //two facets sharing an edge, the first with a wrong normal
+        let ascii = "solid square
+  facet normal 0 0 0
+    outer loop
+      vertex 0 0 0
+      vertex 1 0 0
+      vertex 1 0 -1
+    endloop
+  endfacet
+  facet normal 0 1 0
+    outer loop
+      vertex 0 0 0
+      vertex 1 0 -1
+      vertex -0 0 -1
+    endloop
+  endfacet
+endsolid square
+";
+        let mesh_info = read_stl(ascii.as_bytes(), NormalsSpec::default()).ok().unwrap();
+        assert_eq!(mesh_info.positions.len(), 4);
+        assert_eq!(mesh_info.triangles.len(), 2);
+        assert_eq!(mesh_info.triangles[1].0, [0, 2, 3]);
+        for &(_, normals, _) in mesh_info.triangles.iter() {
+            for &normal in normals.iter() {
+                assert_eq!(mesh_info.normals[normal], Vec3::new(0.0, 1.0, 0.0));
+            }
+        }
+
+        //the same facets in binary, with a header starting with solid
+        let mut binary = b"solid".to_vec();
+        binary.resize(80, 0);
+        binary.extend(&[2, 0, 0, 0]);
+        let corners = [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, -1.0,
+            0.0, 0.0, 0.0, 1.0, 0.0, -1.0, 0.0, 0.0, -1.0];
+        for triangle in corners.chunks(9) {
+            for &value in [0.0f32; 3].iter().chain(triangle.iter()) {
+                binary.extend((0..4).map(|byte| (value.to_bits() >> (8 * byte)) as u8));
+            }
+            binary.extend(&[0, 0]);
+        }
+        let binary_info = read_stl(binary.as_slice(), NormalsSpec::Flat).ok().unwrap();
+        assert_eq!(binary_info.positions, mesh_info.positions);
+        assert_eq!(binary_info.triangles[1].0, [0, 2, 3]);
+
+        assert!(read_stl(&binary[..100], NormalsSpec::Flat).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+extern crate image;
+
+use utilities::math::*;
+use utilities::color::*;
+
+use self::image::{Pixel, ColorType, DynamicImage, GenericImageView};
+
+use std::fmt;
+
+///How texture coordinates outside of [0, 1] are mapped onto the image
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror
+}
+
+impl WrapMode {
+    fn wrap(&self, index: i64, size: i64) -> usize {
+        let wrapped = match *self {
+            WrapMode::Repeat => ((index % size) + size) % size,
+            WrapMode::Clamp => index.max(0).min(size - 1),
+            WrapMode::Mirror => {
+                let period = ((index % (2 * size)) + 2 * size) % (2 * size);
+                if period < size { period } else { 2 * size - 1 - period }
+            }
+        };
+        wrapped as usize
+    }
+}
+
+fn open(filepath: &str) -> Result<DynamicImage, String> {
+    image::open(filepath)
+        .map_err(|err| format!("could not load image {}: {}", filepath, err))
+        .and_then(|image| nonempty(image, filepath))
+}
+
+///Texel indices are wrapped modulo the image's size, so it needs at least one texel
+fn nonempty(image: DynamicImage, filepath: &str) -> Result<DynamicImage, String> {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return Err(format!("could not load image {}: it has no pixels", filepath))
+    }
+    Ok(image)
+}
+
+///An image sampled with bilinear filtering. Texels are stored in linear color
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    texels: Vec<Color3>,
+    wrap: WrapMode
+}
+
+impl ImageTexture {
+    pub fn load(filepath: &str, wrap: WrapMode, srgb: bool) -> Result<ImageTexture, String> {
+        let image = open(filepath)?.to_rgb();
+        let decode = |channel: u8| {
+            let value = channel as f32 / 255.0;
+            if srgb { srgb_to_linear(value) } else { value }
+        };
+        let texels = image.pixels()
+            .map(|pixel| {
+                let channels = pixel.channels();
+                Color3::new(decode(channels[0]), decode(channels[1]), decode(channels[2]))
+            })
+            .collect();
+
+        Ok(ImageTexture {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            texels,
+            wrap
+        })
+    }
+
+    ///The image's alpha channel in all three channels. Images without
+    ///an alpha channel give their linear gray values instead
+    pub fn load_alpha(filepath: &str, wrap: WrapMode) -> Result<ImageTexture, String> {
+        let image = open(filepath)?;
+        let has_alpha = match image.color() {
+            ColorType::GrayA(_) | ColorType::RGBA(_) | ColorType::BGRA(_) => true,
+            _ => false
+        };
+        if !has_alpha {
+            return ImageTexture::load(filepath, wrap, false)
+        }
+        let image = image.to_rgba();
+        let texels = image.pixels()
+            .map(|pixel| {
+                let alpha = pixel.channels()[3] as f32 / 255.0;
+                Color3::new(alpha, alpha, alpha)
+            })
+            .collect();
+
+        Ok(ImageTexture {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            texels,
+            wrap
+        })
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Color3 {
+        let x = self.wrap.wrap(x, self.width as i64);
+        let y = self.wrap.wrap(y, self.height as i64);
+        self.texels[y * self.width + x]
+    }
+
+    ///uv (0, 0) is the bottom left corner of the image
+    pub fn lookup(&self, uv: &Vec2) -> Color3 {
+        //texel centers are at half integer coordinates
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = self.texel(x0, y0) * (1.0 - tx) + self.texel(x0 + 1, y0) * tx;
+        let bottom = self.texel(x0, y0 + 1) * (1.0 - tx) + self.texel(x0 + 1, y0 + 1) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+}
+
+impl fmt::Debug for ImageTexture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ImageTexture {}x{}", self.width, self.height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_modes() {
+        assert_eq!(WrapMode::Repeat.wrap(-1, 4), 3);
+        assert_eq!(WrapMode::Repeat.wrap(5, 4), 1);
+        assert_eq!(WrapMode::Clamp.wrap(-3, 4), 0);
+        assert_eq!(WrapMode::Clamp.wrap(9, 4), 3);
+        assert_eq!(WrapMode::Mirror.wrap(-1, 4), 0);
+        assert_eq!(WrapMode::Mirror.wrap(4, 4), 3);
+        assert_eq!(WrapMode::Mirror.wrap(9, 4), 1);
+    }
+
+    #[test]
+    fn test_empty_image() {
+        assert!(nonempty(DynamicImage::new_rgb8(0, 4), "empty.png").is_err());
+        assert!(nonempty(DynamicImage::new_rgb8(4, 0), "empty.png").is_err());
+        assert!(nonempty(DynamicImage::new_rgb8(1, 1), "texel.png").is_ok());
+    }
+
+    #[test]
+    fn test_bilinear_lookup() {
+        //2x1 image, black on the left and white on the right
+        let image = ImageTexture {
+            width: 2,
+            height: 1,
+            texels: vec![Color3::new(0.0, 0.0, 0.0), Color3::new(1.0, 1.0, 1.0)],
+            wrap: WrapMode::Clamp
+        };
+        assert_near!(image.lookup(&Vec2::new(0.25, 0.5)).x, 0.0, 0.00001);
+        assert_near!(image.lookup(&Vec2::new(0.5, 0.5)).x, 0.5, 0.00001);
+        assert_near!(image.lookup(&Vec2::new(0.75, 0.5)).x, 1.0, 0.00001);
+        assert_near!(image.lookup(&Vec2::new(1.5, 0.5)).x, 1.0, 0.00001);
+    }
+}
@@ -0,0 +1,111 @@
+//!Material parameters that vary over a surface
+
+use utilities::codable::*;
+use utilities::color::*;
+
+use super::intersectable::IntersectionRecord;
+
+mod image_texture;
+mod procedural;
+mod node;
+
+pub use self::image_texture::{ImageTexture, WrapMode};
+pub use self::procedural::{ProceduralTexture, Pattern, TextureSpace};
+pub use self::node::{Node, NodeSpec};
+
+#[derive(Debug)]
+pub enum Texture {
+    Constant(Color3),
+    Image(ImageTexture),
+    Procedural(ProceduralTexture),
+    Node(Box<Node>)
+}
+
+impl Texture {
+    pub fn color(&self, record: &IntersectionRecord) -> Color3 {
+        match *self {
+            Texture::Constant(color) => color,
+            Texture::Image(ref image) => image.lookup(&record.uv),
+            Texture::Procedural(ref procedural) => procedural.color(record),
+            Texture::Node(ref node) => node.color(record)
+        }
+    }
+
+    ///Scalar parameters use the average of the color channels
+    pub fn value(&self, record: &IntersectionRecord) -> f32 {
+        let color = self.color(record);
+        (color.x + color.y + color.z) / 3.0
+    }
+}
+
+impl From<Color3> for Texture {
+    fn from(color: Color3) -> Texture {
+        Texture::Constant(color)
+    }
+}
+
+///A shader parameter given as a constant, an image file, a procedural pattern or
+///a graph of nodes
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum TextureSpec {
+    Scalar(f32),
+    Color(CodableWrapper<Color3>),
+    ///before Image, whose fields some nodes share
+    Node(NodeSpec),
+    ///srgb defaults to true for colors and false for scalar parameters
+    Image { image: String, wrap: Option<WrapMode>, srgb: Option<bool> },
+    ///space defaults to Object and colors to black and white
+    Procedural {
+        pattern: Pattern,
+        space: Option<TextureSpace>,
+        colors: Option<(CodableWrapper<Color3>, CodableWrapper<Color3>)>
+    }
+}
+
+impl TextureSpec {
+    pub fn to_color_texture(&self) -> Result<Texture, String> {
+        self.to_texture(true)
+    }
+
+    pub fn to_scalar_texture(&self) -> Result<Texture, String> {
+        self.to_texture(false)
+    }
+
+    ///Like to_scalar_texture, but images give their alpha channel if they have one
+    pub fn to_opacity_texture(&self) -> Result<Texture, String> {
+        match *self {
+            TextureSpec::Image { ref image, wrap, .. } => Ok(Texture::Image(
+                ImageTexture::load_alpha(image.as_str(), wrap.unwrap_or(WrapMode::Repeat))?
+            )),
+            _ => self.to_scalar_texture()
+        }
+    }
+
+    fn to_texture(&self, srgb_by_default: bool) -> Result<Texture, String> {
+        match *self {
+            TextureSpec::Scalar(value) => Ok(Color3::new(value, value, value).into()),
+            TextureSpec::Color(ref color) => Ok(color.get().into()),
+            TextureSpec::Image { ref image, wrap, srgb } => {
+                let image = ImageTexture::load(
+                    image.as_str(),
+                    wrap.unwrap_or(WrapMode::Repeat),
+                    srgb.unwrap_or(srgb_by_default)
+                )?;
+                Ok(Texture::Image(image))
+            },
+            TextureSpec::Procedural { ref pattern, space, ref colors } => {
+                let colors = colors.as_ref()
+                    .map(|&(ref first, ref second)| (first.get(), second.get()))
+                    .unwrap_or((Color3::new(0.0, 0.0, 0.0), Color3::new(1.0, 1.0, 1.0)));
+                Ok(Texture::Procedural(ProceduralTexture {
+                    pattern: pattern.clone(),
+                    space: space.unwrap_or(TextureSpace::Object),
+                    colors
+                }))
+            },
+            TextureSpec::Node(ref node) =>
+                Ok(Texture::Node(Box::new(node.to_node(srgb_by_default)?)))
+        }
+    }
+}
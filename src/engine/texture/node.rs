@@ -0,0 +1,213 @@
+use utilities::math::*;
+use utilities::color::*;
+
+use engine::intersectable::IntersectionRecord;
+use engine::material::fresnel_dielectric;
+use super::{Texture, TextureSpec, ImageTexture, WrapMode, TextureSpace};
+
+///Node of a shader graph, evaluated for each hit. Vectors are returned as colors
+#[derive(Debug)]
+pub enum Node {
+    UV,
+    Position(TextureSpace),
+    Normal,
+    VertexColor { srgb: bool },
+    ///image looked up at the x and y of coordinates
+    Image { image: ImageTexture, coordinates: Texture },
+    Mix { first: Texture, second: Texture, weight: Texture },
+    Add(Texture, Texture),
+    Multiply(Texture, Texture),
+    Channel(Texture, usize),
+    Remap { input: Texture, from: (f32, f32), to: (f32, f32), clamp: bool },
+    Fresnel { index_of_refraction: f32 }
+}
+
+impl Node {
+    pub fn color(&self, record: &IntersectionRecord) -> Color3 {
+        match *self {
+            Node::UV => Color3::new(record.uv.x, record.uv.y, 0.0),
+            Node::Position(space) => space.point(record),
+            Node::Normal => *record.normal.value(),
+            Node::VertexColor { srgb } => {
+                let color = record.vertex_color;
+                if srgb {
+                    Color3::new(srgb_to_linear(color.x), srgb_to_linear(color.y),
+                                srgb_to_linear(color.z))
+                } else {
+                    color
+                }
+            },
+            Node::Image { ref image, ref coordinates } => {
+                let coordinates = coordinates.color(record);
+                image.lookup(&Vec2::new(coordinates.x, coordinates.y))
+            },
+            Node::Mix { ref first, ref second, ref weight } => {
+                let weight = weight.color(record);
+                let one = Color3::new(1.0, 1.0, 1.0);
+                first.color(record).mul_element_wise(one - weight) +
+                    second.color(record).mul_element_wise(weight)
+            },
+            Node::Add(ref first, ref second) => first.color(record) + second.color(record),
+            Node::Multiply(ref first, ref second) =>
+                first.color(record).mul_element_wise(second.color(record)),
+            Node::Channel(ref input, index) => {
+                let value = input.color(record)[index];
+                Color3::new(value, value, value)
+            },
+            Node::Remap { ref input, from: (from_min, from_max), to: (to_min, to_max), clamp } => {
+                let remap = |value: f32| {
+                    let fraction = if from_max != from_min {
+                        (value - from_min) / (from_max - from_min)
+                    } else {
+                        0.0
+                    };
+                    let fraction = if clamp { fraction.max(0.0).min(1.0) } else { fraction };
+                    to_min + (to_max - to_min) * fraction
+                };
+                let input = input.color(record);
+                Color3::new(remap(input.x), remap(input.y), remap(input.z))
+            },
+            Node::Fresnel { index_of_refraction } => {
+                let cosine = record.ray_direction.dot(*record.normal.value()).abs();
+                let reflectance = fresnel_dielectric(cosine, 1.0, index_of_refraction);
+                Color3::new(reflectance, reflectance, reflectance)
+            }
+        }
+    }
+}
+
+///Shader graph nodes. Their inputs are parameters themselves, so they can be constants,
+///textures or other nodes
+#[derive(Deserialize)]
+#[serde(tag = "node")]
+pub enum NodeSpec {
+    ///texture coordinates as [u, v, 0]
+    UV,
+    ///space defaults to Object
+    Position { space: Option<TextureSpace> },
+    ///world space shading normal
+    Normal,
+    ///color of the mesh's vertices, such as those of a ply file. White without them.
+    ///srgb works as for image parameters
+    VertexColor { srgb: Option<bool> },
+    ///image looked up at the x and y of coordinates instead of the texture coordinates.
+    ///wrap and srgb work as for image parameters
+    Image {
+        image: String,
+        coordinates: Box<TextureSpec>,
+        wrap: Option<WrapMode>,
+        srgb: Option<bool>
+    },
+    ///per channel weight. 0 gives only first, 1 gives only second
+    Mix { first: Box<TextureSpec>, second: Box<TextureSpec>, weight: Box<TextureSpec> },
+    Add { first: Box<TextureSpec>, second: Box<TextureSpec> },
+    Multiply { first: Box<TextureSpec>, second: Box<TextureSpec> },
+    ///one channel of input, 0 for red or x to 2 for blue or z, in all three channels
+    Channel { input: Box<TextureSpec>, index: usize },
+    ///maps each channel of input linearly from the range from to the range to,
+    ///clamped to it unless clamp is false
+    Remap { input: Box<TextureSpec>, from: (f32, f32), to: (f32, f32), clamp: Option<bool> },
+    ///reflectance of a dielectric seen from the ray that hit the surface
+    Fresnel { ior: f32 }
+}
+
+impl NodeSpec {
+    ///Parameters feeding colors are srgb decoded by default, like the node itself.
+    ///Weights and coordinates are not
+    pub fn to_node(&self, srgb_by_default: bool) -> Result<Node, String> {
+        let node = match *self {
+            NodeSpec::UV => Node::UV,
+            NodeSpec::Position { space } => Node::Position(space.unwrap_or(TextureSpace::Object)),
+            NodeSpec::Normal => Node::Normal,
+            NodeSpec::VertexColor { srgb } => Node::VertexColor {
+                srgb: srgb.unwrap_or(srgb_by_default)
+            },
+            NodeSpec::Image { ref image, ref coordinates, wrap, srgb } => Node::Image {
+                image: ImageTexture::load(
+                    image.as_str(),
+                    wrap.unwrap_or(WrapMode::Repeat),
+                    srgb.unwrap_or(srgb_by_default)
+                )?,
+                coordinates: coordinates.to_texture(false)?
+            },
+            NodeSpec::Mix { ref first, ref second, ref weight } => Node::Mix {
+                first: first.to_texture(srgb_by_default)?,
+                second: second.to_texture(srgb_by_default)?,
+                weight: weight.to_texture(false)?
+            },
+            NodeSpec::Add { ref first, ref second } => Node::Add(
+                first.to_texture(srgb_by_default)?, second.to_texture(srgb_by_default)?
+            ),
+            NodeSpec::Multiply { ref first, ref second } => Node::Multiply(
+                first.to_texture(srgb_by_default)?, second.to_texture(srgb_by_default)?
+            ),
+            NodeSpec::Channel { ref input, index } => {
+                if index > 2 {
+                    return Err(format!("channel {} out of range", index))
+                }
+                Node::Channel(input.to_texture(srgb_by_default)?, index)
+            },
+            NodeSpec::Remap { ref input, from, to, clamp } => Node::Remap {
+                input: input.to_texture(srgb_by_default)?,
+                from,
+                to,
+                clamp: clamp.unwrap_or(true)
+            },
+            NodeSpec::Fresnel { ior } => Node::Fresnel { index_of_refraction: ior }
+        };
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate serde_yaml;
+
+    fn evaluate(yaml: &str, record: &IntersectionRecord) -> Color3 {
+        let spec: TextureSpec = serde_yaml::from_str(yaml).unwrap();
+        spec.to_color_texture().ok().unwrap().color(record)
+    }
+
+    #[test]
+    fn test_evaluate_node_graph() {
+        let mut record = IntersectionRecord::no_intersection();
+        record.uv = Vec2::new(0.25, 0.75);
+
+        let uv = evaluate("node: UV", &record);
+        assert_eq!(uv, Color3::new(0.25, 0.75, 0.0));
+
+        //a ramp from red to blue along u
+        let graph = "
+node: Mix
+first: [1, 0, 0]
+second: [0, 0, 1]
+weight:
+  node: Remap
+  input:
+    node: Channel
+    input: {node: UV}
+    index: 0
+  from: [0, 0.5]
+  to: [0, 1]
+";
+        let color = evaluate(graph, &record);
+        assert!(apprx_eq(color.x, 0.5, 1e-6) && apprx_eq(color.z, 0.5, 1e-6), "{:?}", color);
+        record.uv = Vec2::new(0.75, 0.75);
+        assert_eq!(evaluate(graph, &record), Color3::new(0.0, 0.0, 1.0));
+
+        //fresnel grows towards grazing angles
+        let fresnel = "{node: Fresnel, ior: 1.5}";
+        record.ray_direction = Vec3::new(0.0, -1.0, 0.0);
+        assert!(apprx_eq(evaluate(fresnel, &record).x, 0.04, 1e-4));
+        record.ray_direction = Vec3::new(0.99, -0.1, 0.0).normalize();
+        assert!(evaluate(fresnel, &record).x > 0.4);
+
+        record.vertex_color = Color3::new(0.5, 0.25, 1.0);
+        assert_eq!(evaluate("{node: VertexColor, srgb: false}", &record), record.vertex_color);
+        assert!(evaluate("node: VertexColor", &record).x < 0.25);
+
+        let missing_input = "{node: Multiply, first: {node: UV}}";
+        assert!(serde_yaml::from_str::<TextureSpec>(missing_input).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use utilities::codable::CodableWrapper;
+use utilities::math::*;
+use utilities::color::*;
+
+use engine::intersectable::IntersectionRecord;
+
+use std::f32;
+
+///Coordinates a procedural pattern is evaluated in
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TextureSpace {
+    ///mesh coordinates before the mesh's transformations
+    Object,
+    World,
+    ///texture coordinates, with z = 0
+    UV
+}
+
+impl TextureSpace {
+    pub fn point(&self, record: &IntersectionRecord) -> Vec3 {
+        match *self {
+            TextureSpace::Object => record.object_position,
+            TextureSpace::World => record.position,
+            TextureSpace::UV => Vec3::new(record.uv.x, record.uv.y, 0.0)
+        }
+    }
+}
+
+///Scalar patterns in [0, 1]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind")]
+pub enum Pattern {
+    ///alternates between 0 and 1 every 1 / scale units
+    Checker { scale: f32 },
+    ///fractal sum of perlin noise. A single octave gives plain perlin noise
+    Noise { scale: f32, octaves: Option<u32> },
+    ///distance to the closest of randomly scattered points, about one per 1 / scale units
+    Voronoi { scale: f32 },
+    ///0 at start, 1 at end, constant on planes perpendicular to end - start
+    LinearGradient { start: CodableWrapper<Vec3>, end: CodableWrapper<Vec3> },
+    ///0 at center, 1 at radius or further
+    RadialGradient { center: CodableWrapper<Vec3>, radius: f32 }
+}
+
+impl Pattern {
+    pub fn value(&self, point: Vec3) -> f32 {
+        match *self {
+            Pattern::Checker { scale } => {
+                let p = point * scale;
+                let sum = p.x.floor() as i64 + p.y.floor() as i64 + p.z.floor() as i64;
+                if sum % 2 == 0 { 0.0 } else { 1.0 }
+            },
+            Pattern::Noise { scale, octaves } => {
+                let octaves = octaves.unwrap_or(1).max(1);
+                let mut amplitude = 1.0;
+                let mut frequency = scale;
+                let mut sum = 0.0;
+                let mut total_amplitude = 0.0;
+                for _ in 0..octaves {
+                    sum += perlin_noise(point * frequency) * amplitude;
+                    total_amplitude += amplitude;
+                    amplitude *= 0.5;
+                    frequency *= 2.0;
+                }
+                (0.5 + 0.5 * sum / total_amplitude).max(0.0).min(1.0)
+            },
+            Pattern::Voronoi { scale } => cellular_noise(point * scale).min(1.0),
+            Pattern::LinearGradient { ref start, ref end } => {
+                let axis = end.get() - start.get();
+                let length2 = axis.magnitude2();
+                if length2 == 0.0 {
+                    return 0.0
+                }
+                ((point - start.get()).dot(axis) / length2).max(0.0).min(1.0)
+            },
+            Pattern::RadialGradient { ref center, radius } => {
+                if radius <= 0.0 {
+                    return 1.0
+                }
+                ((point - center.get()).magnitude() / radius).min(1.0)
+            }
+        }
+    }
+}
+
+///Blends between two colors using a pattern
+#[derive(Debug)]
+pub struct ProceduralTexture {
+    pub pattern: Pattern,
+    pub space: TextureSpace,
+    pub colors: (Color3, Color3)
+}
+
+impl ProceduralTexture {
+    pub fn color(&self, record: &IntersectionRecord) -> Color3 {
+        let t = self.pattern.value(self.space.point(record));
+        self.colors.0 * (1.0 - t) + self.colors.1 * t
+    }
+}
+
+fn hash(x: i64, y: i64, z: i64, seed: u32) -> u32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343) ^
+        (y as u32).wrapping_mul(0xd816_3841) ^
+        (z as u32).wrapping_mul(0xcb1a_b31f) ^
+        seed.wrapping_mul(0x27d4_eb2d);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1_e995);
+    h ^= h >> 15;
+    h
+}
+
+fn hash_to_unit(h: u32) -> f32 {
+    (h >> 8) as f32 / (1u32 << 24) as f32
+}
+
+///Improved perlin noise, roughly in [-1, 1]
+fn perlin_noise(point: Vec3) -> f32 {
+    let cell = (point.x.floor(), point.y.floor(), point.z.floor());
+    let (x, y, z) = (point.x - cell.0, point.y - cell.1, point.z - cell.2);
+    let cell = (cell.0 as i64, cell.1 as i64, cell.2 as i64);
+
+    let fade = |t: f32| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let lerp = |t: f32, a: f32, b: f32| a + t * (b - a);
+    //dot product with one of the 12 directions to the edges of a cube
+    let gradient = |dx: i64, dy: i64, dz: i64| {
+        let h = hash(cell.0 + dx, cell.1 + dy, cell.2 + dz, 0) & 15;
+        let (x, y, z) = (x - dx as f32, y - dy as f32, z - dz as f32);
+        let u = if h < 8 { x } else { y };
+        let v = if h < 4 { y } else if h == 12 || h == 14 { x } else { z };
+        (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+    };
+
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+    lerp(w,
+         lerp(v, lerp(u, gradient(0, 0, 0), gradient(1, 0, 0)),
+              lerp(u, gradient(0, 1, 0), gradient(1, 1, 0))),
+         lerp(v, lerp(u, gradient(0, 0, 1), gradient(1, 0, 1)),
+              lerp(u, gradient(0, 1, 1), gradient(1, 1, 1))))
+}
+
+///Distance to the closest feature point, with one feature point per unit cell
+fn cellular_noise(point: Vec3) -> f32 {
+    let cell = (point.x.floor() as i64, point.y.floor() as i64, point.z.floor() as i64);
+    let mut closest2 = f32::INFINITY;
+    for dx in -1..2 {
+        for dy in -1..2 {
+            for dz in -1..2 {
+                let (cx, cy, cz) = (cell.0 + dx, cell.1 + dy, cell.2 + dz);
+                let feature = Vec3::new(
+                    cx as f32 + hash_to_unit(hash(cx, cy, cz, 1)),
+                    cy as f32 + hash_to_unit(hash(cx, cy, cz, 2)),
+                    cz as f32 + hash_to_unit(hash(cx, cy, cz, 3))
+                );
+                closest2 = closest2.min((feature - point).magnitude2());
+            }
+        }
+    }
+    closest2.sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_ranges() {
+        let checker = Pattern::Checker { scale: 2.0 };
+        assert_eq!(checker.value(Vec3::new(0.1, 0.1, 0.1)), 0.0);
+        assert_eq!(checker.value(Vec3::new(0.6, 0.1, 0.1)), 1.0);
+        assert_eq!(checker.value(Vec3::new(-0.1, 0.1, 0.1)), 1.0);
+
+        let patterns = [
+            Pattern::Noise { scale: 3.0, octaves: Some(5) },
+            Pattern::Voronoi { scale: 3.0 }
+        ];
+        for pattern in patterns.iter() {
+            let (mut lowest, mut highest) = (f32::INFINITY, f32::NEG_INFINITY);
+            for i in 0..1000 {
+                let t = i as f32 * 0.0137;
+                let value = pattern.value(Vec3::new(t, t * 0.7 - 3.0, t * 1.3 + 5.0));
+                lowest = lowest.min(value);
+                highest = highest.max(value);
+            }
+            assert!(lowest >= 0.0 && highest <= 1.0);
+            //the pattern shouldn't be constant
+            assert!(highest - lowest > 0.2);
+        }
+
+        //noise is zero at lattice points, which maps to 0.5
+        assert_near!(Pattern::Noise { scale: 1.0, octaves: None }.value(Vec3::new(2.0, -3.0, 1.0)),
+                     0.5, 0.00001);
+    }
+}
@@ -0,0 +1,416 @@
+//!Statistical validation of the warpers and shaders: chi-square goodness of fit tests of
+//!their sampling against their pdfs, and white furnace tests of their energy conservation
+
+extern crate rand;
+extern crate serde_yaml;
+
+use utilities::math::*;
+use utilities::color::*;
+use utilities::sampler::{Sampler, NumberSequenceSampler};
+
+use engine::probability::*;
+use engine::intersectable::IntersectionRecord;
+use engine::material::*;
+
+use self::rand::{Rng, SeedableRng, XorShiftRng};
+
+use std::collections::HashMap;
+use std::f64;
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+const THETA_BINS: usize = 20;
+const PHI_BINS: usize = 20;
+///Each bin's expected frequency is integrated on a grid of this many points per side
+const BIN_INTEGRATION_RESOLUTION: usize = 16;
+const CHI_SQUARE_SAMPLES: usize = 100000;
+///Cells expecting fewer samples are pooled together
+const MIN_EXPECTED_FREQUENCY: f64 = 5.0;
+///Probability of rejecting a correct sampler
+const SIGNIFICANCE_LEVEL: f64 = 0.01;
+
+const FURNACE_SAMPLES: usize = 50000;
+const FURNACE_TOLERANCE: f32 = 0.02;
+///Burley's diffuse retro reflection adds about 5% at grazing angles for a roughness of 0.4
+const BURLEY_FURNACE_TOLERANCE: f32 = 0.1;
+///Grid of the midpoint rule used by hemisphere_integral
+const HEMISPHERE_THETA_STEPS: usize = 200;
+const HEMISPHERE_PHI_STEPS: usize = 400;
+
+///Sampler with a fixed seed, so that statistical tests give the same result every run
+#[derive(Debug)]
+pub struct SeededSampler {
+    rng: XorShiftRng
+}
+
+impl SeededSampler {
+    pub fn new(seed: u32) -> SeededSampler {
+        SeededSampler { rng: XorShiftRng::from_seed([seed, 0x193a6754, 0xa8a7d469, 0x97830e05]) }
+    }
+}
+
+impl Sampler for SeededSampler {
+    fn get_f32(&mut self) -> f32 {
+        self.rng.next_f32()
+    }
+}
+
+///Cell of a direction on the sphere, in bins of theta and phi. Bins near the normal [0, 1, 0]
+///are small, so peaked distributions still spread over several of them
+fn direction_cell(direction: &Vec3) -> usize {
+    let theta = direction.y.max(-1.0).min(1.0).acos() / PI;
+    let phi = (direction.z.atan2(direction.x) + PI) / (2.0 * PI);
+    let theta_bin = ((theta * THETA_BINS as f32) as usize).min(THETA_BINS - 1);
+    let phi_bin = ((phi * PHI_BINS as f32) as usize).min(PHI_BINS - 1);
+    theta_bin * PHI_BINS + phi_bin
+}
+
+///Probability of each cell of direction_cell under a solid angle density
+fn cell_probabilities<P: Fn(&Vec3) -> f32>(pdf: P) -> Vec<f64> {
+    let resolution = BIN_INTEGRATION_RESOLUTION;
+    let step_area = (f64::consts::PI / (THETA_BINS * resolution) as f64) *
+        (2.0 * f64::consts::PI / (PHI_BINS * resolution) as f64);
+    let mut probabilities = Vec::with_capacity(THETA_BINS * PHI_BINS);
+    for theta_bin in 0..THETA_BINS {
+        for phi_bin in 0..PHI_BINS {
+            let mut total = 0.0;
+            for i in 0..resolution {
+                let theta = PI * (theta_bin as f32 + (i as f32 + 0.5) / resolution as f32) /
+                    THETA_BINS as f32;
+                for j in 0..resolution {
+                    let phi = -PI + 2.0 * PI * (phi_bin as f32 +
+                        (j as f32 + 0.5) / resolution as f32) / PHI_BINS as f32;
+                    let direction = Vec3::new(theta.sin() * phi.cos(), theta.cos(),
+                                              theta.sin() * phi.sin());
+                    total += pdf(&direction) as f64 * theta.sin() as f64;
+                }
+            }
+            probabilities.push(total * step_area);
+        }
+    }
+    probabilities
+}
+
+///Chi-square test of the directions drawn by sample against the solid angle density pdf.
+///sample returns None for rejected samples, which make the pdf integrate to less than one.
+///Panics if the directions don't follow the pdf
+pub fn chi_square_test<S, P>(name: &str, mut sample: S, pdf: P)
+    where S: FnMut() -> Option<Vec3>, P: Fn(&Vec3) -> f32
+{
+    let mut probabilities = cell_probabilities(pdf);
+    let total_probability: f64 = probabilities.iter().sum();
+    assert!(total_probability < 1.0 + 1e-2,
+            "{}: the pdf integrates to {}", name, total_probability);
+    //the last cell holds the rejected samples
+    probabilities.push((1.0 - total_probability).max(0.0));
+
+    let mut observed = vec![0.0; probabilities.len()];
+    for _ in 0..CHI_SQUARE_SAMPLES {
+        match sample() {
+            Some(direction) => observed[direction_cell(&direction)] += 1.0,
+            None => observed[probabilities.len() - 1] += 1.0
+        }
+    }
+
+    let mut cells: Vec<(f64, f64)> = probabilities.iter()
+        .map(|probability| probability * CHI_SQUARE_SAMPLES as f64)
+        .zip(observed.into_iter())
+        .collect();
+    for &(expected, observed) in &cells {
+        assert!(expected > 0.0 || observed == 0.0,
+                "{}: {} samples where the pdf is zero", name, observed);
+    }
+    cells.sort_by(|first, second| first.0.partial_cmp(&second.0).unwrap());
+    let mut statistic = 0.0;
+    let mut degrees_of_freedom = 0;
+    let (mut pooled_expected, mut pooled_observed) = (0.0, 0.0);
+    for &(expected, observed) in &cells {
+        if expected == 0.0 {
+            continue
+        }
+        if expected < MIN_EXPECTED_FREQUENCY {
+            pooled_expected += expected;
+            pooled_observed += observed;
+            continue
+        }
+        statistic += (observed - expected) * (observed - expected) / expected;
+        degrees_of_freedom += 1;
+    }
+    if pooled_expected > 0.0 {
+        statistic += (pooled_observed - pooled_expected).powi(2) / pooled_expected;
+        degrees_of_freedom += 1;
+    }
+    degrees_of_freedom -= 1;
+
+    let p_value = chi_square_survival(statistic, degrees_of_freedom as f64);
+    assert!(p_value > SIGNIFICANCE_LEVEL,
+            "{}: chi-square statistic {} with {} degrees of freedom, p-value {}",
+            name, statistic, degrees_of_freedom, p_value);
+}
+
+///Probability of a chi-square statistic at least this large, with the Wilson-Hilferty
+///approximation of the chi-square distribution
+fn chi_square_survival(statistic: f64, degrees_of_freedom: f64) -> f64 {
+    let variance = 2.0 / (9.0 * degrees_of_freedom);
+    let z = ((statistic / degrees_of_freedom).powf(1.0 / 3.0) - (1.0 - variance)) /
+        variance.sqrt();
+    0.5 * complementary_error_function(z / f64::consts::SQRT_2)
+}
+
+///Abramowitz and Stegun 7.1.26
+fn complementary_error_function(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let polynomial = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 +
+        t * (-1.453152027 + t * 1.061405429))));
+    let erfc = polynomial * (-x * x).exp();
+    if x >= 0.0 { erfc } else { 2.0 - erfc }
+}
+
+fn sampler_for(seed: u32, samples: usize) -> NumberSequenceSampler {
+    //generous, so that the sequence doesn't wrap around for bsdfs drawing many numbers
+    NumberSequenceSampler::new_from_sampler(&mut SeededSampler::new(seed), 8 * samples)
+}
+
+///Chi-square test of a bsdf's sample against its sample_pdf, which also checks that
+///the samples report the pdf and value of sample_pdf and brdf
+pub fn bsdf_chi_square_test(name: &str, bsdf: &BSDFMaterial, outgoing: &UnitVec3) {
+    let record = IntersectionRecord::no_intersection();
+    let mut sampler = sampler_for(1, CHI_SQUARE_SAMPLES);
+    let sample = || {
+        let sample = bsdf.sample(&record, outgoing, &mut sampler);
+        assert!(!sample.from_delta_lobe, "{}: sampled a delta lobe", name);
+        if sample.pdf <= 0.0 {
+            return None
+        }
+        let light_directions = LightDirectionPair { incoming: &sample.direction, outgoing };
+        let pdf = bsdf.sample_pdf(&record, &light_directions);
+        assert!(apprx_eq(sample.pdf, pdf, 1e-3 * pdf),
+                "{}: sampled pdf {} but sample_pdf gives {}", name, sample.pdf, pdf);
+        let cosine = sample.direction.value().dot(*record.normal.value()).abs();
+        let value = bsdf.brdf(&record, &light_directions) * cosine;
+        for channel in 0..3 {
+            assert!(apprx_eq(sample.value[channel], value[channel],
+                             1e-3 * value[channel].max(1.0)),
+                    "{}: sampled value {:?} but brdf gives {:?}", name, sample.value, value);
+        }
+        Some(*sample.direction.value())
+    };
+    chi_square_test(name, sample, |direction| bsdf.sample_pdf(&record, &LightDirectionPair {
+        incoming: &direction.unit(), outgoing
+    }));
+}
+
+///Fraction of the light arriving from every direction that a white bsdf reflects
+///towards outgoing, estimated with its own sampling
+pub fn furnace_albedo(bsdf: &BSDFMaterial, outgoing: &UnitVec3) -> f32 {
+    let record = IntersectionRecord::no_intersection();
+    let mut sampler = sampler_for(2, FURNACE_SAMPLES);
+    let total = (0..FURNACE_SAMPLES)
+        .map(|_| average(&bsdf.sample(&record, outgoing, &mut sampler).weight()))
+        .fold(0.0, |total, weight| total + weight);
+    total / FURNACE_SAMPLES as f32
+}
+
+///Integral over the solid angle of the hemisphere around the normal [0, 1, 0],
+///with the midpoint rule on a grid of theta and phi
+pub fn hemisphere_integral<F: FnMut(&UnitVec3) -> f32>(mut integrand: F) -> f32 {
+    let theta_step = PI / 2.0 / HEMISPHERE_THETA_STEPS as f32;
+    let phi_step = 2.0 * PI / HEMISPHERE_PHI_STEPS as f32;
+    let mut total = 0.0;
+    for i in 0..HEMISPHERE_THETA_STEPS {
+        let theta = (i as f32 + 0.5) * theta_step;
+        for j in 0..HEMISPHERE_PHI_STEPS {
+            let phi = (j as f32 + 0.5) * phi_step;
+            let direction =
+                Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin()).unit();
+            total += integrand(&direction) * theta.sin();
+        }
+    }
+    total * theta_step * phi_step
+}
+
+///furnace_albedo integrated from the brdf instead of estimated with the bsdf's sampling,
+///so that it doesn't depend on sample and has no noise
+pub fn brdf_albedo(bsdf: &BSDFMaterial, outgoing: &UnitVec3) -> f32 {
+    let record = IntersectionRecord::no_intersection();
+    hemisphere_integral(|incoming| {
+        let light_directions = LightDirectionPair { incoming, outgoing };
+        average(&bsdf.brdf(&record, &light_directions)) * incoming.value().y
+    })
+}
+
+///Outgoing direction at the given angle to the normal [0, 1, 0]
+pub fn outgoing_at(cos_theta: f32) -> UnitVec3 {
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    Vec3::new(sin_theta * 0.8, cos_theta, sin_theta * 0.6).unit()
+}
+
+///Every shader kind, with white colors. Mix, Coated and Sheen use diffuse as their base
+const SHADERS: &str = "
+diffuse: {kind: Diffuse, color: 1}
+oren_nayar: {kind: OrenNayar, color: 1, sigma: 0.8}
+microfacet: {kind: Microfacet, color: 1, ior: 1.5, roughness: 0.3}
+anisotropic_microfacet:
+  {kind: Microfacet, color: 1, ior: 1.5, roughness: 0.5, roughness_y: 0.2}
+thin_film_microfacet:
+  kind: Microfacet
+  color: 1
+  ior: 1.5
+  roughness: 0.3
+  thin_film: {thickness: 400, ior: 1.33}
+mirror: {kind: Mirror, color: 1}
+glass: {kind: Dielectric, ior: 1.5}
+rough_glass: {kind: Dielectric, ior: 1.5, roughness: 0.3}
+mix: {kind: Mix, first: diffuse, second: microfacet, weight: 0.5}
+delta_mix: {kind: Mix, first: diffuse, second: mirror, weight: 0.5}
+coated: {kind: Coated, base: diffuse, ior: 1.5}
+rough_coated: {kind: Coated, base: diffuse, ior: 1.5, roughness: 0.2}
+principled:
+  kind: Principled
+  base_color: 1
+  roughness: 0.4
+  metallic: 0.5
+  clearcoat: 0.5
+  clearcoat_gloss: 0.2
+  sheen: 0.5
+  anisotropic: 0.5
+principled_metal: {kind: Principled, base_color: 1, roughness: 0.4, metallic: 1}
+principled_glass: {kind: Principled, base_color: 1, roughness: 0.4, transmission: 1}
+conductor: {kind: Conductor, metal: Al, roughness: 0.3}
+anisotropic_conductor: {kind: Conductor, metal: Ag, roughness: 0.4, roughness_y: 0.2}
+polished_conductor: {kind: Conductor, metal: Cu}
+cloth: {kind: Cloth, color: 1, roughness: 0.5}
+sheen: {kind: Sheen, base: diffuse, color: 1, roughness: 0.3}
+subsurface: {kind: Subsurface, albedo: 1, mean_free_path: [1, 1, 1]}
+";
+
+///Synthetic table for a measured shader, glossy around the normal and tinted so that
+///every channel differs. Measured shaders load from files, so it isn't in SHADERS
+fn measured_values() -> Vec<Color3> {
+    let (theta_half_bins, diff_bins) = (90, 90 * 180);
+    (0..theta_half_bins * diff_bins)
+        .map(|index| {
+            let theta_half_bin = (index / diff_bins) as f32;
+            let value = 0.15 + 2.0 * (-theta_half_bin / 8.0).exp();
+            Color3::new(value, value * 0.8, value * 0.6)
+        })
+        .collect()
+}
+
+fn build_shaders() -> HashMap<String, Rc<Material>> {
+    let specs: HashMap<String, MaterialEntrySpec> = serde_yaml::from_str(SHADERS).unwrap();
+    let mut shaders = build_materials(&specs).ok().unwrap();
+    let measured = MeasuredBSDFMaterial::from_values(measured_values());
+    shaders.insert("measured".into(), Rc::new(measured.into()));
+    shaders
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warper_chi_square() {
+        let mut sampler = SeededSampler::new(3);
+        chi_square_test("uniform hemisphere",
+                        || Some(UniformHemisphereWarper.sample(&mut sampler)),
+                        |direction| if direction.y >= 0.0 {
+                            UniformHemisphereWarper.pdf(direction)
+                        } else {
+                            0.0
+                        });
+        let mut sampler = SeededSampler::new(4);
+        chi_square_test("uniform sphere",
+                        || Some(UniformSphereWarper.sample(&mut sampler)),
+                        |direction| UniformSphereWarper.pdf(direction));
+        let mut sampler = SeededSampler::new(5);
+        chi_square_test("cosine hemisphere",
+                        || Some(CosineHemisphereWarper.sample(&mut sampler)),
+                        |direction| CosineHemisphereWarper.pdf(direction));
+        //points on the disk lifted onto the hemisphere, where dA = cos dw
+        let mut sampler = SeededSampler::new(6);
+        chi_square_test("uniform circle",
+                        || {
+                            let point = UniformCircleWarper.sample(&mut sampler);
+                            let height = (1.0 - point.magnitude2()).max(0.0).sqrt();
+                            Some(Vec3::new(point.x, height, point.y))
+                        },
+                        |direction| {
+                            let point = Vec2::new(direction.x, direction.z);
+                            UniformCircleWarper.pdf(&point) * direction.y.max(0.0)
+                        });
+
+        let distributions = [GGXDistribution::isotropic(0.3),
+                             GGXDistribution { alpha_x: 0.6, alpha_y: 0.15 }];
+        for (index, distribution) in distributions.iter().enumerate() {
+            for outgoing in &[Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.7, 0.3, -0.4).normalize()] {
+                let warper = GGXVisibleNormalWarper { distribution: *distribution, outgoing: *outgoing };
+                let mut sampler = SeededSampler::new(7 + index as u32);
+                chi_square_test(&format!("ggx visible normals {:?} {:?}", distribution, outgoing),
+                                || Some(warper.sample(&mut sampler)),
+                                |direction| warper.pdf(direction));
+            }
+        }
+    }
+
+    #[test]
+    fn test_bsdf_chi_square() {
+        let shaders = build_shaders();
+        let reflective = ["diffuse", "oren_nayar", "microfacet", "anisotropic_microfacet",
+            "thin_film_microfacet", "mix", "rough_coated", "principled", "conductor",
+            "anisotropic_conductor", "cloth", "sheen", "measured"];
+        for name in reflective.iter() {
+            for &cos_theta in &[0.9, 0.3] {
+                bsdf_chi_square_test(&format!("{} at cos {}", name, cos_theta),
+                                     shaders[*name].bsdf(), &outgoing_at(cos_theta));
+            }
+        }
+        //transmissive shaders are also seen from below
+        for name in ["rough_glass", "principled_glass"].iter() {
+            for &cos_theta in &[0.9, 0.3, -0.6] {
+                bsdf_chi_square_test(&format!("{} at cos {}", name, cos_theta),
+                                     shaders[*name].bsdf(), &outgoing_at(cos_theta));
+            }
+        }
+    }
+
+    #[test]
+    fn test_white_furnace() {
+        let shaders = build_shaders();
+        //shaders that scatter all of the light they receive
+        let conserving = ["diffuse", "oren_nayar", "mirror", "delta_mix", "sheen"];
+        let all = ["diffuse", "oren_nayar", "microfacet", "anisotropic_microfacet",
+            "thin_film_microfacet", "mirror", "glass", "rough_glass", "mix", "delta_mix",
+            "coated", "rough_coated", "principled", "principled_metal", "principled_glass",
+            "conductor", "anisotropic_conductor", "polished_conductor", "cloth", "sheen",
+            "subsurface", "measured"];
+        for name in all.iter() {
+            //Burley's diffuse, used by non metallic principled shaders, reflects more
+            //than it receives at grazing angles
+            let tolerance = if *name == "principled" {
+                BURLEY_FURNACE_TOLERANCE
+            } else {
+                FURNACE_TOLERANCE
+            };
+            for &cos_theta in &[1.0, 0.5, 0.1] {
+                let albedo = furnace_albedo(shaders[*name].bsdf(), &outgoing_at(cos_theta));
+                assert!(albedo.is_finite() && albedo <= 1.0 + tolerance,
+                        "{} creates energy at cos {}: albedo {}", name, cos_theta, albedo);
+                if conserving.contains(name) {
+                    assert!(albedo >= 1.0 - FURNACE_TOLERANCE,
+                            "{} loses energy at cos {}: albedo {}", name, cos_theta, albedo);
+                }
+            }
+        }
+
+        //integrating the brdf agrees with sampling it
+        for name in ["diffuse", "microfacet", "cloth"].iter() {
+            let outgoing = outgoing_at(0.5);
+            let sampled = furnace_albedo(shaders[*name].bsdf(), &outgoing);
+            let integrated = brdf_albedo(shaders[*name].bsdf(), &outgoing);
+            assert!(apprx_eq(sampled, integrated, FURNACE_TOLERANCE),
+                    "{}: sampled albedo {} but the brdf integrates to {}",
+                    name, sampled, integrated);
+        }
+    }
+}
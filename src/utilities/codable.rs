@@ -23,7 +23,7 @@ impl<T> From<T> for CodableWrapper<T> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CodableWrapper<T>(pub T);
 
 impl<T: Clone> CodableWrapper<T> {
@@ -26,3 +26,22 @@ impl RgbU8Convertible for Color3 {
         ]}
     }
 }
+
+///Decodes an sRGB encoded channel in [0, 1] to linear intensity
+pub fn srgb_to_linear(encoded: f32) -> f32 {
+    if encoded <= 0.04045 {
+        encoded / 12.92
+    } else {
+        ((encoded + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+///Relative luminance of a linear rgb color
+pub fn luminance(color: &Color3) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+///Mean of the channels
+pub fn average(color: &Color3) -> f32 {
+    (color.x + color.y + color.z) / 3.0
+}
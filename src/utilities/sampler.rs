@@ -43,8 +43,9 @@ pub trait Sampler: Debug {
         (self.get_f32(), self.get_f32())
     }
 
+    ///Maps a sample in [0, 1) to an integer in [0, limit)
     fn get_usize_from_f32(&mut self, limit: usize) -> usize {
-        (self.get_f32() as usize) % limit
+        ((self.get_f32() * limit as f32) as usize).min(limit - 1)
     }
 }
 